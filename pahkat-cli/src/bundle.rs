@@ -0,0 +1,30 @@
+use std::path::Path;
+
+use pahkat_client::{PackageKey, PackageStore};
+
+pub async fn bundle(
+    store: &dyn PackageStore,
+    packages: &[String],
+    output_path: &Path,
+) -> Result<(), anyhow::Error> {
+    std::fs::create_dir_all(output_path)?;
+
+    let keys: Vec<PackageKey> = packages
+        .iter()
+        .map(|id| store.find_package_by_id(id).map(|x| x.0))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let bundled = pahkat_client::bundle::create_bundle(store, &keys, output_path).await?;
+
+    for key in bundled.iter() {
+        println!("Bundled {}", key.id);
+    }
+
+    println!(
+        "Wrote {} packages to {}; add it as a local repository to install from it.",
+        bundled.len(),
+        output_path.display()
+    );
+
+    Ok(())
+}
@@ -31,11 +31,17 @@ pub(crate) enum Args {
     #[structopt(template(SUB_TEMPLATE))]
     Download(command::Download),
     #[structopt(template(SUB_TEMPLATE))]
+    Bundle(command::Bundle),
+    #[structopt(template(SUB_TEMPLATE))]
     Install(command::Install),
     #[structopt(template(SUB_TEMPLATE))]
+    Upgrade(command::Upgrade),
+    #[structopt(template(SUB_TEMPLATE))]
     Uninstall(command::Uninstall),
     #[structopt(template(SUB_TEMPLATE))]
     Status(command::Status),
+    #[structopt(template(SUB_TEMPLATE))]
+    Search(command::Search),
     #[structopt(template(SUBC_TEMPLATE))]
     Config(command::Config),
 }
@@ -46,10 +52,13 @@ impl ConfigPath for Args {
         match self {
             Args::Init(x) => x.config_path(),
             Args::Download(x) => x.config_path(),
+            Args::Bundle(x) => x.config_path(),
             Args::Install(x) => x.config_path(),
+            Args::Upgrade(x) => x.config_path(),
             Args::Uninstall(x) => x.config_path(),
             Args::Config(x) => x.config_path(),
             Args::Status(x) => x.config_path(),
+            Args::Search(x) => x.config_path(),
         }
     }
 }
@@ -60,9 +69,12 @@ impl Platform for Args {
         match self {
             Args::Init(x) => x.platform(),
             Args::Download(x) => x.platform(),
+            Args::Bundle(x) => x.platform(),
             Args::Install(x) => x.platform(),
+            Args::Upgrade(x) => x.platform(),
             Args::Uninstall(x) => x.platform(),
             Args::Status(x) => x.platform(),
+            Args::Search(x) => x.platform(),
             Args::Config(x) => None,
         }
     }
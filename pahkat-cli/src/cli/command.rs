@@ -23,11 +23,40 @@ pub struct Download {
     global_opts: super::GlobalOpts,
 }
 
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Bundle packages and their dependencies as a repository for offline installs")]
+pub struct Bundle {
+    #[structopt(required = true, help = "Packages to bundle")]
+    pub packages: Vec<String>,
+
+    #[structopt(
+        short,
+        long = "output",
+        help = "Directory to write the bundle to",
+        parse(from_os_str)
+    )]
+    pub output_path: PathBuf,
+
+    #[structopt(flatten)]
+    global_opts: super::GlobalOpts,
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Install packages from configured repositories")]
 pub struct Install {
     #[structopt(required = true, help = "Packages to install")]
     pub packages: Vec<String>,
+    #[structopt(long, help = "Show what would be downloaded and installed without doing it")]
+    pub dry_run: bool,
+    #[structopt(flatten)]
+    global_opts: super::GlobalOpts,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Update every installed package that has a newer release")]
+pub struct Upgrade {
+    #[structopt(long, help = "Show what would be downloaded and installed without doing it")]
+    pub dry_run: bool,
     #[structopt(flatten)]
     global_opts: super::GlobalOpts,
 }
@@ -64,6 +93,19 @@ pub struct Status {
     global_opts: super::GlobalOpts,
 }
 
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Search for packages in configured repositories")]
+pub struct Search {
+    #[structopt(required = true, help = "Search terms")]
+    pub query: Vec<String>,
+    #[structopt(short, long = "tag", help = "Only show packages with the given tag")]
+    pub tags: Vec<String>,
+    #[structopt(short, long, help = "Maximum number of results to show")]
+    pub limit: Option<usize>,
+    #[structopt(flatten)]
+    global_opts: super::GlobalOpts,
+}
+
 use crate::{ConfigPath, Platform};
 
 impl ConfigPath for Download {
@@ -81,6 +123,20 @@ impl Platform for Download {
     }
 }
 
+impl ConfigPath for Bundle {
+    #[inline]
+    fn config_path(&self) -> Option<&Path> {
+        self.global_opts.config_path.as_ref().map(PathBuf::as_path)
+    }
+}
+
+impl Platform for Bundle {
+    #[inline]
+    fn platform(&self) -> Option<&str> {
+        self.global_opts.platform.as_ref().map(|x| &**x)
+    }
+}
+
 impl ConfigPath for Install {
     #[inline]
     fn config_path(&self) -> Option<&Path> {
@@ -96,6 +152,20 @@ impl Platform for Install {
     }
 }
 
+impl ConfigPath for Upgrade {
+    #[inline]
+    fn config_path(&self) -> Option<&Path> {
+        self.global_opts.config_path.as_ref().map(PathBuf::as_path)
+    }
+}
+
+impl Platform for Upgrade {
+    #[inline]
+    fn platform(&self) -> Option<&str> {
+        self.global_opts.platform.as_ref().map(|x| &**x)
+    }
+}
+
 impl ConfigPath for Uninstall {
     #[inline]
     fn config_path(&self) -> Option<&Path> {
@@ -126,6 +196,21 @@ impl Platform for Status {
     }
 }
 
+impl ConfigPath for Search {
+    #[inline]
+    fn config_path(&self) -> Option<&Path> {
+        self.global_opts.config_path.as_ref().map(PathBuf::as_path)
+    }
+
+}
+
+impl Platform for Search {
+    #[inline]
+    fn platform(&self) -> Option<&str> {
+        self.global_opts.platform.as_ref().map(|x| &**x)
+    }
+}
+
 impl ConfigPath for Init {
     #[inline]
     fn config_path(&self) -> Option<&Path> {
@@ -16,10 +16,7 @@ pub async fn download<'a>(
     let keys: Vec<PackageKey> = packages
         .iter()
         .map(|id| {
-            store
-                .find_package_by_id(id)
-                .map(|x| x.0)
-                .ok_or_else(|| anyhow::anyhow!("Could not find package for: `{}`", id))
+            store.find_package_by_id(id).map(|x| x.0)
         })
         .collect::<Result<Vec<_>, _>>()?;
 
@@ -1,11 +1,13 @@
 use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
 
-use futures::stream::StreamExt;
+use futures::stream::{Stream, StreamExt};
 
 use pahkat_client::{
-    transaction::{PackageAction, PackageTransaction},
-    package_store::InstallTarget,
+    transaction::{PackageAction, PackageTransaction, TransactionEvent},
+    package_store::{DownloadControl, DownloadOptions, InstallTarget},
+    DownloadEvent,
     PackageStore,
     PackageKey,
 };
@@ -15,15 +17,13 @@ pub(crate) async fn install<'a>(
     store: Arc<dyn PackageStore>,
     packages: &'a Vec<String>,
     target: InstallTarget,
+    dry_run: bool,
     args: &'a crate::Args,
 ) -> Result<(), anyhow::Error> {
     let keys: Vec<PackageKey> = packages
         .iter()
         .map(|id| {
-            let mut key: PackageKey = store
-                .find_package_by_id(id)
-                .map(|x| x.0)
-                .ok_or_else(|| anyhow::anyhow!("Could not find package for: `{}`", id))?;
+            let mut key: PackageKey = store.find_package_by_id(id).map(|x| x.0)?;
 
             if let Some(platform) = args.platform() {
                 key.query.platform = Some(platform.to_string());
@@ -60,10 +60,71 @@ pub(crate) async fn install<'a>(
             .collect(),
     )?;
 
+    run(transaction, dry_run).await
+}
+
+/// Downloads and processes a transaction, or only describes it for a dry run.
+pub(crate) async fn run(transaction: PackageTransaction, dry_run: bool) -> Result<(), anyhow::Error> {
+    // Fails before anything is downloaded if there is not enough space.
+    let plan = transaction.plan()?;
+
+    if dry_run {
+        for planned in plan.actions.iter() {
+            println!(
+                "{:?} {}: download {} bytes{}, installed size {:+} bytes",
+                planned.action.action,
+                planned.action.id,
+                planned.download_size,
+                if planned.is_cached { " (cached)" } else { "" },
+                planned.installed_size_delta
+            );
+        }
+
+        println!(
+            "Total: download {} bytes, installed size {:+} bytes",
+            plan.download_size, plan.installed_size_delta
+        );
+        return Ok(());
+    }
+
+    let control = DownloadControl::default();
+    let (_download_canceler, mut downloads) = transaction.download_with_options(DownloadOptions {
+        control: control.clone(),
+        ..Default::default()
+    });
+    let mut toggles = pause_toggles();
+
+    loop {
+        tokio::select! {
+            event = downloads.next() => match event {
+                Some((key, DownloadEvent::Error(e))) => {
+                    anyhow::bail!("Failed to download {}: {}", key, e);
+                }
+                Some(_) => {}
+                None => break,
+            },
+            Some(()) = toggles.next() => {
+                if control.is_paused() {
+                    eprintln!("Resuming downloads");
+                    control.resume();
+                } else {
+                    eprintln!("Pausing downloads, send SIGUSR1 again to resume");
+                    control.pause();
+                }
+            }
+        }
+    }
+
     let (canceler, mut tx) = transaction.process();
 
-    if let Some(event) = tx.next().await {
-        // TODO;
+    while let Some(event) = tx.next().await {
+        match event {
+            TransactionEvent::Error(key, e) => {
+                anyhow::bail!("Failed to install {}: {}", key, e);
+            }
+            TransactionEvent::HookFailed(_, e) => eprintln!("Warning: {}", e),
+            _ => {}
+        }
     }
     // transaction
     //     .process(|key, event| {
@@ -74,3 +135,19 @@ pub(crate) async fn install<'a>(
     //     .unwrap()?;
     Ok(())
 }
+
+/// Fires whenever downloads should be paused or resumed, which is on every
+/// SIGUSR1. Other platforms have no such signal, so there it never fires.
+fn pause_toggles() -> Pin<Box<dyn Stream<Item = ()> + Send>> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::user_defined1()) {
+            Ok(signals) => return Box::pin(signals),
+            Err(e) => eprintln!("Warning: downloads cannot be paused: {}", e),
+        }
+    }
+
+    Box::pin(futures::stream::pending())
+}
@@ -1,8 +1,11 @@
+mod bundle;
 mod cli;
 mod download;
 mod install;
+mod search;
 mod status;
 mod uninstall;
+mod upgrade;
 
 use anyhow::{Context, Result};
 use cli::{Args, Platform, ConfigPath};
@@ -32,6 +35,28 @@ use std::sync::Arc;
 // }
 
 
+fn print_repo_errors(store: &dyn PackageStore) {
+    let repos = store.repos();
+    let repos = repos.read().unwrap();
+    let repo_errors = store.repo_errors();
+    let repo_errors = repo_errors.read().unwrap();
+
+    for (url, error) in repo_errors.iter() {
+        let mut message = error.to_string();
+        let mut source = std::error::Error::source(&**error);
+        while let Some(e) = source {
+            message.push_str(&format!(": {}", e));
+            source = e.source();
+        }
+
+        if repos.contains_key(url) {
+            println!("WARNING: Using stale cached copy of {}: {}", url, message);
+        } else {
+            println!("WARNING: Could not load {}: {}", url, message);
+        }
+    }
+}
+
 #[inline(always)]
 #[cfg(feature = "prefix")]
 async fn store(config_path: Option<&Path>) -> anyhow::Result<Arc<dyn PackageStore>> {
@@ -43,6 +68,8 @@ async fn store(config_path: Option<&Path>) -> anyhow::Result<Arc<dyn PackageStor
         println!("WARNING: There are no repositories in the given config.");
     }
 
+    print_repo_errors(&*store);
+
     Ok(store)
 }
 
@@ -60,6 +87,8 @@ async fn store(config_path: Option<&Path>) -> anyhow::Result<Arc<dyn PackageStor
         println!("WARNING: There are no repositories in the given config.");
     }
 
+    print_repo_errors(&*store);
+
     Ok(store)
 }
 
@@ -87,17 +116,29 @@ async fn main() -> anyhow::Result<()> {
                     .unwrap_or_else(|| std::env::current_dir().unwrap()),
             ).await?
         }
+        cli::Args::Bundle(a) => {
+            let store = store(args.config_path()).await?;
+            bundle::bundle(&*store, &a.packages, &a.output_path).await?
+        }
         cli::Args::Status(a) => {
             let store = store(args.config_path()).await?;
             status::status(&*store, &a.packages, Default::default())?
         }
+        cli::Args::Search(a) => {
+            let store = store(args.config_path()).await?;
+            search::search(&*store, &a.query.join(" "), &a.tags, a.limit)?
+        }
         cli::Args::Uninstall(a) => {
             let store = store(args.config_path()).await?;
             uninstall::uninstall(&*store, &a.packages, Default::default())?
         }
         cli::Args::Install(a) => {
             let store = store(args.config_path()).await?;
-            install::install(store, &a.packages, Default::default(), &args).await?
+            install::install(store, &a.packages, Default::default(), a.dry_run, &args).await?
+        }
+        cli::Args::Upgrade(a) => {
+            let store = store(args.config_path()).await?;
+            upgrade::upgrade(store, Default::default(), a.dry_run).await?
         }
         _ => {}
     }
@@ -0,0 +1,40 @@
+use pahkat_client::{package_store::InstallTarget, repo::SearchOptions, PackageStore};
+
+pub fn search(
+    store: &dyn PackageStore,
+    query: &str,
+    tags: &[String],
+    limit: Option<usize>,
+) -> Result<(), anyhow::Error> {
+    let options = SearchOptions {
+        tags: if tags.is_empty() {
+            None
+        } else {
+            Some(tags.to_vec())
+        },
+        limit,
+    };
+
+    let results = store.search(query, &options, &[InstallTarget::System, InstallTarget::User]);
+
+    if results.is_empty() {
+        println!("No packages found.");
+        return Ok(());
+    }
+
+    for descriptor in results {
+        let name = descriptor
+            .name
+            .get("en")
+            .or_else(|| descriptor.name.values().next())
+            .map(|x| &**x)
+            .unwrap_or("");
+
+        println!(
+            "{} {} [{:?}] {}",
+            &descriptor.key.id, &descriptor.release.version, descriptor.status, name
+        );
+    }
+
+    Ok(())
+}
@@ -14,9 +14,9 @@ pub fn status(
 
     for id in packages {
         let (package_key, _) = match store.find_package_by_id(id) {
-            Some(v) => v,
-            None => {
-                println!("{}: not found", &id);
+            Ok(v) => v,
+            Err(e) => {
+                println!("{}: {}", &id, e);
                 continue;
             }
         };
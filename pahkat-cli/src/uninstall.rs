@@ -8,10 +8,7 @@ pub fn uninstall(
     target: InstallTarget,
 ) -> Result<(), anyhow::Error> {
     for id in packages {
-        let pkg_key = store
-            .find_package_by_id(id)
-            .map(|x| x.0)
-            .ok_or_else(|| anyhow::anyhow!("Could not find package for: `{}`", id))?;
+        let pkg_key = store.find_package_by_id(id).map(|x| x.0)?;
         println!("Uninstalling {}", &pkg_key);
         let status = store.uninstall(&pkg_key, target)?;
         println!("{:?}", status);
@@ -0,0 +1,18 @@
+use std::sync::Arc;
+
+use pahkat_client::{package_store::InstallTarget, PackageStore, PackageTransaction};
+
+pub(crate) async fn upgrade(
+    store: Arc<dyn PackageStore>,
+    target: InstallTarget,
+    dry_run: bool,
+) -> Result<(), anyhow::Error> {
+    let transaction = PackageTransaction::upgrade_all(store, &[target])?;
+
+    if transaction.actions().is_empty() {
+        println!("All packages are up to date.");
+        return Ok(());
+    }
+
+    crate::install::run(transaction, dry_run).await
+}
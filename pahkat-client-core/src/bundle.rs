@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+
+use futures::stream::StreamExt;
+use pahkat_types::package::Descriptor;
+use pahkat_types::payload::AsDownloadUrl;
+use pahkat_types::repo::{Agent, Index, RepositoryData};
+use url::Url;
+
+use crate::download::DownloadError;
+use crate::package_store::{DownloadEvent, InstallTarget};
+use crate::repo::{PackageCandidate, PackageCandidateError};
+use crate::{PackageKey, PackageStore};
+
+/// Payloads are stored below this directory of a bundle, in a directory per
+/// package.
+const PAYLOADS_DIR: &str = "payloads";
+
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+    #[error("Could not resolve the packages to bundle")]
+    Resolve(#[from] PackageCandidateError),
+
+    #[error("More than one package to bundle has the id `{0}`")]
+    DuplicateId(String),
+
+    #[error("Could not download {0}")]
+    Download(PackageKey, #[source] DownloadError),
+
+    #[error("Could not write {0:?}")]
+    Write(PathBuf, #[source] std::io::Error),
+
+    #[error("Could not serialize the repository index")]
+    ToToml(#[from] toml::ser::Error),
+}
+
+/// Downloads `keys` and everything they depend on into `path`, as a
+/// repository that can be added as a local repository on a machine without
+/// network access. Returns the keys of every bundled package.
+///
+/// Only the release and target resolved for this machine are bundled, without
+/// their channel, so the bundle installs the same versions whichever channel
+/// its repository is configured with. Payload URLs are relative to `path`, and
+/// dependencies on bundled packages refer to them by id.
+pub async fn create_bundle(
+    store: &dyn PackageStore,
+    keys: &[PackageKey],
+    path: &Path,
+) -> Result<Vec<PackageKey>, BundleError> {
+    let candidates =
+        crate::repo::resolve_package_closure(store, keys, &[InstallTarget::default()])?;
+
+    let mut ids = HashMap::new();
+    for candidate in candidates.iter() {
+        let key = &candidate.package_key;
+        if ids.insert(key.id.clone(), key.clone()).is_some() {
+            return Err(BundleError::DuplicateId(key.id.clone()));
+        }
+    }
+
+    let mut urls = HashMap::new();
+    let mut descriptors = vec![];
+
+    for candidate in candidates.iter() {
+        let key = &candidate.package_key;
+        let cached = download(store, key).await?;
+
+        let file_name = cached
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let relative_url = format!("{}/{}/{}", PAYLOADS_DIR, key.id, file_name);
+
+        let dest_path = path.join(PAYLOADS_DIR).join(&key.id).join(&file_name);
+        crate::download::link_or_copy(cached, dest_path.clone())
+            .await
+            .map_err(|e| BundleError::Write(dest_path, e))?;
+
+        let url = candidate.target.payload.as_download_url().clone();
+        urls.insert(url, relative_url);
+        descriptors.push(bundled_descriptor(candidate, &ids));
+    }
+
+    write_index(path, &descriptors, &urls)?;
+
+    Ok(candidates.into_iter().map(|x| x.package_key).collect())
+}
+
+async fn download(store: &dyn PackageStore, key: &PackageKey) -> Result<PathBuf, BundleError> {
+    let mut events = store.download(key);
+
+    while let Some(event) = events.next().await {
+        match event {
+            DownloadEvent::Complete(path) => return Ok(path),
+            DownloadEvent::Error(e) => return Err(BundleError::Download(key.clone(), e)),
+            _ => {}
+        }
+    }
+
+    Err(BundleError::Download(key.clone(), DownloadError::UserCancelled))
+}
+
+/// The descriptor of a candidate with only its resolved release and target.
+fn bundled_descriptor(candidate: &PackageCandidate, ids: &HashMap<String, PackageKey>) -> Descriptor {
+    let mut target = candidate.target.clone();
+    target.dependencies = target
+        .dependencies
+        .into_iter()
+        .map(|(key, version)| {
+            let id = PackageKey::try_from(&*key)
+                .ok()
+                .map(|x| x.id)
+                .filter(|id| ids.contains_key(id));
+            (id.unwrap_or(key), version)
+        })
+        .collect();
+
+    let mut release = candidate.release.clone();
+    release.channel = None;
+    release.target = vec![target];
+
+    let mut descriptor = candidate.descriptor.clone();
+    descriptor.release = vec![release];
+    descriptor
+}
+
+fn write_index(
+    path: &Path,
+    descriptors: &[Descriptor],
+    urls: &HashMap<Url, String>,
+) -> Result<(), BundleError> {
+    let packages_path = path.join("packages");
+    std::fs::create_dir_all(&packages_path)
+        .map_err(|e| BundleError::Write(packages_path.clone(), e))?;
+
+    // Replaced with wherever the bundle is loaded from.
+    let url = path
+        .canonicalize()
+        .ok()
+        .and_then(|x| Url::from_directory_path(x).ok())
+        .ok_or_else(|| {
+            BundleError::Write(
+                path.to_path_buf(),
+                std::io::Error::from(std::io::ErrorKind::InvalidInput),
+            )
+        })?;
+
+    let data = RepositoryData::builder().url(url).build();
+    let agent = Agent::builder()
+        .name("pahkat".to_string())
+        .version(env!("CARGO_PKG_VERSION").into())
+        .build();
+    let index = Index::builder().repository(data).agent(agent).build();
+
+    let index_path = path.join("index.toml");
+    std::fs::write(&index_path, toml::to_string(&index)?)
+        .map_err(|e| BundleError::Write(index_path, e))?;
+
+    let payload_url = |url: &Url| urls.get(url).cloned().unwrap_or_else(|| url.to_string());
+    let packages = crate::fbs::builder::build_index_with(descriptors, &payload_url);
+
+    let packages_file_path = packages_path.join("index.bin");
+    std::fs::write(&packages_file_path, packages)
+        .map_err(|e| BundleError::Write(packages_file_path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetworkSettings;
+    use crate::http::HttpClient;
+    use crate::repo::LoadedRepository;
+    use crate::testing::{descriptor, payload, MemoryPackageStore, RepositoryBuilder};
+
+    #[tokio::test]
+    async fn bundle_loads_as_a_repository_with_relative_payloads() {
+        let source = tempfile::tempdir().unwrap();
+        let payload_path = source.path().join("app-1.0.0.bin");
+        std::fs::write(&payload_path, b"payload").unwrap();
+
+        let repo_url = Url::parse("https://pahkat.test/repo/").unwrap();
+        let payload_url = Url::from_file_path(&payload_path).unwrap();
+        let store = MemoryPackageStore::new();
+        store.add_repo(
+            RepositoryBuilder::new(repo_url.clone())
+                .package(descriptor("app", "1.0.0", payload(payload_url)))
+                .build(),
+        );
+
+        let bundle = tempfile::tempdir().unwrap();
+        let key = PackageKey::new_unchecked(repo_url, "app".into(), None);
+        let bundled = create_bundle(&store, &[key], bundle.path()).await.unwrap();
+        assert_eq!(bundled.len(), 1);
+
+        let bundle_url = Url::from_directory_path(bundle.path()).unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (repo, error) = LoadedRepository::refresh(
+            HttpClient::new(&NetworkSettings::default()).unwrap(),
+            bundle_url.clone(),
+            None,
+            cache_dir.path().to_path_buf(),
+            Ok(None),
+            true,
+        )
+        .await;
+        assert!(error.is_none(), "{:?}", error);
+        let repo = repo.unwrap();
+
+        let packages = repo.packages();
+        let pkg = packages.packages().unwrap().get("app").unwrap();
+        let descriptor = crate::fbs::descriptor(&pkg, Some(&bundle_url)).unwrap();
+
+        let url = descriptor.release[0].target[0].payload.as_download_url();
+        assert_eq!(url, &bundle_url.join("payloads/app/app-1.0.0.bin").unwrap());
+        assert_eq!(std::fs::read(url.to_file_path().unwrap()).unwrap(), b"payload");
+    }
+}
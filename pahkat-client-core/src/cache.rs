@@ -0,0 +1,186 @@
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use hashbrown::HashSet;
+use serde::{Deserialize, Serialize};
+
+/// Summary of what was removed from a cache directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheReport {
+    pub bytes_freed: u64,
+    pub files_removed: u64,
+}
+
+impl AddAssign for CacheReport {
+    fn add_assign(&mut self, other: CacheReport) {
+        self.bytes_freed += other.bytes_freed;
+        self.files_removed += other.files_removed;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    size: u64,
+    files: u64,
+    last_used: Option<SystemTime>,
+}
+
+/// Sums the size of every file below `path`, tracking the most recent time
+/// any of them was used. Access times are preferred, but are not available
+/// on every filesystem, so modification times are used as a fallback.
+fn usage(path: &Path) -> Usage {
+    let mut usage = Usage::default();
+
+    let entries = match std::fs::read_dir(path) {
+        Ok(v) => v,
+        Err(_) => return usage,
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let meta = match entry.metadata() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+
+        if meta.is_dir() {
+            let child = self::usage(&entry.path());
+            usage.size += child.size;
+            usage.files += child.files;
+            usage.last_used = usage.last_used.max(child.last_used);
+        } else {
+            let last_used = meta.accessed().or_else(|_| meta.modified()).ok();
+            usage.size += meta.len();
+            usage.files += 1;
+            usage.last_used = usage.last_used.max(last_used);
+        }
+    }
+
+    usage
+}
+
+fn remove(path: &Path, is_dir: bool) -> std::io::Result<CacheReport> {
+    let (bytes_freed, files_removed) = if is_dir {
+        let usage = usage(path);
+        std::fs::remove_dir_all(path)?;
+        (usage.size, usage.files)
+    } else {
+        let size = std::fs::metadata(path)?.len();
+        std::fs::remove_file(path)?;
+        (size, 1)
+    };
+
+    Ok(CacheReport {
+        bytes_freed,
+        files_removed,
+    })
+}
+
+/// Removes everything inside `dir`, leaving the directory itself in place.
+pub(crate) fn purge(dir: &Path) -> CacheReport {
+    let mut report = CacheReport::default();
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(e) => {
+            log::trace!("Could not read cache dir {:?}: {:?}", dir, e);
+            return report;
+        }
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let is_dir = entry.file_type().map(|x| x.is_dir()).unwrap_or(false);
+
+        match remove(&path, is_dir) {
+            Ok(v) => report += v,
+            Err(e) => log::warn!("Could not remove {:?} from cache: {:?}", &path, e),
+        }
+    }
+
+    log::debug!("Purged {:?}: {:?}", dir, &report);
+    report
+}
+
+/// Lists the directories holding a single payload each. These are always
+/// three levels below the package cache, see `repo::download_dir`.
+fn payload_dirs(package_cache_dir: &Path) -> Vec<PathBuf> {
+    fn subdirs(path: &Path) -> Vec<PathBuf> {
+        match std::fs::read_dir(path) {
+            Ok(v) => v
+                .filter_map(Result::ok)
+                .filter(|x| x.file_type().map(|x| x.is_dir()).unwrap_or(false))
+                .map(|x| x.path())
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    subdirs(package_cache_dir)
+        .iter()
+        .flat_map(|x| subdirs(x))
+        .flat_map(|x| subdirs(&x))
+        .collect()
+}
+
+/// Removes the least recently used payloads from the package cache until it
+/// is no larger than `limit` bytes. Payloads in `protected` are never removed.
+pub(crate) fn evict(
+    package_cache_dir: &Path,
+    limit: u64,
+    protected: &HashSet<PathBuf>,
+) -> CacheReport {
+    let mut report = CacheReport::default();
+
+    let mut payloads = payload_dirs(package_cache_dir)
+        .into_iter()
+        .map(|path| {
+            let usage = usage(&path);
+            (path, usage)
+        })
+        .collect::<Vec<_>>();
+
+    let mut total = payloads.iter().fold(0, |acc, (_, usage)| acc + usage.size);
+    log::debug!("Package cache size: {} bytes, limit: {} bytes", total, limit);
+
+    if total <= limit {
+        return report;
+    }
+
+    payloads.sort_by(|(_, a), (_, b)| a.last_used.cmp(&b.last_used));
+
+    for (path, usage) in payloads.into_iter() {
+        if total <= limit {
+            break;
+        }
+
+        if protected.contains(&path) {
+            log::trace!("Keeping {:?}, required by an installed package", &path);
+            continue;
+        }
+
+        match std::fs::remove_dir_all(&path) {
+            Ok(_) => {
+                total -= usage.size;
+                report += CacheReport {
+                    bytes_freed: usage.size,
+                    files_removed: usage.files,
+                };
+
+                // Clean up the now possibly empty hash prefix directories.
+                let mut parent = path.parent();
+                while let Some(dir) = parent {
+                    if dir == package_cache_dir || std::fs::remove_dir(dir).is_err() {
+                        break;
+                    }
+                    parent = dir.parent();
+                }
+            }
+            Err(e) => log::warn!("Could not evict {:?} from cache: {:?}", &path, e),
+        }
+    }
+
+    log::debug!("Evicted from package cache: {:?}", &report);
+    report
+}
@@ -1,14 +1,21 @@
+mod credentials;
+mod layers;
 pub(crate) mod path;
 mod repos;
 mod settings;
+mod watch;
 
+pub use credentials::{Credential, CredentialError, Credentials, CredentialsData};
+pub use layers::{env_overrides, ConfigLayer, ConfigLoader};
 pub use path::ConfigPath;
 pub use repos::{RepoRecord, Repos, ReposData};
-pub use settings::{Settings, SettingsData};
+pub use settings::{NetworkSettings, ResolutionPolicy, Settings, SettingsData};
+pub use watch::{watch, ConfigEvent};
 
 use std::path::{Path, PathBuf};
 
 use thiserror::Error;
+use url::Url;
 
 use crate::defaults;
 
@@ -17,11 +24,20 @@ pub enum Error {
     #[error("No default configuration path found for this platform")]
     NoDefaultConfigPath,
 
+    #[error("No configuration path was given")]
+    NoConfigPath,
+
     #[error("Error loading repos.toml file")]
     ReposFile(#[source] FileError),
 
     #[error("Error loading settings.toml file")]
     SettingsFile(#[source] FileError),
+
+    #[error("Error loading credentials.toml file")]
+    CredentialsFile(#[source] FileError),
+
+    #[error("Invalid setting in PAHKAT_* environment variables")]
+    Environment(#[source] toml::de::Error),
 }
 
 #[derive(Debug, Error)]
@@ -29,6 +45,12 @@ pub enum FileError {
     #[error("The file {0} is read only and could not be written to.")]
     ReadOnly(PathBuf),
 
+    #[error("{0} is configured by the read only {1} configuration.")]
+    ReadOnlyLayer(String, ConfigLayer),
+
+    #[error("The repository alias `{0}` is already used by {1}.")]
+    DuplicateAlias(String, Url),
+
     #[error("Could not read file: {1}")]
     Read(#[source] std::io::Error, PathBuf),
 
@@ -52,6 +74,22 @@ pub enum FileError {
 pub struct Config {
     repos: Repos,
     settings: Settings,
+    credentials: Credentials,
+    /// How this config was loaded, so that it can be loaded again.
+    loader: Option<ConfigLoader>,
+}
+
+/// What differs between a config and the one that replaced it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConfigChange {
+    pub settings: bool,
+    pub repos: bool,
+}
+
+impl ConfigChange {
+    pub fn is_empty(&self) -> bool {
+        !self.settings && !self.repos
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -65,47 +103,64 @@ impl Config {
         Config {
             repos: Repos::read_only(),
             settings: Settings::read_only(),
+            credentials: Credentials::read_only(),
+            loader: None,
         }
     }
 
+    /// Loads the system configuration, overridden by the configuration of
+    /// the current user and then by `PAHKAT_*` environment variables.
     #[cfg(not(target_os = "android"))]
     pub fn load_default() -> Result<Config, Error> {
         let path = defaults::config_path().ok_or(Error::NoDefaultConfigPath)?;
-        Self::load(path, Permission::ReadWrite)
+        let mut loader = ConfigLoader::new();
+
+        // The user is the system when running as root or SYSTEM.
+        if let Some(system_path) = defaults::system_config_path().filter(|x| x != &path) {
+            loader = loader.system(system_path);
+        }
+
+        loader.user(path, Permission::ReadWrite).env().load()
     }
 
+    /// Loads the configuration in a single directory, without any other
+    /// layers. Use `ConfigLoader` to combine several.
     pub fn load<P: AsRef<Path>>(path: P, permission: Permission) -> Result<Config, Error> {
-        let config_path = path.as_ref();
+        ConfigLoader::new().user(path, permission).load()
+    }
 
-        let settings_path = config_path.join("settings.toml");
+    pub fn new(settings: Settings, repos: Repos) -> Config {
+        Config {
+            repos,
+            settings,
+            credentials: Credentials::read_only(),
+            loader: None,
+        }
+    }
 
-        let settings = match Settings::load(&settings_path, permission) {
-            Ok(v) => v,
-            Err(FileError::Read(_, _)) if permission != Permission::ReadOnly => {
-                Settings::create(&settings_path).map_err(Error::SettingsFile)?
-            }
-            Err(e) => return Err(Error::SettingsFile(e)),
+    /// Loads the files this config was loaded from again, replacing it only
+    /// if they are all valid. A config that was not loaded from files is left
+    /// as is.
+    pub fn reload(&mut self) -> Result<ConfigChange, Error> {
+        let loader = match self.loader.as_ref() {
+            Some(v) => v.clone(),
+            None => return Ok(ConfigChange::default()),
         };
 
-        let repos_path = config_path.join("repos.toml");
-
-        let repos = match Repos::load(&repos_path, permission) {
-            Ok(v) => v,
-            Err(FileError::Read(_, _)) if permission != Permission::ReadOnly => {
-                Repos::create(&repos_path).map_err(Error::ReposFile)?
-            }
-            Err(e) => return Err(Error::ReposFile(e)),
+        let config = loader.load()?;
+        let change = ConfigChange {
+            settings: config.settings.data() != self.settings.data(),
+            repos: config.repos.data() != self.repos.data(),
         };
 
-        let config = Config { repos, settings };
-
-        log::trace!("Config loaded: {:#?}", &config);
-
-        Ok(config)
+        *self = config;
+        Ok(change)
     }
 
-    pub fn new(settings: Settings, repos: Repos) -> Config {
-        Config { repos, settings }
+    /// The files this config was loaded from, including those that do not
+    /// exist yet.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.loader.as_ref().map(|x| x.files()).unwrap_or_default()
     }
 
     pub fn repos(&self) -> &Repos {
@@ -123,4 +178,38 @@ impl Config {
     pub fn settings_mut(&mut self) -> &mut Settings {
         &mut self.settings
     }
+
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    pub fn credentials_mut(&mut self) -> &mut Credentials {
+        &mut self.credentials
+    }
+
+    /// The credential to send with a request to `url` on behalf of the
+    /// repository at `repo_url`.
+    ///
+    /// Credentials are only ever sent to the same origin as the repository,
+    /// so payloads hosted elsewhere never receive them.
+    pub fn credential_for(
+        &self,
+        repo_url: &Url,
+        url: &Url,
+    ) -> Result<Option<Credential>, CredentialError> {
+        if repo_url.origin() != url.origin() {
+            return Ok(None);
+        }
+
+        let name = match self.repos.get(repo_url).and_then(|x| x.credentials.as_ref()) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        self.credentials
+            .get(name)
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| CredentialError::Missing(name.to_string()))
+    }
 }
@@ -0,0 +1,175 @@
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::FileError;
+use crate::config::Permission;
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum CredentialError {
+    #[error("No credentials named `{0}` found in credentials.toml")]
+    Missing(String),
+
+    #[error("Credentials were rejected by {0}")]
+    Rejected(Url),
+
+    #[error("{0} requires credentials, but none are configured")]
+    Required(Url),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Credential {
+    Basic {
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+    Bearer {
+        token: String,
+    },
+}
+
+// Config is logged at trace level, so secrets must never be printed.
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credential::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+            Credential::Bearer { .. } => f
+                .debug_struct("Bearer")
+                .field("token", &"<redacted>")
+                .finish(),
+        }
+    }
+}
+
+impl Credential {
+    pub(crate) fn apply(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self {
+            Credential::Basic { username, password } => request.basic_auth(username, password.as_ref()),
+            Credential::Bearer { token } => request.bearer_auth(token),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(transparent)]
+pub struct CredentialsData(IndexMap<String, Credential>);
+
+impl CredentialsData {
+    fn load<P: AsRef<Path>>(path: P) -> Result<CredentialsData, FileError> {
+        let file = std::fs::read_to_string(&path)
+            .map_err(|e| FileError::Read(e, path.as_ref().to_path_buf()))?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            if let Ok(meta) = std::fs::metadata(&path) {
+                if meta.permissions().mode() & 0o077 != 0 {
+                    log::warn!(
+                        "{:?} is readable by other users; it should only be accessible by its owner.",
+                        path.as_ref()
+                    );
+                }
+            }
+        }
+
+        let file = toml::from_str(&file)
+            .map_err(|e| FileError::FromToml(e, path.as_ref().to_path_buf()))?;
+        Ok(file)
+    }
+
+    fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), FileError> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+
+        // Only the owner may read or write the file.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options
+            .open(&path)
+            .map_err(|e| FileError::Write(e, path.as_ref().to_path_buf()))?;
+        let b =
+            toml::to_vec(&self).map_err(|e| FileError::ToToml(e, path.as_ref().to_path_buf()))?;
+        file.write_all(&b)
+            .map_err(|e| FileError::Write(e, path.as_ref().to_path_buf()))?;
+        Ok(())
+    }
+}
+
+/// Named credentials, referenced from `RepoRecord::credentials`.
+///
+/// These are kept in their own file so that repos.toml can be shared freely.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    path: PathBuf,
+    data: CredentialsData,
+    permission: Permission,
+}
+
+impl std::ops::Deref for Credentials {
+    type Target = IndexMap<String, Credential>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data.0
+    }
+}
+
+impl Credentials {
+    pub fn read_only() -> Credentials {
+        Credentials {
+            path: PathBuf::from("/dev/null"),
+            data: CredentialsData::default(),
+            permission: Permission::ReadOnly,
+        }
+    }
+
+    /// Loads the credentials file. A missing file is treated as empty, and is
+    /// only created once a credential is inserted.
+    pub fn load<P: AsRef<Path>>(path: P, permission: Permission) -> Result<Credentials, FileError> {
+        let data = if path.as_ref().exists() {
+            CredentialsData::load(path.as_ref())?
+        } else {
+            CredentialsData::default()
+        };
+
+        Ok(Credentials {
+            path: path.as_ref().to_path_buf(),
+            data,
+            permission,
+        })
+    }
+
+    pub fn insert(&mut self, name: String, value: Credential) -> Result<(), FileError> {
+        self.data.0.insert(name, value);
+
+        if self.permission == Permission::ReadWrite {
+            return self.data.save(&self.path);
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<bool, FileError> {
+        let result = self.data.0.remove(name).is_some();
+
+        if self.permission == Permission::ReadWrite {
+            self.data.save(&self.path)?;
+        }
+
+        Ok(result)
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use toml::value::{Table, Value};
+
+use super::{Config, Credentials, Error, Permission, Repos, Settings};
+
+/// Prefix of the environment variables that override settings.
+const ENV_PREFIX: &str = "PAHKAT_";
+
+/// Where a configuration value came from. Later layers take precedence over
+/// earlier ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ConfigLayer {
+    /// The built in default, as no layer sets the value.
+    Default,
+    /// Machine wide configuration, such as `/etc/pahkat`. It is never written
+    /// to.
+    System,
+    User,
+    Prefix,
+    /// `PAHKAT_*` environment variables, which only override settings.
+    Environment,
+}
+
+impl fmt::Display for ConfigLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConfigLayer::Default => "default",
+            ConfigLayer::System => "system",
+            ConfigLayer::User => "user",
+            ConfigLayer::Prefix => "prefix",
+            ConfigLayer::Environment => "environment",
+        })
+    }
+}
+
+/// A configuration directory and how it may be used.
+#[derive(Debug, Clone)]
+pub(super) struct Layer {
+    pub(super) kind: ConfigLayer,
+    pub(super) dir: PathBuf,
+    pub(super) permission: Permission,
+}
+
+/// Loads a `Config` from several configuration directories.
+///
+/// Settings are merged key by key, including keys of tables such as
+/// `network`, and repositories are merged by URL. Changes are written to the
+/// layer with the highest precedence, whose files are created if missing
+/// unless it is read only. Credentials belong to whoever runs pahkat, so they
+/// are only read from that layer.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigLoader {
+    layers: Vec<Layer>,
+    env: bool,
+}
+
+impl ConfigLoader {
+    pub fn new() -> ConfigLoader {
+        ConfigLoader::default()
+    }
+
+    /// Machine wide configuration, which is always read only.
+    pub fn system<P: AsRef<Path>>(self, path: P) -> ConfigLoader {
+        self.layer(ConfigLayer::System, path, Permission::ReadOnly)
+    }
+
+    pub fn user<P: AsRef<Path>>(self, path: P, permission: Permission) -> ConfigLoader {
+        self.layer(ConfigLayer::User, path, permission)
+    }
+
+    pub fn prefix<P: AsRef<Path>>(self, path: P, permission: Permission) -> ConfigLoader {
+        self.layer(ConfigLayer::Prefix, path, permission)
+    }
+
+    /// Overrides settings with `PAHKAT_*` environment variables. See
+    /// `env_overrides` for how they are named.
+    pub fn env(mut self) -> ConfigLoader {
+        self.env = true;
+        self
+    }
+
+    /// Every file that is loaded, whether or not it exists.
+    pub(super) fn files(&self) -> Vec<PathBuf> {
+        self.layers
+            .iter()
+            .flat_map(|layer| {
+                ["settings.toml", "repos.toml", "credentials.toml"]
+                    .iter()
+                    .map(move |name| layer.dir.join(name))
+            })
+            .collect()
+    }
+
+    fn layer<P: AsRef<Path>>(
+        mut self,
+        kind: ConfigLayer,
+        path: P,
+        permission: Permission,
+    ) -> ConfigLoader {
+        self.layers.push(Layer {
+            kind,
+            dir: path.as_ref().to_path_buf(),
+            permission,
+        });
+        self
+    }
+
+    pub fn load(mut self) -> Result<Config, Error> {
+        self.layers.sort_by_key(|x| x.kind);
+        let top = self.layers.last().ok_or(Error::NoConfigPath)?;
+
+        let env = if self.env {
+            Some(env_overrides(std::env::vars()))
+        } else {
+            None
+        };
+
+        let settings = Settings::load_layered(&self.layers, env)?;
+        let repos = Repos::load_layered(&self.layers).map_err(Error::ReposFile)?;
+        let credentials = Credentials::load(top.dir.join("credentials.toml"), top.permission)
+            .map_err(Error::CredentialsFile)?;
+
+        let config = Config {
+            repos,
+            settings,
+            credentials,
+            loader: Some(self),
+        };
+
+        log::trace!("Config loaded: {:#?}", &config);
+
+        Ok(config)
+    }
+}
+
+/// The settings set by environment variables. The name after `PAHKAT_` is the
+/// key in lowercase, with `__` separating tables, so `PAHKAT_CACHE_SIZE_LIMIT`
+/// sets `cache_size_limit` and `PAHKAT_NETWORK__PROXY` sets `network.proxy`.
+/// Values are parsed as TOML where possible, and are strings otherwise.
+pub fn env_overrides<I: Iterator<Item = (String, String)>>(vars: I) -> Table {
+    let mut table = Table::new();
+
+    for (name, raw_value) in vars {
+        if !name.starts_with(ENV_PREFIX) || name.len() == ENV_PREFIX.len() {
+            continue;
+        }
+
+        let name = name[ENV_PREFIX.len()..].to_lowercase();
+        let mut keys = name.split("__").collect::<Vec<_>>();
+        let last = keys.pop().unwrap();
+
+        let mut parent = &mut table;
+        for key in keys {
+            let entry = parent
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Table(Table::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            parent = entry.as_table_mut().unwrap();
+        }
+
+        parent.insert(last.to_string(), parse_value(&raw_value));
+    }
+
+    table
+}
+
+fn parse_value(raw_value: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", raw_value))
+        .ok()
+        .and_then(|mut x| x.remove("value"))
+        .unwrap_or_else(|| Value::String(raw_value.to_string()))
+}
+
+/// Merges `table` into `into`, recording `layer` as the source of every value
+/// it sets by its dotted key.
+pub(super) fn merge(
+    into: &mut Table,
+    table: Table,
+    layer: ConfigLayer,
+    prefix: &str,
+    sources: &mut BTreeMap<String, ConfigLayer>,
+) {
+    for (key, value) in table {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+
+        match value {
+            Value::Table(value) => {
+                let entry = into
+                    .entry(key)
+                    .or_insert_with(|| Value::Table(Table::new()));
+                if !entry.is_table() {
+                    *entry = Value::Table(Table::new());
+                }
+                merge(entry.as_table_mut().unwrap(), value, layer, &path, sources);
+            }
+            value => {
+                sources.insert(path, layer);
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(toml: &str) -> Table {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn merge_overrides_keys_of_nested_tables() {
+        let mut merged = Table::new();
+        let mut sources = BTreeMap::new();
+
+        merge(
+            &mut merged,
+            table("cache_size_limit = 1\n[network]\nretries = 2\nread_timeout = 3"),
+            ConfigLayer::System,
+            "",
+            &mut sources,
+        );
+        merge(
+            &mut merged,
+            table("[network]\nretries = 5"),
+            ConfigLayer::User,
+            "",
+            &mut sources,
+        );
+
+        assert_eq!(
+            merged,
+            table("cache_size_limit = 1\n[network]\nretries = 5\nread_timeout = 3")
+        );
+        assert_eq!(sources["cache_size_limit"], ConfigLayer::System);
+        assert_eq!(sources["network.retries"], ConfigLayer::User);
+        assert_eq!(sources["network.read_timeout"], ConfigLayer::System);
+        assert!(!sources.contains_key("network"));
+    }
+
+    #[test]
+    fn merge_replaces_a_value_with_a_table() {
+        let mut merged = table("network = 1");
+        let mut sources = BTreeMap::new();
+
+        merge(
+            &mut merged,
+            table("[network]\nretries = 2"),
+            ConfigLayer::Prefix,
+            "",
+            &mut sources,
+        );
+
+        assert_eq!(merged, table("[network]\nretries = 2"));
+        assert_eq!(sources["network.retries"], ConfigLayer::Prefix);
+    }
+
+    #[test]
+    fn env_overrides_only_reads_pahkat_variables() {
+        let vars = vec![
+            ("PAHKAT_CACHE_SIZE_LIMIT", "1024"),
+            ("PAHKAT_NETWORK__PROXY", "http://proxy.example:3128"),
+            ("PAHKAT_NETWORK__NO_PROXY", r#"["example.com"]"#),
+            ("PAHKAT_", "ignored"),
+            ("PATH", "/usr/bin"),
+        ];
+
+        let overrides = env_overrides(
+            vars.into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        assert_eq!(
+            overrides,
+            table(
+                r#"
+                cache_size_limit = 1024
+                [network]
+                proxy = "http://proxy.example:3128"
+                no_proxy = ["example.com"]
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn env_overrides_replace_a_value_with_a_table() {
+        let vars = vec![("PAHKAT_NETWORK", "1"), ("PAHKAT_NETWORK__RETRIES", "2")];
+
+        let overrides = env_overrides(
+            vars.into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        assert_eq!(overrides, table("[network]\nretries = 2"));
+    }
+
+    #[test]
+    fn settings_know_the_layer_of_each_key() {
+        let cache = tempfile::tempdir().unwrap();
+        let system = tempfile::tempdir().unwrap();
+        let user = tempfile::tempdir().unwrap();
+
+        std::fs::write(
+            system.path().join("settings.toml"),
+            format!(
+                "cache_dir = '{}'\ncache_size_limit = 1\n[network]\nretries = 2",
+                cache.path().display()
+            ),
+        )
+        .unwrap();
+        std::fs::write(user.path().join("settings.toml"), "cache_size_limit = 5").unwrap();
+
+        let config = ConfigLoader::new()
+            .user(user.path(), Permission::ReadWrite)
+            .system(system.path())
+            .load()
+            .unwrap();
+        let settings = config.settings();
+
+        assert_eq!(settings.cache_size_limit(), 5);
+        assert_eq!(settings.network().retries, 2);
+        assert_eq!(settings.source("cache_size_limit"), ConfigLayer::User);
+        assert_eq!(settings.source("network.retries"), ConfigLayer::System);
+        assert_eq!(settings.source("progress_interval"), ConfigLayer::Default);
+    }
+
+    #[test]
+    fn environment_overrides_every_layer() {
+        let cache = tempfile::tempdir().unwrap();
+        let prefix = tempfile::tempdir().unwrap();
+
+        std::fs::write(
+            prefix.path().join("settings.toml"),
+            format!(
+                "cache_dir = '{}'\ncache_size_limit = 1",
+                cache.path().display()
+            ),
+        )
+        .unwrap();
+
+        let layers = [Layer {
+            kind: ConfigLayer::Prefix,
+            dir: prefix.path().to_path_buf(),
+            permission: Permission::ReadWrite,
+        }];
+        let env = table("cache_size_limit = 9");
+
+        let settings = Settings::load_layered(&layers, Some(env)).unwrap();
+
+        assert_eq!(settings.cache_size_limit(), 9);
+        assert_eq!(
+            settings.source("cache_size_limit"),
+            ConfigLayer::Environment
+        );
+        assert_eq!(settings.source("cache_dir"), ConfigLayer::Prefix);
+    }
+}
@@ -6,15 +6,36 @@ use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
+use super::layers::{ConfigLayer, Layer};
 use super::FileError;
 use crate::config::Permission;
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[inline(always)]
+fn is_zero(value: &i32) -> bool {
+    *value == 0
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RepoRecord {
     pub channel: Option<String>,
+    /// Repositories with a higher priority are preferred when more than one
+    /// provides the same package id. Equal priorities fall back to the order
+    /// of repos.toml.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: i32,
+    /// Name of an entry in credentials.toml to authenticate with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<String>,
+    /// Ids of packages to keep at their installed version when updating.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned: Vec<String>,
+    /// Short name of the repository, so that its packages can be given as
+    /// `alias:id` instead of by URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
 #[serde(transparent)]
 pub struct ReposData(IndexMap<Url, RepoRecord>);
 
@@ -60,7 +81,13 @@ impl ReposData {
 #[derive(Debug, Clone)]
 pub struct Repos {
     path: PathBuf,
+    /// Every repository, with those of this layer overriding inherited ones.
     data: ReposData,
+    /// The repositories of this layer, which are the ones saved to `path`.
+    own: ReposData,
+    /// The repositories of the read only layers below this one.
+    inherited: IndexMap<Url, (ConfigLayer, RepoRecord)>,
+    layer: ConfigLayer,
     permission: Permission,
 }
 
@@ -79,79 +106,203 @@ impl std::ops::DerefMut for Repos {
 }
 
 impl Repos {
-    pub fn read_only() -> Repos {
+    fn new(path: PathBuf, own: ReposData, layer: ConfigLayer, permission: Permission) -> Repos {
         Repos {
-            path: PathBuf::from("/dev/null"),
-            data: ReposData::default(),
-            permission: Permission::ReadOnly,
+            path,
+            data: own.clone(),
+            own,
+            inherited: IndexMap::new(),
+            layer,
+            permission,
         }
     }
 
+    pub fn read_only() -> Repos {
+        Repos::new(
+            PathBuf::from("/dev/null"),
+            ReposData::default(),
+            ConfigLayer::User,
+            Permission::ReadOnly,
+        )
+    }
+
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Repos, FileError> {
         let data = ReposData::create(path.as_ref())?;
-
-        Ok(Repos {
-            path: path.as_ref().to_path_buf(),
+        Ok(Repos::new(
+            path.as_ref().to_path_buf(),
             data,
-            permission: Permission::ReadWrite,
-        })
+            ConfigLayer::User,
+            Permission::ReadWrite,
+        ))
     }
 
     pub fn load<P: AsRef<Path>>(path: P, permission: Permission) -> Result<Repos, FileError> {
         let data = ReposData::load(path.as_ref())?;
-
-        Ok(Repos {
-            path: path.as_ref().to_path_buf(),
+        Ok(Repos::new(
+            path.as_ref().to_path_buf(),
             data,
+            ConfigLayer::User,
             permission,
-        })
+        ))
     }
 
-    fn reload(&mut self) -> Result<(), FileError> {
-        if self.permission == Permission::ReadOnly {
-            return Err(FileError::ReadOnly(self.path.clone()));
+    /// Merges the repositories of every layer, where a later layer overrides
+    /// the record of a repository in an earlier one. Only the last layer is
+    /// written to, and its file is created if missing unless it is read only.
+    pub(super) fn load_layered(layers: &[Layer]) -> Result<Repos, FileError> {
+        let (top, below) = match layers.split_last() {
+            Some(v) => v,
+            None => return Ok(Repos::read_only()),
+        };
+
+        let mut inherited = IndexMap::new();
+        for layer in below {
+            let path = layer.dir.join("repos.toml");
+            if !path.exists() {
+                continue;
+            }
+
+            for (url, record) in ReposData::load(&path)?.0 {
+                inherited.insert(url, (layer.kind, record));
+            }
         }
-        self.data = ReposData::load(&self.path)?;
-        Ok(())
+
+        let path = top.dir.join("repos.toml");
+        let own = if path.exists() {
+            ReposData::load(&path)?
+        } else if top.permission != Permission::ReadOnly {
+            ReposData::create(&path)?
+        } else {
+            ReposData::default()
+        };
+
+        let mut repos = Repos::new(path, own, top.kind, top.permission);
+        repos.inherited = inherited;
+        repos.merge();
+        Ok(repos)
+    }
+
+    fn merge(&mut self) {
+        let mut data = self
+            .inherited
+            .iter()
+            .map(|(url, (_, record))| (url.clone(), record.clone()))
+            .collect::<IndexMap<_, _>>();
+
+        for (url, record) in self.own.0.iter() {
+            data.insert(url.clone(), record.clone());
+        }
+
+        self.data = ReposData(data);
     }
 
     fn save(&self) -> Result<(), FileError> {
         if self.permission == Permission::ReadOnly {
             return Err(FileError::ReadOnly(self.path.clone()));
         }
-        self.data.save(&self.path)
+        self.own.save(&self.path)
+    }
+
+    /// Fails if an alias in `records` belongs to more than one repository.
+    fn check_aliases<'a, I>(records: I) -> Result<(), FileError>
+    where
+        I: IntoIterator<Item = (&'a Url, &'a RepoRecord)>,
+    {
+        let mut seen = IndexMap::new();
+
+        for (url, record) in records {
+            if let Some(alias) = record.alias.as_ref() {
+                if let Some(other) = seen.insert(alias, url) {
+                    if other != url {
+                        return Err(FileError::DuplicateAlias(alias.to_string(), other.clone()));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The repository named `alias`.
+    pub fn find_alias(&self, alias: &str) -> Option<&Url> {
+        self.data
+            .0
+            .iter()
+            .find(|(_, record)| record.alias.as_deref() == Some(alias))
+            .map(|(url, _)| url)
     }
 
+    /// Replaces the repositories of this layer with those in `data` that
+    /// differ from the inherited ones. Inherited repositories missing from
+    /// `data` stay configured, as their layers are read only.
     pub fn set(&mut self, data: ReposData) -> Result<(), FileError> {
-        self.data = data;
+        let others = self
+            .inherited
+            .iter()
+            .filter(|(url, _)| !data.0.contains_key(*url))
+            .map(|(url, (_, record))| (url, record));
+        Repos::check_aliases(data.0.iter().chain(others))?;
+
+        let inherited = &self.inherited;
+        self.own = ReposData(
+            data.0
+                .into_iter()
+                .filter(|(url, record)| inherited.get(url).map(|x| &x.1) != Some(record))
+                .collect(),
+        );
+        self.merge();
 
         if self.permission == Permission::ReadWrite {
-            return self.data.save(&self.path);
+            return self.own.save(&self.path);
         }
 
         Ok(())
     }
 
     pub fn insert(&mut self, key: Url, value: RepoRecord) -> Result<(), FileError> {
-        self.data.0.insert(key, value);
+        let others = self.data.0.iter().filter(|(url, _)| *url != &key);
+        Repos::check_aliases(others.chain(std::iter::once((&key, &value))))?;
+
+        self.own.0.insert(key, value);
+        self.merge();
 
         if self.permission == Permission::ReadWrite {
-            return self.data.save(&self.path);
+            return self.own.save(&self.path);
         }
 
         Ok(())
     }
 
+    /// Removes a repository from this layer. If a lower layer also configures
+    /// it, its record from that layer applies again. Repositories only
+    /// configured by a lower layer cannot be removed.
     pub fn remove(&mut self, key: &Url) -> Result<bool, FileError> {
-        let result = self.data.0.remove(key).is_some();
+        let result = self.own.0.remove(key).is_some();
+
+        if !result {
+            if let Some((layer, _)) = self.inherited.get(key) {
+                return Err(FileError::ReadOnlyLayer(key.to_string(), *layer));
+            }
+        }
+
+        self.merge();
 
         if self.permission == Permission::ReadWrite {
-            self.data.save(&self.path)?;
+            self.own.save(&self.path)?;
         }
 
         Ok(result)
     }
 
+    /// The layer that configures the repository at `key`.
+    pub fn source(&self, key: &Url) -> Option<ConfigLayer> {
+        if self.own.0.contains_key(key) {
+            return Some(self.layer);
+        }
+
+        self.inherited.get(key).map(|x| x.0)
+    }
+
     pub fn data(&self) -> &ReposData {
         &self.data
     }
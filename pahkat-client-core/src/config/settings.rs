@@ -1,15 +1,90 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
+use toml::value::{Table, Value};
+use url::Url;
 
+use super::layers::{ConfigLayer, Layer};
 use super::path::ConfigPath;
-use super::FileError;
+use super::{Error, FileError};
 use crate::config::Permission;
 use crate::defaults;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// How a bare package id is resolved when more than one repository provides it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResolutionPolicy {
+    /// Use the repository with the highest priority, warning about the others.
+    FirstMatch,
+    /// Use whichever repository offers the highest version.
+    HighestVersion,
+    /// Refuse to resolve the id at all.
+    Strict,
+}
+
+impl Default for ResolutionPolicy {
+    fn default() -> Self {
+        ResolutionPolicy::FirstMatch
+    }
+}
+
+/// Settings for every HTTP request made by the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkSettings {
+    /// Proxy to send all requests through. When unset, the system proxy
+    /// environment variables are used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<Url>,
+    /// Hosts that bypass `proxy`. An entry also matches all of its subdomains.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_proxy: Vec<String>,
+    /// Extra PEM or DER encoded CA certificates to trust.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ca_certificates: Vec<PathBuf>,
+    /// Seconds to wait for a connection to be established.
+    #[serde(default = "defaults::connect_timeout")]
+    pub connect_timeout: u64,
+    /// Seconds to wait for a response, or for the next chunk of a download.
+    #[serde(default = "defaults::read_timeout")]
+    pub read_timeout: u64,
+    /// How many times a download is retried after a transient failure.
+    #[serde(default = "defaults::download_retries")]
+    pub retries: u32,
+    /// Seconds to wait before the first retry. This doubles on every attempt.
+    #[serde(default = "defaults::retry_delay")]
+    pub retry_delay: u64,
+    /// Bandwidth limit for downloads in bytes per second. Zero means no limit.
+    #[serde(default)]
+    pub download_limit: u64,
+    /// Bandwidth limit for downloads started in the background, such as
+    /// automatic updates, in bytes per second. Zero means no limit.
+    #[serde(default)]
+    pub background_download_limit: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> NetworkSettings {
+        NetworkSettings {
+            proxy: None,
+            no_proxy: vec![],
+            ca_certificates: vec![],
+            connect_timeout: defaults::connect_timeout(),
+            read_timeout: defaults::read_timeout(),
+            retries: defaults::download_retries(),
+            retry_delay: defaults::retry_delay(),
+            download_limit: 0,
+            background_download_limit: 0,
+            user_agent: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SettingsData {
     #[serde(default = "defaults::cache_dir")]
     pub cache_dir: ConfigPath,
@@ -17,6 +92,25 @@ pub struct SettingsData {
     pub tmp_dir: ConfigPath,
     #[serde(default)]
     pub max_concurrent_downloads: u8,
+    /// Maximum size of the package cache in bytes. Zero means no limit.
+    #[serde(default)]
+    pub cache_size_limit: u64,
+    #[serde(default)]
+    pub resolution_policy: ResolutionPolicy,
+    /// Minimum milliseconds between download progress events.
+    #[serde(default = "defaults::progress_interval")]
+    pub progress_interval: u64,
+    /// Directory of hooks to run around transactions, relative to the config
+    /// directory that sets it. See `transaction::hooks::from_dir` for its
+    /// layout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hooks_dir: Option<PathBuf>,
+    /// Seconds a hook may run before it is killed and counted as failed.
+    #[serde(default = "defaults::hook_timeout")]
+    pub hook_timeout: u64,
+    // TOML requires tables to come after plain values, so this must stay last.
+    #[serde(default)]
+    pub network: NetworkSettings,
 }
 
 impl Default for SettingsData {
@@ -25,6 +119,12 @@ impl Default for SettingsData {
             cache_dir: defaults::cache_dir(),
             tmp_dir: defaults::tmp_dir(),
             max_concurrent_downloads: 0,
+            cache_size_limit: 0,
+            resolution_policy: ResolutionPolicy::default(),
+            progress_interval: defaults::progress_interval(),
+            hooks_dir: None,
+            hook_timeout: defaults::hook_timeout(),
+            network: NetworkSettings::default(),
         }
     }
 }
@@ -55,11 +155,31 @@ impl SettingsData {
     }
 }
 
+/// Reads a settings file without applying defaults, so that it only overrides
+/// the keys it sets. Relative paths are resolved against `dir`, as they would
+/// otherwise be resolved against whichever layer is written to.
+fn load_table(path: &Path, dir: &Path) -> Result<Table, FileError> {
+    let file = std::fs::read_to_string(path).map_err(|e| FileError::Read(e, path.to_path_buf()))?;
+    let mut table: Table =
+        toml::from_str(&file).map_err(|e| FileError::FromToml(e, path.to_path_buf()))?;
+
+    // Reject invalid values here, where it is still known which file they are from.
+    SettingsData::deserialize(Value::Table(table.clone()))
+        .map_err(|e| FileError::FromToml(e, path.to_path_buf()))?;
+
+    if let Some(Value::String(hooks_dir)) = table.get_mut("hooks_dir") {
+        *hooks_dir = dir.join(&*hooks_dir).to_string_lossy().to_string();
+    }
+
+    Ok(table)
+}
+
 #[derive(Debug, Clone)]
 pub struct Settings {
     path: PathBuf,
     data: SettingsData,
     permission: Permission,
+    sources: BTreeMap<String, ConfigLayer>,
 }
 
 impl Settings {
@@ -68,6 +188,7 @@ impl Settings {
             path: PathBuf::from("/dev/null"),
             data: SettingsData::default(),
             permission: Permission::ReadOnly,
+            sources: BTreeMap::new(),
         }
     }
 
@@ -80,6 +201,7 @@ impl Settings {
             path,
             data,
             permission,
+            sources: BTreeMap::new(),
         };
 
         let package_cache_dir = settings.package_cache_dir();
@@ -107,12 +229,44 @@ impl Settings {
         Self::new(path.as_ref().to_path_buf(), data, Permission::ReadWrite)
     }
 
-    fn reload(&mut self) -> Result<(), FileError> {
-        if self.permission == Permission::ReadOnly {
-            return Err(FileError::ReadOnly(self.path.clone()));
+    /// Merges the settings of every layer, followed by the `env` overrides.
+    /// A missing file sets nothing, and is created empty for the last layer
+    /// unless it is read only, so that it does not override the layers below.
+    pub(super) fn load_layered(layers: &[Layer], env: Option<Table>) -> Result<Settings, Error> {
+        let top = layers.last().ok_or(Error::NoConfigPath)?;
+        let path = top.dir.join("settings.toml");
+
+        if !path.exists() && top.permission != Permission::ReadOnly {
+            std::fs::create_dir_all(&top.dir)
+                .map_err(|e| Error::SettingsFile(FileError::CreateParentDir(e, top.dir.clone())))?;
+            std::fs::write(&path, "")
+                .map_err(|e| Error::SettingsFile(FileError::Write(e, path.clone())))?;
         }
-        self.data = SettingsData::load(&self.path)?;
-        Ok(())
+
+        let mut merged = Table::new();
+        let mut sources = BTreeMap::new();
+
+        for layer in layers {
+            let layer_path = layer.dir.join("settings.toml");
+            if !layer_path.exists() {
+                continue;
+            }
+
+            let table = load_table(&layer_path, &layer.dir).map_err(Error::SettingsFile)?;
+            super::layers::merge(&mut merged, table, layer.kind, "", &mut sources);
+        }
+
+        if let Some(env) = env {
+            SettingsData::deserialize(Value::Table(env.clone())).map_err(Error::Environment)?;
+            super::layers::merge(&mut merged, env, ConfigLayer::Environment, "", &mut sources);
+        }
+
+        let data = SettingsData::deserialize(Value::Table(merged))
+            .map_err(|e| Error::SettingsFile(FileError::FromToml(e, path.clone())))?;
+
+        let mut settings = Self::new(path, data, top.permission).map_err(Error::SettingsFile)?;
+        settings.sources = sources;
+        Ok(settings)
     }
 
     fn save(&self) -> Result<(), FileError> {
@@ -135,6 +289,10 @@ impl Settings {
         self.path.parent().unwrap()
     }
 
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.permission == Permission::ReadOnly
+    }
+
     pub fn download_cache_dir(&self) -> PathBuf {
         self.cache_dir("downloads").to_path_buf().unwrap()
     }
@@ -154,4 +312,50 @@ impl Settings {
     pub fn max_concurrent_downloads(&self) -> u8 {
         self.data.max_concurrent_downloads
     }
+
+    pub fn cache_size_limit(&self) -> u64 {
+        self.data.cache_size_limit
+    }
+
+    pub fn resolution_policy(&self) -> ResolutionPolicy {
+        self.data.resolution_policy
+    }
+
+    pub fn progress_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.data.progress_interval)
+    }
+
+    pub fn hooks_dir(&self) -> Option<PathBuf> {
+        self.data
+            .hooks_dir
+            .as_ref()
+            .map(|x| self.config_dir().join(x))
+    }
+
+    pub fn hook_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.data.hook_timeout)
+    }
+
+    pub fn data(&self) -> &SettingsData {
+        &self.data
+    }
+
+    pub fn network(&self) -> &NetworkSettings {
+        &self.data.network
+    }
+
+    /// The layer that set `key`, given as a dotted path such as
+    /// `network.proxy`.
+    pub fn source(&self, key: &str) -> ConfigLayer {
+        self.sources
+            .get(key)
+            .copied()
+            .unwrap_or(ConfigLayer::Default)
+    }
+
+    /// Every key set by a layer, and the layer it was set by. Keys that are
+    /// missing have their default value.
+    pub fn sources(&self) -> &BTreeMap<String, ConfigLayer> {
+        &self.sources
+    }
 }
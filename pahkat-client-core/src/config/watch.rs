@@ -0,0 +1,77 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use futures::stream::Stream;
+
+use super::{Config, ConfigChange, Error};
+
+#[derive(Debug)]
+pub enum ConfigEvent {
+    /// Files changed and were reloaded, changing what is described. Changes
+    /// that do not alter the config, such as a file being saved again, are
+    /// not reported.
+    Reloaded(ConfigChange),
+    /// Files changed but could not be loaded, so the config is unchanged.
+    Invalid(Error),
+}
+
+/// When each file was last modified and its size, or `None` if it is missing.
+type Snapshot = Vec<(PathBuf, Option<(SystemTime, u64)>)>;
+
+fn snapshot(files: &[PathBuf]) -> Snapshot {
+    files
+        .iter()
+        .map(|path| {
+            let meta = std::fs::metadata(path)
+                .ok()
+                .and_then(|x| x.modified().ok().map(|modified| (modified, x.len())));
+            (path.clone(), meta)
+        })
+        .collect()
+}
+
+/// Checks the files of `config` for changes every `interval`, and reloads it
+/// when they change. An invalid file is reported and otherwise ignored until
+/// it changes again, so that a half saved file never replaces a working
+/// config.
+///
+/// The stream ends once nothing else holds `config`.
+pub fn watch(config: Arc<RwLock<Config>>, interval: Duration) -> impl Stream<Item = ConfigEvent> {
+    let weak_config = Arc::downgrade(&config);
+
+    async_stream::stream! {
+        let mut last = match weak_config.upgrade() {
+            Some(config) => snapshot(&config.read().unwrap().files()),
+            None => return,
+        };
+
+        loop {
+            tokio::time::delay_for(interval).await;
+
+            let config = match weak_config.upgrade() {
+                Some(v) => v,
+                None => break,
+            };
+
+            let files = config.read().unwrap().files();
+            let current = snapshot(&files);
+            if current == last {
+                continue;
+            }
+            last = current;
+
+            log::debug!("Configuration files changed, reloading");
+
+            let result = config.write().unwrap().reload();
+            match result {
+                Ok(change) if change.is_empty() => {}
+                Ok(change) => yield ConfigEvent::Reloaded(change),
+                Err(e) => {
+                    log::error!("Not reloading the configuration: {}", &e);
+                    yield ConfigEvent::Invalid(e);
+                }
+            }
+        }
+    }
+}
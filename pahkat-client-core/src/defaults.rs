@@ -17,6 +17,18 @@ pub fn config_path() -> Option<PathBuf> {
     }
 }
 
+/// Machine wide configuration, which the configuration of each user overrides.
+#[cfg(not(target_os = "android"))]
+pub fn system_config_path() -> Option<PathBuf> {
+    if cfg!(windows) {
+        std::env::var_os("ProgramData").map(|x| PathBuf::from(x).join("Pahkat").join("config"))
+    } else if cfg!(target_os = "macos") {
+        Some(Path::new(r"/Library/Preferences/Pahkat").to_path_buf())
+    } else {
+        Some(Path::new("/etc/pahkat").to_path_buf())
+    }
+}
+
 #[inline(always)]
 #[cfg(not(target_os = "android"))]
 fn raw_cache_dir() -> Option<PathBuf> {
@@ -133,6 +145,71 @@ pub(crate) const fn arch() -> Option<&'static str> {
     arch!("powerpc64");
 }
 
+/// Names that refer to the same architecture. The first is the name Rust uses.
+const ARCH_ALIASES: &[&[&str]] = &[
+    &["x86_64", "amd64", "x64"],
+    &["x86", "i686", "i586", "i386"],
+    &["aarch64", "arm64"],
+    &["arm", "armv7", "armhf"],
+];
+
+/// Architectures that run through emulation or compatibility layers on a
+/// platform, from the most to the least preferred.
+const ARCH_FALLBACKS: &[(&str, &str, &[&str])] = &[
+    ("macos", "aarch64", &["x86_64"]),
+    ("windows", "aarch64", &["x86_64", "x86"]),
+    ("windows", "x86_64", &["x86"]),
+];
+
+fn arch_group(arch: &str) -> Option<usize> {
+    ARCH_ALIASES
+        .iter()
+        .position(|names| names.iter().any(|x| x.eq_ignore_ascii_case(arch)))
+}
+
+pub(crate) fn is_same_arch(a: &str, b: &str) -> bool {
+    if a.eq_ignore_ascii_case(b) {
+        return true;
+    }
+
+    match (arch_group(a), arch_group(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// How well a target built for `target_arch` suits `arch` on `platform`,
+/// lower being better, or `None` if it cannot run there.
+///
+/// Native targets come first, then targets without an architecture, then
+/// those that only run through emulation. Without an `arch`, targets without
+/// an architecture are preferred but any other is accepted.
+pub(crate) fn arch_rank(
+    platform: &str,
+    arch: Option<&str>,
+    target_arch: Option<&str>,
+) -> Option<usize> {
+    let arch = match arch {
+        Some(v) => v,
+        None => return Some(if target_arch.is_none() { 0 } else { 1 }),
+    };
+
+    let target_arch = match target_arch {
+        Some(v) => v,
+        None => return Some(1),
+    };
+
+    if is_same_arch(arch, target_arch) {
+        return Some(0);
+    }
+
+    ARCH_FALLBACKS
+        .iter()
+        .find(|(p, a, _)| *p == platform && is_same_arch(a, arch))
+        .and_then(|(_, _, fallbacks)| fallbacks.iter().position(|x| is_same_arch(x, target_arch)))
+        .map(|index| index + 2)
+}
+
 #[inline(always)]
 pub(crate) fn payloads() -> &'static [&'static str] {
     #[cfg(all(feature = "windows", not(feature = "macos"), not(feature = "prefix")))]
@@ -155,3 +232,80 @@ pub(crate) fn payloads() -> &'static [&'static str] {
     ))]
     compile_error!("One of the above features must be enabled");
 }
+
+pub fn connect_timeout() -> u64 {
+    30
+}
+
+pub fn read_timeout() -> u64 {
+    60
+}
+
+pub fn download_retries() -> u32 {
+    3
+}
+
+pub fn retry_delay() -> u64 {
+    1
+}
+
+pub fn progress_interval() -> u64 {
+    750
+}
+
+pub fn hook_timeout() -> u64 {
+    300
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases_are_the_same_arch() {
+        assert!(is_same_arch("i686", "x86"));
+        assert!(is_same_arch("x86", "I386"));
+        assert!(is_same_arch("amd64", "x86_64"));
+        assert!(is_same_arch("arm64", "aarch64"));
+        assert!(is_same_arch("riscv64", "RISCV64"));
+        assert!(!is_same_arch("x86", "x86_64"));
+        assert!(!is_same_arch("riscv64", "x86_64"));
+    }
+
+    #[test]
+    fn aarch64_macos_prefers_native_over_x86_64() {
+        let native = arch_rank("macos", Some("aarch64"), Some("arm64")).unwrap();
+        let any = arch_rank("macos", Some("aarch64"), None).unwrap();
+        let rosetta = arch_rank("macos", Some("aarch64"), Some("x86_64")).unwrap();
+
+        assert!(native < any);
+        assert!(any < rosetta);
+        assert_eq!(arch_rank("macos", Some("aarch64"), Some("x86")), None);
+    }
+
+    #[test]
+    fn x86_64_windows_falls_back_to_x86() {
+        assert_eq!(arch_rank("windows", Some("x86_64"), Some("x64")), Some(0));
+        assert!(arch_rank("windows", Some("x86_64"), Some("i686")).is_some());
+        assert_eq!(arch_rank("windows", Some("x86_64"), Some("aarch64")), None);
+
+        // Only Windows runs x86 on x86_64 out of the box.
+        assert_eq!(arch_rank("linux", Some("x86_64"), Some("x86")), None);
+    }
+
+    #[test]
+    fn aarch64_windows_prefers_x86_64_over_x86() {
+        let x86_64 = arch_rank("windows", Some("aarch64"), Some("x86_64")).unwrap();
+        let x86 = arch_rank("windows", Some("aarch64"), Some("x86")).unwrap();
+
+        assert!(x86_64 < x86);
+    }
+
+    #[test]
+    fn no_arch_prefers_targets_without_one() {
+        let none = arch_rank("linux", None, None).unwrap();
+        let some = arch_rank("linux", None, Some("x86_64")).unwrap();
+
+        assert!(none < some);
+    }
+}
@@ -1,13 +1,21 @@
 use std::fs;
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
+use once_cell::sync::Lazy;
 use reqwest::header;
 use url::Url;
 
+use crate::config::{Credential, CredentialError};
 use crate::ext::PathExt;
-use crate::package_store::DownloadEvent;
+use crate::http::{HttpClient, HttpClientError};
+use crate::package_store::{
+    CancellationToken, DownloadEvent, DownloadOptions, DownloadPhase, DownloadProgress,
+    TransferPriority,
+};
 
 pub trait Download {
     fn download<F>(
@@ -20,39 +28,304 @@ pub trait Download {
         F: Fn(u64, u64) -> bool + Send + 'static;
 }
 
+/// Holds the `If-Range` validator for the partial download in the same directory.
+const VALIDATOR_FILE: &str = ".validator";
+
+/// A strong ETag if there is one, as weak ETags cannot be used with
+/// `If-Range`, or otherwise the last modified date.
+fn validator(headers: &header::HeaderMap) -> Option<String> {
+    let etag = headers
+        .get(header::ETAG)
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| !x.starts_with("W/"));
+
+    etag.or_else(|| {
+        headers
+            .get(header::LAST_MODIFIED)
+            .and_then(|x| x.to_str().ok())
+    })
+    .map(|x| x.to_string())
+}
+
+/// How often a waiting download checks whether it has been resumed or
+/// cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Sleeps for `duration`, waking early if `cancel` is cancelled. Returns
+/// `false` if it was.
+async fn sleep(duration: Duration, cancel: &CancellationToken) -> bool {
+    let until = Instant::now() + duration;
+
+    loop {
+        if cancel.is_cancelled() {
+            return false;
+        }
+
+        let now = Instant::now();
+        if now >= until {
+            return true;
+        }
+
+        tokio::time::delay_for((until - now).min(POLL_INTERVAL)).await;
+    }
+}
+
+/// Shares a bandwidth limit between every download of the same priority,
+/// so running downloads concurrently does not multiply the limit.
+#[derive(Debug)]
+struct RateLimiter {
+    /// The time at which the bytes reserved so far will have been sent.
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new() -> RateLimiter {
+        RateLimiter {
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Reserves bandwidth for `bytes`, returning how long to wait before
+    /// continuing.
+    fn reserve(&self, bytes: u64, limit: u64) -> Duration {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap();
+
+        // Time spent idle must not turn into a burst later.
+        *next = (*next).max(now) + Duration::from_secs_f64(bytes as f64 / limit as f64);
+        *next - now
+    }
+}
+
+static FOREGROUND_LIMITER: Lazy<RateLimiter> = Lazy::new(RateLimiter::new);
+static BACKGROUND_LIMITER: Lazy<RateLimiter> = Lazy::new(RateLimiter::new);
+
+fn limiter(priority: TransferPriority) -> &'static RateLimiter {
+    match priority {
+        TransferPriority::Foreground => &FOREGROUND_LIMITER,
+        TransferPriority::Background => &BACKGROUND_LIMITER,
+    }
+}
+
+fn progress(current: u64, total: u64, resumed_from: u64, started: Instant) -> DownloadProgress {
+    let elapsed = started.elapsed().as_secs_f64();
+    let bytes_per_second = if elapsed > 0.0 {
+        ((current - resumed_from) as f64 / elapsed) as u64
+    } else {
+        0
+    };
+
+    let eta = if total > 0 && bytes_per_second > 0 {
+        Some(Duration::from_secs(total.saturating_sub(current) / bytes_per_second))
+    } else {
+        None
+    };
+
+    DownloadProgress {
+        current,
+        total,
+        bytes_per_second,
+        eta,
+        resumed_from,
+    }
+}
+
 pub(crate) struct DownloadManager {
-    client: reqwest::Client,
+    client: HttpClient,
     path: PathBuf,
-    // max_concurrent_downloads: u8,
+    progress_interval: Duration,
 }
 
 // type Stream<T> = Pin<
 //     Box<dyn futures::Stream<Item = std::result::Result<T, Status>> + Send + Sync + 'static>,
 // >;
 
-impl DownloadManager {
-    pub fn new(path: PathBuf, _max_concurrent_downloads: u8) -> DownloadManager {
-        let client = Self::client();
+/// Hard links `source` to `dest` where possible, and copies it otherwise,
+/// replacing anything already at `dest`. Runs on the blocking thread pool, as
+/// copying from a network share can take a while.
+pub(crate) async fn link_or_copy(source: PathBuf, dest: PathBuf) -> std::io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // A partial copy may be left behind by an earlier failure.
+        if dest.exists() {
+            fs::remove_file(&dest)?;
+        }
 
+        if let Err(e) = fs::hard_link(&source, &dest) {
+            log::debug!("Could not link {:?}, copying instead: {:?}", &source, e);
+            fs::copy(&source, &dest)?;
+        }
+
+        Ok(())
+    })
+    .await
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+}
+
+impl DownloadManager {
+    pub fn new(client: HttpClient, path: PathBuf, progress_interval: Duration) -> DownloadManager {
         DownloadManager {
             client,
             path,
-            // max_concurrent_downloads,
+            progress_interval,
         }
     }
 
-    #[inline]
-    fn client() -> reqwest::Client {
-        reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(60))
-            .build()
-            .unwrap()
+    /// Payloads from a repository on disk or on a network share are hard
+    /// linked into the cache where possible, and copied otherwise.
+    async fn from_local_file(
+        url: &Url,
+        dest_file_path: PathBuf,
+    ) -> Result<
+        std::pin::Pin<
+            Box<dyn futures::stream::Stream<Item = DownloadEvent> + Send + Sync + 'static>,
+        >,
+        DownloadError,
+    > {
+        let source = url.to_file_path().map_err(|_| DownloadError::InvalidUrl)?;
+        let size = fs::metadata(&source)?.len();
+
+        link_or_copy(source, dest_file_path.clone()).await?;
+
+        Ok(Box::pin(async_stream::stream! {
+            yield DownloadEvent::Phase(DownloadPhase::Moving);
+            yield DownloadEvent::Progress(DownloadProgress {
+                current: size,
+                total: size,
+                bytes_per_second: 0,
+                eta: None,
+                resumed_from: 0,
+            });
+            yield DownloadEvent::Complete(dest_file_path);
+        }))
+    }
+
+    /// Sends the request for a download, resuming the partial file at
+    /// `tmp_dest_path` if the server still has the same file.
+    ///
+    /// Returns the file to append to, the response, and the number of bytes
+    /// already downloaded and expected in total.
+    async fn start(
+        client: &HttpClient,
+        url: &Url,
+        tmp_dest_path: &Path,
+        validator_path: &Path,
+        credential: Option<&Credential>,
+        cancel: &CancellationToken,
+    ) -> Result<(fs::File, reqwest::Response, u64, u64), DownloadError> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .open(&tmp_dest_path)
+            .or_else(|_| fs::File::create(&tmp_dest_path))
+            .map_err(|e| {
+                log::error!("{:?}", &e);
+                DownloadError::IoError(e)
+            })?;
+        let meta = file.metadata().map_err(|e| {
+            log::error!("metadata error: {:?}", &e);
+            DownloadError::IoError(e)
+        })?;
+
+        let mut downloaded_bytes = meta.len();
+        log::debug!("Downloaded bytes: {}", downloaded_bytes);
+
+        let mut req = client.get_streaming(url.as_str());
+        if downloaded_bytes > 0 {
+            match fs::read_to_string(validator_path) {
+                Ok(validator) => {
+                    // The server sends the whole file instead if it has changed.
+                    req = req
+                        .header(header::RANGE, format!("bytes={}-", downloaded_bytes))
+                        .header(header::IF_RANGE, validator.trim());
+                }
+                Err(_) => {
+                    log::debug!("Partial download cannot be validated, starting over.");
+                    file.set_len(0)?;
+                    downloaded_bytes = 0;
+                }
+            }
+        }
+        if let Some(credential) = credential {
+            req = credential.apply(req);
+        }
+
+        // Get URL headers
+        let (tx, mut rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let response = req.send().await;
+            let _ = tx.send(response);
+        });
+        let res = loop {
+            if cancel.is_cancelled() {
+                return Err(DownloadError::UserCancelled);
+            }
+
+            if let Ok(v) = tokio::time::timeout(POLL_INTERVAL, &mut rx).await {
+                break v.map_err(|_| DownloadError::UserCancelled)??;
+            }
+        };
+
+        let status = res.status();
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            let url = res.url().clone();
+            return Err(match credential {
+                Some(_) => CredentialError::Rejected(url),
+                None => CredentialError::Required(url),
+            }
+            .into());
+        }
+        let res = res.error_for_status()?;
+
+        // Get content length and send if exists
+        let content_len = res
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .map(|ct_len| ct_len.to_str().unwrap_or("").parse::<u64>().unwrap_or(0u64))
+            .unwrap_or(0u64);
+        log::debug!("Content length: {}", content_len);
+
+        // Check if range request was accepted!
+        let is_partial = status == reqwest::StatusCode::PARTIAL_CONTENT;
+        log::debug!("Is partial: {}", is_partial);
+
+        let total_bytes = if !is_partial {
+            file.set_len(0).map_err(|e| {
+                log::error!("error setting length of file: {:?}", &e);
+                DownloadError::IoError(e)
+            })?;
+            downloaded_bytes = 0;
+
+            // Remember what this response was, so a later resume can check
+            // that it is continuing the same file.
+            match validator(res.headers()) {
+                Some(v) => fs::write(validator_path, v)?,
+                None => {
+                    let _ = fs::remove_file(validator_path);
+                }
+            }
+
+            content_len
+        } else if content_len > 0 {
+            content_len + downloaded_bytes
+        } else {
+            // If no content len, having downloaded bytes doesn't mean we have a known total...
+            0
+        };
+
+        log::debug!("Total bytes: {}", total_bytes);
+
+        Ok((file, res, downloaded_bytes, total_bytes))
     }
 
     pub async fn download<P: AsRef<Path>>(
         &self,
         url: &Url,
         dest_path: P,
+        credential: Option<Credential>,
+        options: DownloadOptions,
     ) -> Result<
         std::pin::Pin<
             Box<dyn futures::stream::Stream<Item = DownloadEvent> + Send + Sync + 'static>,
@@ -83,6 +356,10 @@ impl DownloadManager {
             }
         }
 
+        if url.scheme() == "file" {
+            return Self::from_local_file(url, dest_file_path).await;
+        }
+
         // Create temp dirs if they don't yet exist
         if !self.path.exists() {
             fs::create_dir_all(&self.path).map_err(|e| {
@@ -101,131 +378,190 @@ impl DownloadManager {
         }
 
         let tmp_dest_path = cache_dir.join(filename);
+        let validator_path = cache_dir.join(VALIDATOR_FILE);
 
-        let file = fs::OpenOptions::new()
-            .append(true)
-            .open(&tmp_dest_path)
-            .or_else(|_| fs::File::create(&tmp_dest_path))
-            .map_err(|e| {
-                log::error!("{:?}", &e);
-                DownloadError::IoError(e)
-            })?;
-        let meta = file.metadata().map_err(|e| {
-            log::error!("metadata error: {:?}", &e);
-            DownloadError::IoError(e)
-        })?;
-
-        let mut downloaded_bytes = meta.len();
-        log::debug!("Downloaded bytes: {}", downloaded_bytes);
+        let client = self.client.clone();
+        let url = url.clone();
+        let limit = client.download_limit(options.priority);
+        let limiter = limiter(options.priority);
+        let control = options.control;
+        let cancel = options.cancel;
 
-        let client = &self.client;
-        let mut req = client.get(url.as_str());
-        if downloaded_bytes > 0 {
-            req = req.header(header::RANGE, format!("bytes={}-", downloaded_bytes));
-        }
+        let stream = async_stream::stream! {
+            let mut attempt = 0u32;
 
-        let req = req.build().map_err(DownloadError::ReqwestError)?;
+            let result = loop {
+                yield DownloadEvent::Phase(DownloadPhase::Connecting);
 
-        // Get URL headers
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        tokio::spawn(async move {
-            let response = Self::client()
-                .execute(req)
-                .await
-                .and_then(|x| x.error_for_status());
-            tx.send(response).unwrap();
-        });
-        let mut res = rx.await.unwrap()?;
+                let error = match Self::start(&client, &url, &tmp_dest_path, &validator_path, credential.as_ref(), &cancel).await {
+                    Ok((file, mut res, mut downloaded_bytes, total_bytes)) => {
+                        yield DownloadEvent::Phase(DownloadPhase::Downloading);
 
-        // Get content length and send if exists
-        let content_len = res
-            .headers()
-            .get(header::CONTENT_LENGTH)
-            .map(|ct_len| ct_len.to_str().unwrap_or("").parse::<u64>().unwrap_or(0u64))
-            .unwrap_or(0u64);
-        log::debug!("Content length: {}", content_len);
+                        let mut file = BufWriter::new(file);
+                        let mut error = None;
+                        let resumed_from = downloaded_bytes;
+                        let started = Instant::now();
 
-        // Check if range request was accepted!
-        let is_partial = res.headers().get(header::CONTENT_RANGE).is_some();
-        log::debug!("Is partial: {}", is_partial);
+                        loop {
+                            if control.is_paused() {
+                                log::debug!("Download of {} paused", &url);
+                                while control.is_paused() && !cancel.is_cancelled() {
+                                    tokio::time::delay_for(POLL_INTERVAL).await;
+                                }
+                                log::debug!("Download of {} resumed", &url);
+                            }
 
-        let total_bytes = if !is_partial {
-            file.set_len(0).map_err(|e| {
-                log::error!("error setting length of file: {:?}", &e);
-                DownloadError::IoError(e)
-            })?;
-            content_len
-        } else if content_len > 0 {
-            content_len + downloaded_bytes
-        } else {
-            // If no content len, having downloaded bytes doesn't mean we have a known total...
-            0
-        };
+                            // Waits in short steps so a cancellation is noticed
+                            // even while no data is arriving.
+                            let waiting = Instant::now();
+                            let chunk = loop {
+                                if cancel.is_cancelled() {
+                                    break Err(DownloadError::UserCancelled);
+                                }
 
-        log::debug!("Total bytes: {}", total_bytes);
+                                let remaining = client
+                                    .read_timeout()
+                                    .checked_sub(waiting.elapsed())
+                                    .unwrap_or_default();
+                                if remaining == Duration::from_millis(0) {
+                                    break Err(DownloadError::TimedOut);
+                                }
 
-        let stream = async_stream::stream! {
-            let mut file = BufWriter::new(file);
-            loop {
-                let chunk = res.chunk().await.map_err(DownloadError::ReqwestError);
-                match chunk {
-                    Ok(v) => match v {
-                        None => {
-                            break; // Complete
-                        }
-                        Some(v) => {
-                            downloaded_bytes += v.len() as u64;
-                            let result = file.write(&*v).map_err(|e| {
-                                log::error!("error writing output: {:?}", &e);
-                                DownloadError::IoError(e)
-                            });
-                            match result {
-                                Ok(_) => {
-                                    yield DownloadEvent::Progress((downloaded_bytes, total_bytes));
-                                },
+                                if let Ok(v) = tokio::time::timeout(remaining.min(POLL_INTERVAL), res.chunk()).await {
+                                    break v.map_err(DownloadError::ReqwestError);
+                                }
+                            };
+
+                            match chunk {
+                                Ok(None) => break, // Complete
+                                Ok(Some(v)) => {
+                                    downloaded_bytes += v.len() as u64;
+                                    if let Err(e) = file.write_all(&*v) {
+                                        log::error!("error writing output: {:?}", &e);
+                                        error = Some(DownloadError::IoError(e));
+                                        break;
+                                    }
+                                    yield DownloadEvent::Progress(progress(
+                                        downloaded_bytes,
+                                        total_bytes,
+                                        resumed_from,
+                                        started,
+                                    ));
+
+                                    if limit > 0 {
+                                        let delay = limiter.reserve(v.len() as u64, limit);
+                                        if delay > Duration::from_millis(0) {
+                                            sleep(delay, &cancel).await;
+                                        }
+                                    }
+                                }
                                 Err(e) => {
-                                    yield DownloadEvent::Error(e);
+                                    error = Some(e);
                                     break;
                                 }
                             }
                         }
+
+                        // Whatever was received must be on disk before a retry resumes from it.
+                        if let Err(e) = file.flush() {
+                            break Err(DownloadError::IoError(e));
+                        }
+
+                        match error {
+                            None => {
+                                yield DownloadEvent::Phase(DownloadPhase::Verifying);
+
+                                if total_bytes == 0 || downloaded_bytes == total_bytes {
+                                    break Ok(());
+                                }
+
+                                // Start over rather than resume from a file that is known to be bad.
+                                let _ = fs::remove_file(&tmp_dest_path);
+                                let _ = fs::remove_file(&validator_path);
+                                DownloadError::SizeMismatch {
+                                    expected: total_bytes,
+                                    actual: downloaded_bytes,
+                                }
+                            }
+                            Some(e) => e,
+                        }
                     }
-                    Err(e) => {
-                        yield DownloadEvent::Error(e);
-                        break;
-                    }
+                    Err(e) => e,
+                };
+
+                let delay = match client.backoff(attempt) {
+                    Some(v) if error.is_transient() => v,
+                    _ => break Err(error),
+                };
+
+                attempt += 1;
+                log::warn!("Download of {} failed, retrying in {:?}: {:?}", &url, delay, &error);
+                yield DownloadEvent::Retrying { attempt, delay, error };
+                if !sleep(delay, &cancel).await {
+                    break Err(DownloadError::UserCancelled);
                 }
+            };
 
+            if let Err(e) = result {
+                yield DownloadEvent::Error(e);
+                return;
             }
 
             log::debug!("Moving {:?} to {:?}", &tmp_dest_path, &dest_path);
+            yield DownloadEvent::Phase(DownloadPhase::Moving);
 
             // If it's done, move the file!
-            let _ = fs::create_dir_all(dest_path);
-            match fs::copy(&tmp_dest_path, &dest_file_path) {
-                Err(e) => yield DownloadEvent::Error(DownloadError::IoError(e)),
-                _ => {}
-            };
-            match fs::remove_file(&tmp_dest_path) {
-                Err(e) => yield DownloadEvent::Error(DownloadError::IoError(e)),
-                _ => {}
-            };
+            if let Err(e) = link_or_copy(tmp_dest_path.clone(), dest_file_path.clone()).await {
+                yield DownloadEvent::Error(DownloadError::IoError(e));
+                return;
+            }
+            // The payload is already in place, so this is no reason to fail.
+            if let Err(e) = fs::remove_file(&tmp_dest_path) {
+                log::warn!("Could not remove {:?}: {:?}", &tmp_dest_path, e);
+            }
+            let _ = fs::remove_file(&validator_path);
             yield DownloadEvent::Complete(dest_file_path);
         };
 
+        let progress_interval = self.progress_interval;
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
         tokio::spawn(async move {
             use futures::stream::StreamExt;
             futures::pin_mut!(stream);
 
-            while let Some(result) = stream.next().await {
-                tx.send(result).unwrap();
+            // Stop progress events overwhelming receivers. Only the latest is
+            // kept, and all other events are passed on without delay.
+            let mut last_progress: Option<Instant> = None;
+            let mut pending = None;
+
+            while let Some(event) = stream.next().await {
+                let events = match event {
+                    DownloadEvent::Progress(progress) => {
+                        if last_progress.map(|x| x.elapsed() < progress_interval).unwrap_or(false) {
+                            pending = Some(progress);
+                            continue;
+                        }
+                        last_progress = Some(Instant::now());
+                        pending = None;
+                        vec![DownloadEvent::Progress(progress)]
+                    }
+                    event => match pending.take() {
+                        Some(progress) => vec![DownloadEvent::Progress(progress), event],
+                        None => vec![event],
+                    },
+                };
+
+                for event in events {
+                    // The receiver is gone when the download was aborted, so stop
+                    // and drop the response rather than reading it to the end.
+                    if tx.send(event).is_err() {
+                        log::debug!("Download receiver dropped, aborting.");
+                        return;
+                    }
+                }
             }
         });
 
-        // Stop the stream overwhelming receivers.
-        let rx = tokio::time::throttle(std::time::Duration::from_millis(750), rx);
-
         Ok(Box::pin(rx))
     }
 }
@@ -249,4 +585,31 @@ pub enum DownloadError {
 
     #[error("Error downloading file")]
     ReqwestError(#[from] reqwest::Error),
+
+    #[error("Authentication failed")]
+    Credentials(#[from] CredentialError),
+
+    #[error("Could not create HTTP client")]
+    HttpClient(#[from] HttpClientError),
+
+    #[error("Timed out waiting for data")]
+    TimedOut,
+
+    #[error("Expected {expected} bytes, but received {actual}")]
+    SizeMismatch { expected: u64, actual: u64 },
+}
+
+impl DownloadError {
+    /// Whether the error may go away by itself, so the download is worth
+    /// retrying: timeouts, server errors and dropped connections.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::TimedOut | DownloadError::SizeMismatch { .. } => true,
+            DownloadError::ReqwestError(e) => match e.status() {
+                Some(status) => status.is_server_error(),
+                None => !e.is_builder() && !e.is_redirect(),
+            },
+            _ => false,
+        }
+    }
 }
@@ -5,6 +5,11 @@ use sha2::Sha256;
 
 pub(crate) trait PathExt {
     fn join_sha256(&self, bytes: &[u8]) -> PathBuf;
+
+    /// Writes to a uniquely named temporary file beside this path first, so
+    /// an interrupted write never leaves a truncated file behind, and
+    /// concurrent writes to neighbouring files do not collide.
+    fn write_atomic(&self, bytes: &[u8]) -> std::io::Result<()>;
 }
 
 impl PathExt for Path {
@@ -17,4 +22,14 @@ impl PathExt for Path {
         let part3 = &hash_id[4..];
         self.join(part1).join(part2).join(part3)
     }
+
+    fn write_atomic(&self, bytes: &[u8]) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let dir = self.parent().unwrap_or_else(|| Path::new("."));
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(bytes)?;
+        file.persist(self).map_err(|e| e.error)?;
+        Ok(())
+    }
 }
@@ -1,5 +1,9 @@
 use std::convert::TryFrom;
 
+use url::Url;
+
+pub(crate) mod builder;
+
 pub(super) mod generated {
     #![allow(dead_code)]
     butte_build::include_fbs!("index");
@@ -50,8 +54,25 @@ impl<B: AsRef<[u8]>> TargetExt for pahkat_fbs::Target<B> {
     }
 }
 
+/// Offline bundles use payload URLs relative to the repository, which are
+/// resolved against `base`.
+fn payload_url(url: &str, base: Option<&Url>) -> Url {
+    match (Url::parse(url), base) {
+        (Err(url::ParseError::RelativeUrlWithoutBase), Some(base)) => {
+            let mut base = base.clone();
+            if !base.path().ends_with('/') {
+                let path = format!("{}/", base.path());
+                base.set_path(&path);
+            }
+            base.join(url).unwrap()
+        }
+        (result, _) => result.unwrap(),
+    }
+}
+
 fn build_target<B: AsRef<[u8]>>(
     t: &pahkat_fbs::Target<B>,
+    base: Option<&Url>,
 ) -> Result<pahkat_types::payload::Target, butte::Error> {
     let platform = t.platform()?.to_string();
     let arch = t.arch()?.map(str::to_string);
@@ -69,7 +90,7 @@ fn build_target<B: AsRef<[u8]>>(
         pahkat_fbs::Payload::WindowsExecutable(x) => {
             pahkat_types::payload::Payload::WindowsExecutable(
                 pahkat_types::payload::windows::Executable::builder()
-                    .url(x.url()?.parse::<url::Url>().unwrap())
+                    .url(payload_url(x.url()?, base))
                     .product_code(x.product_code()?.to_string())
                     .kind(match x.kind()?.unwrap() {
                         pahkat_fbs::WindowsExecutableKind::NONE => None,
@@ -86,7 +107,7 @@ fn build_target<B: AsRef<[u8]>>(
         }
         pahkat_fbs::Payload::MacOSPackage(x) => pahkat_types::payload::Payload::MacOSPackage(
             pahkat_types::payload::macos::Package::builder()
-                .url(x.url()?.parse::<url::Url>().unwrap())
+                .url(payload_url(x.url()?, base))
                 .pkg_id(x.pkg_id()?.to_string())
                 .size(x.size()?.unwrap())
                 .installed_size(x.installed_size()?.unwrap())
@@ -94,7 +115,7 @@ fn build_target<B: AsRef<[u8]>>(
         ),
         pahkat_fbs::Payload::TarballPackage(x) => pahkat_types::payload::Payload::TarballPackage(
             pahkat_types::payload::tarball::Package::builder()
-                .url(x.url()?.parse::<url::Url>().unwrap())
+                .url(payload_url(x.url()?, base))
                 .size(x.size()?.unwrap())
                 .installed_size(x.installed_size()?.unwrap())
                 .build(),
@@ -113,69 +134,78 @@ impl<'a> TryFrom<&'a pahkat_fbs::Descriptor<&'a [u8]>> for pahkat_types::package
     type Error = butte::Error;
 
     fn try_from(pkg: &'a pahkat_fbs::Descriptor<&'a [u8]>) -> Result<Self, Self::Error> {
-        use std::collections::BTreeMap;
+        descriptor(pkg, None)
+    }
+}
 
-        let descriptor = pahkat_types::package::Descriptor::builder()
-            .package(
-                pahkat_types::package::DescriptorData::builder()
-                    .id(pkg.id()?.into())
-                    .tags(
-                        pkg.tags()?
-                            .map(|tags| tags.iter().map(|x| x.unwrap_or("").to_string()).collect())
-                            .unwrap_or(vec![]),
-                    )
-                    .build(),
-            )
-            .name(
-                pkg.name()
-                    .map(|x| {
-                        let mut out = BTreeMap::new();
-                        for (k, v) in x.iter() {
-                            out.insert(k.to_string(), v.to_string());
-                        }
-                        out
-                    })
-                    .unwrap_or_else(|| Default::default()),
-            )
-            .description(
-                pkg.description()
-                    .map(|x| {
-                        let mut out = BTreeMap::new();
-                        for (k, v) in x.iter() {
-                            out.insert(k.to_string(), v.to_string());
-                        }
-                        out
-                    })
-                    .unwrap_or_else(|| Default::default()),
-            )
-            .release(
-                pkg.release()?
-                    .unwrap()
-                    .iter()
-                    .filter_map(Result::ok)
-                    .map(|x| {
-                        let release = pahkat_types::package::Release::builder()
-                            .version(
-                                pahkat_types::package::version::Version::new(x.version()?).unwrap(),
-                            )
-                            .channel(x.channel()?.map(|x| x.to_string()))
-                            .target(
-                                x.target()?
-                                    .unwrap()
-                                    .iter()
-                                    .filter_map(Result::ok)
-                                    .map(|t| build_target(&t))
-                                    .collect::<Result<Vec<_>, _>>()?,
-                            )
-                            .build();
-                        Ok(release)
-                    })
-                    .collect::<Result<Vec<_>, _>>()?,
-            )
-            .build();
+/// Converts a package from the index of the repository at `repo_url`, which
+/// relative payload URLs are resolved against.
+pub(crate) fn descriptor(
+    pkg: &pahkat_fbs::Descriptor<&[u8]>,
+    repo_url: Option<&Url>,
+) -> Result<pahkat_types::package::Descriptor, butte::Error> {
+    use std::collections::BTreeMap;
 
-        Ok(descriptor)
-    }
+    let descriptor = pahkat_types::package::Descriptor::builder()
+        .package(
+            pahkat_types::package::DescriptorData::builder()
+                .id(pkg.id()?.into())
+                .tags(
+                    pkg.tags()?
+                        .map(|tags| tags.iter().map(|x| x.unwrap_or("").to_string()).collect())
+                        .unwrap_or(vec![]),
+                )
+                .build(),
+        )
+        .name(
+            pkg.name()
+                .map(|x| {
+                    let mut out = BTreeMap::new();
+                    for (k, v) in x.iter() {
+                        out.insert(k.to_string(), v.to_string());
+                    }
+                    out
+                })
+                .unwrap_or_else(|| Default::default()),
+        )
+        .description(
+            pkg.description()
+                .map(|x| {
+                    let mut out = BTreeMap::new();
+                    for (k, v) in x.iter() {
+                        out.insert(k.to_string(), v.to_string());
+                    }
+                    out
+                })
+                .unwrap_or_else(|| Default::default()),
+        )
+        .release(
+            pkg.release()?
+                .unwrap()
+                .iter()
+                .filter_map(Result::ok)
+                .map(|x| {
+                    let release = pahkat_types::package::Release::builder()
+                        .version(
+                            pahkat_types::package::version::Version::new(x.version()?).unwrap(),
+                        )
+                        .channel(x.channel()?.map(|x| x.to_string()))
+                        .target(
+                            x.target()?
+                                .unwrap()
+                                .iter()
+                                .filter_map(Result::ok)
+                                .map(|t| build_target(&t, repo_url))
+                                .collect::<Result<Vec<_>, _>>()?,
+                        )
+                        .build();
+                    Ok(release)
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+        .build();
+
+    Ok(descriptor)
 }
 
 pub struct Map<'a, K, V> {
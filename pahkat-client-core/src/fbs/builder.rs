@@ -0,0 +1,413 @@
+use butte::FlatBufferBuilder;
+use pahkat_types::package::Descriptor;
+use url::Url;
+
+/// Gives the URL written to the index for a payload URL.
+type PayloadUrl<'u> = &'u dyn Fn(&Url) -> String;
+
+fn vectorize_strings<'a>(
+    keys: Vec<butte::WIPOffset<&'a str>>,
+    builder: &mut FlatBufferBuilder<'a>,
+) -> butte::WIPOffset<butte::Vector<'a, butte::WIPOffset<&'a str>>> {
+    let len = keys.len();
+    builder.start_vector::<butte::WIPOffset<&'_ str>>(len);
+    for key in keys.into_iter().rev() {
+        builder.push(key);
+    }
+    builder.end_vector::<butte::WIPOffset<&'_ str>>(len)
+}
+
+fn vectorize_lang_map<'a, 'd>(
+    lang_map: &'d pahkat_types::LangTagMap<String>,
+    lang_keys: &mut std::collections::HashMap<&'d str, butte::WIPOffset<&'a str>>,
+    builder: &mut FlatBufferBuilder<'a>,
+) -> (
+    Option<butte::WIPOffset<butte::Vector<'a, butte::WIPOffset<&'a str>>>>,
+    Option<butte::WIPOffset<butte::Vector<'a, butte::WIPOffset<&'a str>>>>,
+) {
+    let (name_keys, name_values): (Vec<_>, Vec<_>) = lang_map
+        .iter()
+        .map(|(key, value)| {
+            let lang_key_ref = *lang_keys
+                .entry(key)
+                .or_insert_with(|| builder.create_string(key));
+            let value_ref = builder.create_string(value);
+            (lang_key_ref, value_ref)
+        })
+        .unzip();
+
+    let (name_keys_ref, name_values_ref) = if name_keys.is_empty() {
+        (None, None)
+    } else {
+        let keys_ref = vectorize_strings(name_keys, builder);
+        let values_ref = vectorize_strings(name_values, builder);
+        (Some(keys_ref), Some(values_ref))
+    };
+
+    (name_keys_ref, name_values_ref)
+}
+
+fn create_payload_windows_exe<'a>(
+    payload: &pahkat_types::payload::windows::Executable,
+    payload_url: PayloadUrl<'_>,
+    builder: &mut FlatBufferBuilder<'a>,
+) -> butte::WIPOffset<butte::UnionWIPOffset> {
+    let url = builder.create_string(&payload_url(&payload.url));
+    let product_code = builder.create_string(payload.product_code.as_str());
+
+    use crate::pahkat_fbs::WindowsExecutableKind;
+    let kind = match payload.kind.as_ref().map(|x| &**x) {
+        Some("msi") => WindowsExecutableKind::Msi,
+        Some("nsis") => WindowsExecutableKind::Nsis,
+        Some("inno") => WindowsExecutableKind::Inno,
+        _ => WindowsExecutableKind::NONE,
+    };
+
+    let args = payload
+        .args
+        .as_ref()
+        .map(|x| builder.create_string(x.as_str()));
+    let uninstall_args = payload
+        .uninstall_args
+        .as_ref()
+        .map(|x| builder.create_string(x.as_str()));
+
+    use crate::pahkat_fbs::WindowsExecutableFlag;
+    use pahkat_types::payload::windows::RebootSpec;
+
+    let mut flags = 0u8;
+    if payload.requires_reboot.contains(&RebootSpec::Install) {
+        flags |= WindowsExecutableFlag::RequiresRebootOnInstall as u8;
+    }
+    if payload.requires_reboot.contains(&RebootSpec::Update) {
+        flags |= WindowsExecutableFlag::RequiresRebootOnUpdate as u8;
+    }
+    if payload.requires_reboot.contains(&RebootSpec::Uninstall) {
+        flags |= WindowsExecutableFlag::RequiresRebootOnUninstall as u8;
+    }
+
+    let args = crate::pahkat_fbs::WindowsExecutableArgs {
+        url,
+        product_code,
+        flags,
+        kind,
+        size: payload.size,
+        installed_size: payload.installed_size,
+        args,
+        uninstall_args,
+    };
+
+    crate::pahkat_fbs::WindowsExecutable::create(builder, &args).as_union_value()
+}
+
+fn create_payload_macos_pkg<'a>(
+    payload: &pahkat_types::payload::macos::Package,
+    payload_url: PayloadUrl<'_>,
+    builder: &mut FlatBufferBuilder<'a>,
+) -> butte::WIPOffset<butte::UnionWIPOffset> {
+    let url = builder.create_string(&payload_url(&payload.url));
+    let pkg_id = builder.create_string(payload.pkg_id.as_str());
+
+    use crate::pahkat_fbs::MacOSPackageFlag;
+    use pahkat_types::payload::macos::RebootSpec;
+
+    let mut flags = 0u8;
+    if payload.requires_reboot.contains(&RebootSpec::Install) {
+        flags |= MacOSPackageFlag::RequiresRebootOnInstall as u8;
+    }
+    if payload.requires_reboot.contains(&RebootSpec::Update) {
+        flags |= MacOSPackageFlag::RequiresRebootOnUpdate as u8;
+    }
+    if payload.requires_reboot.contains(&RebootSpec::Uninstall) {
+        flags |= MacOSPackageFlag::RequiresRebootOnUninstall as u8;
+    }
+
+    use pahkat_types::payload::macos::InstallTarget;
+
+    if payload.targets.is_empty() {
+        flags |= MacOSPackageFlag::TargetSystem as u8;
+    } else {
+        for target in payload.targets.iter() {
+            match target {
+                InstallTarget::System => flags |= MacOSPackageFlag::TargetSystem as u8,
+                InstallTarget::User => flags |= MacOSPackageFlag::TargetUser as u8,
+            }
+        }
+    }
+
+    let args = crate::pahkat_fbs::MacOSPackageArgs {
+        url,
+        pkg_id,
+        flags,
+        size: payload.size,
+        installed_size: payload.installed_size,
+    };
+
+    crate::pahkat_fbs::MacOSPackage::create(builder, &args).as_union_value()
+}
+
+fn create_payload_tarball_pkg<'a>(
+    payload: &pahkat_types::payload::tarball::Package,
+    payload_url: PayloadUrl<'_>,
+    builder: &mut FlatBufferBuilder<'a>,
+) -> butte::WIPOffset<butte::UnionWIPOffset> {
+    let url = builder.create_string(&payload_url(&payload.url));
+    let args = crate::pahkat_fbs::TarballPackageArgs {
+        url,
+        size: payload.size,
+        installed_size: payload.installed_size,
+    };
+
+    crate::pahkat_fbs::TarballPackage::create(builder, &args).as_union_value()
+}
+
+fn create_targets<'d, 'a>(
+    targets: &'d [pahkat_types::payload::Target],
+    payload_url: PayloadUrl<'_>,
+    builder: &mut FlatBufferBuilder<'a>,
+) -> butte::WIPOffset<butte::Vector<'a, butte::WIPOffset<crate::pahkat_fbs::Target<&'a [u8]>>>> {
+    let targets = targets
+        .iter()
+        .map(|target| {
+            let platform = builder.create_string(&target.platform);
+
+            // TODO: cache keys
+            let (dependencies_keys, dependencies_values): (Vec<_>, Vec<_>) = target
+                .dependencies
+                .iter()
+                .map(|(key, value)| (builder.create_string(key), builder.create_string(value)))
+                .unzip();
+            let (dependencies_keys, dependencies_values) = if dependencies_keys.is_empty() {
+                (None, None)
+            } else {
+                (
+                    Some(vectorize_strings(dependencies_keys, builder)),
+                    Some(vectorize_strings(dependencies_values, builder)),
+                )
+            };
+
+            let arch = target.arch.as_ref().map(|x| builder.create_string(x));
+
+            use crate::pahkat_fbs::butte_gen::PayloadType;
+            use pahkat_types::payload::Payload;
+
+            let (payload_type, payload) = match &target.payload {
+                Payload::WindowsExecutable(p) => (
+                    PayloadType::WindowsExecutable,
+                    create_payload_windows_exe(p, payload_url, builder),
+                ),
+                Payload::MacOSPackage(p) => (
+                    PayloadType::MacOSPackage,
+                    create_payload_macos_pkg(p, payload_url, builder),
+                ),
+                Payload::TarballPackage(p) => (
+                    PayloadType::TarballPackage,
+                    create_payload_tarball_pkg(p, payload_url, builder),
+                ),
+                _ => panic!("Payload must exist"),
+            };
+
+            let args = crate::pahkat_fbs::TargetArgs {
+                platform,
+                arch,
+                dependencies_keys,
+                dependencies_values,
+                payload_type,
+                payload,
+            };
+
+            crate::pahkat_fbs::Target::create(builder, &args)
+        })
+        .collect::<Vec<_>>();
+
+    let len = targets.len();
+    builder.start_vector::<butte::WIPOffset<crate::pahkat_fbs::Target<&'_ [u8]>>>(len);
+    for target in targets.into_iter().rev() {
+        builder.push(target);
+    }
+    builder.end_vector::<butte::WIPOffset<crate::pahkat_fbs::Target<&'_ [u8]>>>(len)
+}
+
+fn create_releases<'d, 'a>(
+    releases: &'d [pahkat_types::package::Release],
+    release_keys: &mut std::collections::HashMap<String, butte::WIPOffset<&'a str>>,
+    str_keys: &mut std::collections::HashMap<&'d str, butte::WIPOffset<&'a str>>,
+    payload_url: PayloadUrl<'_>,
+    builder: &mut FlatBufferBuilder<'a>,
+) -> butte::WIPOffset<butte::Vector<'a, butte::WIPOffset<crate::pahkat_fbs::Release<&'a [u8]>>>> {
+    let releases = releases
+        .iter()
+        .map(|release| {
+            // TODO: handle version type properly
+            use pahkat_types::package::version::Version;
+            let (version_type, version) = match &release.version {
+                // Version::Opaque => 1u8,
+                Version::Semantic(v) => (2u8, v.to_string()),
+                _ => panic!("Unsupported version type"),
+            };
+            let version = *release_keys
+                .entry(version.clone())
+                .or_insert_with(|| builder.create_string(&*version));
+            let channel = release.channel.as_ref().map(|x| {
+                *str_keys
+                    .entry(&*x)
+                    .or_insert_with(|| builder.create_string(&*x))
+            });
+
+            let authors = release
+                .authors
+                .iter()
+                .map(|x| {
+                    *str_keys
+                        .entry(&*x)
+                        .or_insert_with(|| builder.create_string(&*x))
+                })
+                .collect::<Vec<_>>();
+            let authors = if authors.is_empty() {
+                None
+            } else {
+                Some(vectorize_strings(authors, builder))
+            };
+
+            let license = release.license.as_ref().map(|x| {
+                *str_keys
+                    .entry(&*x)
+                    .or_insert_with(|| builder.create_string(&*x))
+            });
+            let license_url = release.license_url.as_ref().map(|x| {
+                *str_keys
+                    .entry(x.as_str())
+                    .or_insert_with(|| builder.create_string(x.as_str()))
+            });
+            let target = Some(create_targets(&release.target, payload_url, builder));
+
+            let args = crate::pahkat_fbs::ReleaseArgs {
+                version_type,
+                version,
+                channel,
+                authors,
+                license,
+                license_url,
+                target,
+            };
+
+            crate::pahkat_fbs::Release::create(builder, &args)
+        })
+        .collect::<Vec<_>>();
+
+    let len = releases.len();
+    builder.start_vector::<butte::WIPOffset<crate::pahkat_fbs::Release<&'_ [u8]>>>(len);
+    for release in releases.into_iter().rev() {
+        builder.push(release);
+    }
+    builder.end_vector::<butte::WIPOffset<crate::pahkat_fbs::Release<&'_ [u8]>>>(len)
+}
+
+/// Encodes descriptors as a `packages/index.bin` package index, the same way
+/// `pahkat-repomgr` does.
+#[cfg(any(test, feature = "test-support"))]
+pub fn build_index(packages: &[Descriptor]) -> Vec<u8> {
+    build_index_with(packages, &|url| url.to_string())
+}
+
+/// Like `build_index`, writing each payload URL as `payload_url` gives it.
+/// Offline bundles use this for URLs relative to the repository.
+pub(crate) fn build_index_with(packages: &[Descriptor], payload_url: PayloadUrl<'_>) -> Vec<u8> {
+    let mut builder = FlatBufferBuilder::new();
+    let builder = &mut builder;
+
+    let mut owned_keys = std::collections::HashMap::new();
+    let mut str_keys = std::collections::HashMap::new();
+
+    // Use the count to create the vectors we need
+    let id_refs = packages
+        .iter()
+        .map(|descriptor| builder.create_string(&descriptor.package.id))
+        .collect::<Vec<_>>();
+
+    builder.start_vector::<butte::WIPOffset<&'_ str>>(id_refs.len());
+    for id in id_refs.iter().rev() {
+        builder.push(*id);
+    }
+    let packages_keys = Some(builder.end_vector::<butte::WIPOffset<&'_ str>>(id_refs.len()));
+
+    builder.start_vector::<u8>(id_refs.len());
+    for _ in id_refs.iter().rev() {
+        builder.push(crate::pahkat_fbs::butte_gen::PackageType::Descriptor as u8);
+    }
+    let packages_values_types = Some(builder.end_vector::<u8>(id_refs.len()));
+
+    let packages_values = id_refs
+        .iter()
+        .zip(packages.iter())
+        .map(|(id_ref, descriptor)| {
+            let tags = if descriptor.package.tags.is_empty() {
+                None
+            } else {
+                let tags = descriptor
+                    .package
+                    .tags
+                    .iter()
+                    .map(|x| {
+                        *str_keys
+                            .entry(&**x)
+                            .or_insert_with(|| builder.create_string(&*x))
+                    })
+                    .collect::<Vec<_>>();
+                let len = tags.len();
+                builder.start_vector::<butte::WIPOffset<&'_ str>>(len);
+                for tag_ref in tags.into_iter().rev() {
+                    builder.push(tag_ref);
+                }
+                Some(builder.end_vector::<butte::WIPOffset<&'_ str>>(len))
+            };
+
+            let (name_keys, name_values) =
+                vectorize_lang_map(&descriptor.name, &mut str_keys, builder);
+            let (description_keys, description_values) =
+                vectorize_lang_map(&descriptor.description, &mut str_keys, builder);
+
+            let release =
+                create_releases(
+                    &descriptor.release,
+                    &mut owned_keys,
+                    &mut str_keys,
+                    payload_url,
+                    builder,
+                );
+
+            let args = crate::pahkat_fbs::DescriptorArgs {
+                id: *id_ref,
+                name_keys,
+                name_values,
+                description_keys,
+                description_values,
+                tags,
+                release: Some(release),
+            };
+            crate::pahkat_fbs::Descriptor::create(builder, &args)
+        })
+        .collect::<Vec<_>>();
+
+    builder
+        .start_vector::<butte::WIPOffset<crate::pahkat_fbs::Descriptor<&'_ [u8]>>>(id_refs.len());
+    for package_value in packages_values.into_iter().rev() {
+        builder.push(package_value);
+    }
+    let packages_values = Some(
+        builder.end_vector::<butte::WIPOffset<crate::pahkat_fbs::Descriptor<&'_ [u8]>>>(
+            id_refs.len(),
+        ),
+    );
+
+    let args = crate::pahkat_fbs::PackagesArgs {
+        packages_values_types,
+        packages_keys,
+        packages_values,
+    };
+
+    let root = crate::pahkat_fbs::Packages::create(builder, &args);
+
+    builder.finish_minimal(root);
+    builder.finished_data().to_vec()
+}
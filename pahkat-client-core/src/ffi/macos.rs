@@ -102,11 +102,18 @@ pub extern "C" fn pahkat_macos_package_store_find_package_by_key(
     handle.find_package_by_key(&package_key)
 }
 
-#[cthulhu::invoke]
+#[cthulhu::invoke(return_marshaler = "JsonMarshaler")]
 pub extern "C" fn pahkat_macos_package_store_clear_cache(
     #[marshal(cursed::ArcRefMarshaler::<MacOSPackageStore>)] handle: Arc<MacOSPackageStore>,
-) {
-    handle.clear_cache();
+) -> crate::CacheReport {
+    handle.clear_cache()
+}
+
+#[cthulhu::invoke(return_marshaler = "JsonMarshaler")]
+pub extern "C" fn pahkat_macos_package_store_prune_cache(
+    #[marshal(cursed::ArcRefMarshaler::<MacOSPackageStore>)] handle: Arc<MacOSPackageStore>,
+) -> crate::CacheReport {
+    handle.prune_cache()
 }
 
 #[cthulhu::invoke]
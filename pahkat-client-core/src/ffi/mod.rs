@@ -27,6 +27,7 @@ use serde::Serialize;
 use url::Url;
 
 use crate::config::ConfigPath;
+use crate::package_store::DownloadControl;
 use crate::repo::PayloadError;
 use crate::transaction::{PackageStatus, PackageStatusError};
 use crate::{Config, PackageKey};
@@ -109,6 +110,34 @@ pub extern "C" fn pahkat_android_init(
     }));
 }
 
+/// A handle to pause and resume the downloads it is passed to, from any
+/// thread. Free it with `pahkat_download_control_free`.
+#[cthulhu::invoke(return_marshaler = "cursed::ArcMarshaler::<DownloadControl>")]
+pub extern "C" fn pahkat_download_control_new() -> Arc<DownloadControl> {
+    Arc::new(DownloadControl::default())
+}
+
+#[cthulhu::invoke]
+pub extern "C" fn pahkat_download_control_pause(
+    #[marshal(cursed::ArcRefMarshaler::<DownloadControl>)] handle: Arc<DownloadControl>,
+) {
+    handle.pause();
+}
+
+#[cthulhu::invoke]
+pub extern "C" fn pahkat_download_control_resume(
+    #[marshal(cursed::ArcRefMarshaler::<DownloadControl>)] handle: Arc<DownloadControl>,
+) {
+    handle.resume();
+}
+
+#[no_mangle]
+pub extern "C" fn pahkat_download_control_free(ptr: *const DownloadControl) {
+    if !ptr.is_null() {
+        unsafe { Arc::from_raw(ptr) };
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn pahkat_str_free(ptr: *const libc::c_char) {
     if !ptr.is_null() {
@@ -11,7 +11,7 @@ use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use crate::download::DownloadError;
-use crate::package_store::PackageStore;
+use crate::package_store::{DownloadControl, PackageStore, PackageUpdate};
 use crate::transaction::{
     PackageAction, PackageStatus, PackageStatusError, PackageTransaction, PackageTransactionError,
 };
@@ -74,6 +74,13 @@ pub extern "C" fn pahkat_prefix_package_store_all_statuses(
         .collect()
 }
 
+#[cthulhu::invoke(return_marshaler = "JsonMarshaler")]
+pub extern "C" fn pahkat_prefix_package_store_updates(
+    #[marshal(cursed::ArcRefMarshaler::<PrefixPackageStore>)] handle: Arc<PrefixPackageStore>,
+) -> Vec<PackageUpdate> {
+    handle.updates(&[Default::default()])
+}
+
 #[cthulhu::invoke(return_marshaler = "cursed::PathBufMarshaler")]
 pub extern "C" fn pahkat_prefix_package_store_import(
     #[marshal(cursed::ArcRefMarshaler::<PrefixPackageStore>)] handle: Arc<PrefixPackageStore>,
@@ -88,9 +95,38 @@ pub extern "C" fn pahkat_prefix_package_store_download(
     #[marshal(cursed::ArcRefMarshaler::<PrefixPackageStore>)] handle: Arc<PrefixPackageStore>,
     #[marshal(PackageKeyMarshaler::<'_>)] package_key: PackageKey,
     progress: extern "C" fn(*const libc::c_char, u64, u64) -> bool,
+) -> Result<PathBuf, Box<dyn Error>> {
+    download(&handle, &package_key, Default::default(), progress)
+}
+
+/// Like `pahkat_prefix_package_store_download`, pausing while `control` is
+/// paused from another thread.
+#[cthulhu::invoke(return_marshaler = "cursed::PathBufMarshaler")]
+pub extern "C" fn pahkat_prefix_package_store_download_with_control(
+    #[marshal(cursed::ArcRefMarshaler::<PrefixPackageStore>)] handle: Arc<PrefixPackageStore>,
+    #[marshal(PackageKeyMarshaler::<'_>)] package_key: PackageKey,
+    #[marshal(cursed::ArcRefMarshaler::<DownloadControl>)] control: Arc<DownloadControl>,
+    progress: extern "C" fn(*const libc::c_char, u64, u64) -> bool,
+) -> Result<PathBuf, Box<dyn Error>> {
+    download(&handle, &package_key, (*control).clone(), progress)
+}
+
+fn download(
+    handle: &PrefixPackageStore,
+    package_key: &PackageKey,
+    control: DownloadControl,
+    progress: extern "C" fn(*const libc::c_char, u64, u64) -> bool,
 ) -> Result<PathBuf, Box<dyn Error>> {
     let package_key_str = CString::new(package_key.to_string()).unwrap();
-    let mut stream = handle.download(&package_key);
+    let cancel = crate::package_store::CancellationToken::new();
+    let mut stream = handle.download_with_options(
+        package_key,
+        crate::package_store::DownloadOptions {
+            control,
+            cancel: cancel.clone(),
+            ..Default::default()
+        },
+    );
 
     let mut path: Option<PathBuf> = None;
 
@@ -101,8 +137,15 @@ pub extern "C" fn pahkat_prefix_package_store_download(
             DownloadEvent::Error(e) => {
                 return Err(e).box_err();
             }
-            DownloadEvent::Progress((current, total)) => {
-                progress(package_key_str.as_ptr(), current, total);
+            DownloadEvent::Progress(p) => {
+                // The callback returns false to cancel the download.
+                if !progress(package_key_str.as_ptr(), p.current, p.total) {
+                    cancel.cancel();
+                }
+            }
+            DownloadEvent::Phase(_) => {}
+            DownloadEvent::Retrying { attempt, delay, error } => {
+                log::warn!("Retrying download, attempt {} in {:?}: {}", attempt, delay, error);
             }
             DownloadEvent::Complete(path_buf) => {
                 path = Some(path_buf);
@@ -146,11 +189,18 @@ pub extern "C" fn pahkat_prefix_package_store_find_package_by_key(
     handle.find_package_by_key(&package_key)
 }
 
-#[cthulhu::invoke]
+#[cthulhu::invoke(return_marshaler = "JsonMarshaler")]
 pub extern "C" fn pahkat_prefix_package_store_clear_cache(
     #[marshal(cursed::ArcRefMarshaler::<PrefixPackageStore>)] handle: Arc<PrefixPackageStore>,
-) {
-    handle.clear_cache();
+) -> crate::CacheReport {
+    handle.clear_cache()
+}
+
+#[cthulhu::invoke(return_marshaler = "JsonMarshaler")]
+pub extern "C" fn pahkat_prefix_package_store_prune_cache(
+    #[marshal(cursed::ArcRefMarshaler::<PrefixPackageStore>)] handle: Arc<PrefixPackageStore>,
+) -> crate::CacheReport {
+    handle.prune_cache()
 }
 
 #[cthulhu::invoke(return_marshaler = "cursed::UnitMarshaler")]
@@ -196,6 +246,15 @@ pub extern "C" fn pahkat_prefix_transaction_new(
         .map_err(|e| e.into())
 }
 
+#[cthulhu::invoke(return_marshaler = "cursed::BoxMarshaler::<PackageTransaction>")]
+pub extern "C" fn pahkat_prefix_transaction_upgrade_all(
+    #[marshal(cursed::ArcRefMarshaler::<PrefixPackageStore>)] handle: Arc<PrefixPackageStore>,
+) -> Result<Box<PackageTransaction>, Box<dyn Error>> {
+    PackageTransaction::upgrade_all(handle as _, &[Default::default()])
+        .map(|x| Box::new(x))
+        .map_err(|e| e.into())
+}
+
 #[cthulhu::invoke(return_marshaler = "JsonMarshaler")]
 pub extern "C" fn pahkat_prefix_transaction_actions(
     #[marshal(cursed::BoxRefMarshaler::<PackageTransaction>)] 
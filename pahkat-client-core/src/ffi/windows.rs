@@ -93,11 +93,18 @@ pub extern "C" fn pahkat_windows_package_store_download(
         .map_err(|e| Box::new(e) as _)
 }
 
-#[cthulhu::invoke]
+#[cthulhu::invoke(return_marshaler = "JsonMarshaler")]
 pub extern "C" fn pahkat_windows_package_store_clear_cache(
     #[marshal(cursed::ArcRefMarshaler::<WindowsPackageStore>)] handle: Arc<WindowsPackageStore>,
-) {
-    handle.clear_cache();
+) -> crate::CacheReport {
+    handle.clear_cache()
+}
+
+#[cthulhu::invoke(return_marshaler = "JsonMarshaler")]
+pub extern "C" fn pahkat_windows_package_store_prune_cache(
+    #[marshal(cursed::ArcRefMarshaler::<WindowsPackageStore>)] handle: Arc<WindowsPackageStore>,
+) -> crate::CacheReport {
+    handle.prune_cache()
 }
 
 #[cthulhu::invoke]
@@ -0,0 +1,144 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use url::Url;
+
+use crate::config::NetworkSettings;
+use crate::package_store::TransferPriority;
+
+const DEFAULT_USER_AGENT: &str = concat!("pahkat-client/", env!("CARGO_PKG_VERSION"));
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum HttpClientError {
+    #[error("Could not read CA certificate {0:?}")]
+    ReadCertificate(PathBuf, #[source] std::io::Error),
+
+    #[error("Invalid CA certificate {0:?}")]
+    InvalidCertificate(PathBuf, #[source] reqwest::Error),
+
+    #[error("Invalid proxy URL")]
+    InvalidProxy(#[source] reqwest::Error),
+
+    #[error("Could not create HTTP client")]
+    Build(#[source] reqwest::Error),
+}
+
+/// The HTTP client shared by repository loading, string fetching and
+/// downloads, configured from the `network` settings.
+#[derive(Debug, Clone)]
+pub(crate) struct HttpClient {
+    client: reqwest::Client,
+    read_timeout: Duration,
+    retries: u32,
+    retry_delay: Duration,
+    download_limit: u64,
+    background_download_limit: u64,
+}
+
+fn is_no_proxy(url: &Url, no_proxy: &[String]) -> bool {
+    let host = match url.host_str() {
+        Some(v) => v.to_lowercase(),
+        None => return false,
+    };
+
+    no_proxy.iter().any(|entry| {
+        let entry = entry.trim().trim_start_matches('.').to_lowercase();
+        entry == "*" || host == entry || host.ends_with(&format!(".{}", entry))
+    })
+}
+
+fn certificate(path: &Path) -> Result<reqwest::Certificate, HttpClientError> {
+    let bytes =
+        std::fs::read(path).map_err(|e| HttpClientError::ReadCertificate(path.to_path_buf(), e))?;
+
+    reqwest::Certificate::from_pem(&bytes)
+        .or_else(|_| reqwest::Certificate::from_der(&bytes))
+        .map_err(|e| HttpClientError::InvalidCertificate(path.to_path_buf(), e))
+}
+
+impl HttpClient {
+    pub(crate) fn new(settings: &NetworkSettings) -> Result<HttpClient, HttpClientError> {
+        let user_agent = settings
+            .user_agent
+            .as_ref()
+            .map(|x| &**x)
+            .unwrap_or(DEFAULT_USER_AGENT);
+
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(settings.connect_timeout))
+            .user_agent(user_agent);
+
+        if let Some(proxy) = settings.proxy.as_ref() {
+            // Validate the proxy up front, the custom proxy would only fail on use.
+            let all = reqwest::Proxy::all(proxy.clone()).map_err(HttpClientError::InvalidProxy)?;
+
+            builder = if settings.no_proxy.is_empty() {
+                builder.proxy(all)
+            } else {
+                let proxy = proxy.clone();
+                let no_proxy = settings.no_proxy.clone();
+                builder.proxy(reqwest::Proxy::custom(move |url| {
+                    if is_no_proxy(url, &no_proxy) {
+                        None
+                    } else {
+                        Some(proxy.clone())
+                    }
+                }))
+            };
+        }
+
+        for path in settings.ca_certificates.iter() {
+            builder = builder.add_root_certificate(certificate(path)?);
+        }
+
+        let client = builder.build().map_err(HttpClientError::Build)?;
+
+        Ok(HttpClient {
+            client,
+            read_timeout: Duration::from_secs(settings.read_timeout),
+            retries: settings.retries,
+            retry_delay: Duration::from_secs(settings.retry_delay),
+            download_limit: settings.download_limit,
+            background_download_limit: settings.background_download_limit,
+        })
+    }
+
+    /// A request for a small file, which must be received in full within the
+    /// read timeout.
+    pub(crate) fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        self.client.get(url).timeout(self.read_timeout)
+    }
+
+    /// A request for a download, which may take any amount of time. Callers
+    /// are responsible for applying `read_timeout` to each chunk.
+    pub(crate) fn get_streaming(&self, url: &str) -> reqwest::RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub(crate) fn read_timeout(&self) -> Duration {
+        self.read_timeout
+    }
+
+    /// Bandwidth limit in bytes per second, or zero for no limit.
+    pub(crate) fn download_limit(&self, priority: TransferPriority) -> u64 {
+        match priority {
+            TransferPriority::Foreground => self.download_limit,
+            TransferPriority::Background => self.background_download_limit,
+        }
+    }
+
+    /// How long to wait before retrying after `attempt` failed retries, or
+    /// `None` if no retries are left.
+    pub(crate) fn backoff(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.retries {
+            return None;
+        }
+
+        let delay = self
+            .retry_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(MAX_RETRY_DELAY);
+        Some(delay.min(MAX_RETRY_DELAY))
+    }
+}
@@ -7,19 +7,29 @@ pub extern crate pahkat_types as types;
 #[cfg(feature = "ffi")]
 pub mod ffi;
 
+pub mod bundle;
 pub mod config;
 pub mod defaults;
+pub mod lockfile;
 pub mod package_store;
 pub mod repo;
 pub mod transaction;
 
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
+
+mod cache;
 mod cmp;
 mod download;
 mod ext;
 mod fbs;
+mod http;
 
+pub use self::cache::CacheReport;
 pub use self::config::{Config, Permission};
 pub use self::download::Download;
+pub use self::http::HttpClientError;
+pub use self::lockfile::Lockfile;
 pub use self::package_store::{DownloadEvent, InstallTarget, PackageStore};
 pub use self::repo::{LoadedRepository, PackageKey};
 pub use self::transaction::{PackageAction, PackageActionType, PackageStatus, PackageTransaction};
@@ -28,7 +38,7 @@ pub use self::transaction::{PackageAction, PackageActionType, PackageStatus, Pac
 pub use package_store::macos::MacOSPackageStore;
 
 #[cfg(feature = "prefix")]
-pub use package_store::prefix::PrefixPackageStore;
+pub use package_store::prefix::{PrefixOptions, PrefixPackageStore};
 
 #[cfg(all(windows, feature = "windows"))]
 pub use package_store::windows::WindowsPackageStore;
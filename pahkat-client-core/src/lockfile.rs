@@ -0,0 +1,334 @@
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use pahkat_types::package::Descriptor;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::package_store::InstallTarget;
+use crate::repo::PackageCandidateError;
+use crate::transaction::{PackageAction, PackageStatus, PackageTransaction};
+use crate::{PackageKey, PackageStore};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockfileError {
+    #[error("Could not read lockfile {0:?}")]
+    Read(PathBuf, #[source] std::io::Error),
+
+    #[error("Could not write lockfile {0:?}")]
+    Write(PathBuf, #[source] std::io::Error),
+
+    #[error("Invalid lockfile {0:?}")]
+    FromToml(PathBuf, #[source] toml::de::Error),
+
+    #[error("Could not serialize lockfile")]
+    ToToml(#[from] toml::ser::Error),
+}
+
+/// An installed package, pinned to its exact version.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LockedPackage {
+    pub repository: Url,
+    pub id: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(default)]
+    pub target: InstallTarget,
+}
+
+impl LockedPackage {
+    /// A key that only resolves to the locked version.
+    pub fn key(&self) -> PackageKey {
+        let mut key = PackageKey::new_unchecked(self.repository.clone(), self.id.clone(), None);
+        key.query.version = Some(self.version.clone());
+        key.query.channel = self.channel.clone();
+        key
+    }
+
+    fn is_same_package(&self, other: &LockedPackage) -> bool {
+        self.repository == other.repository && self.id == other.id && self.target == other.target
+    }
+}
+
+/// How the installed packages differ from a lockfile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Drift {
+    /// Locked, but not installed.
+    Missing(LockedPackage),
+    /// Installed at a different version than the one locked.
+    Changed {
+        locked: LockedPackage,
+        installed: String,
+    },
+    /// Installed, but not in the lockfile.
+    Unlocked(LockedPackage),
+    /// Installed, but at a version the store cannot tell, so it cannot be
+    /// compared with the lockfile.
+    UnknownVersion(PackageKey, InstallTarget),
+}
+
+/// The set of installed packages of a store, for reproducing it elsewhere.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lockfile {
+    #[serde(default, rename = "package", skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<LockedPackage>,
+}
+
+impl Lockfile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Lockfile, LockfileError> {
+        let path = path.as_ref();
+        let file =
+            std::fs::read_to_string(path).map_err(|e| LockfileError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&file).map_err(|e| LockfileError::FromToml(path.to_path_buf(), e))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), LockfileError> {
+        let path = path.as_ref();
+        let file = toml::to_string(self)?;
+        std::fs::write(path, file).map_err(|e| LockfileError::Write(path.to_path_buf(), e))
+    }
+
+    /// Every package installed for any of `targets` from the store's
+    /// repositories, sorted so that the same set always gives the same file.
+    ///
+    /// Packages whose installed version cannot be told are left out with a
+    /// warning. `drift` reports them.
+    pub fn export(
+        store: &dyn PackageStore,
+        targets: &[InstallTarget],
+    ) -> Result<Lockfile, LockfileError> {
+        let (lockfile, unknown) = Self::installed(store, targets);
+
+        for (key, target) in unknown.iter() {
+            log::warn!("Not locking {} ({:?}), its installed version is not known", key, target);
+        }
+
+        Ok(lockfile)
+    }
+
+    /// The installed packages, and those whose version is not known.
+    fn installed(
+        store: &dyn PackageStore,
+        targets: &[InstallTarget],
+    ) -> (Lockfile, Vec<(PackageKey, InstallTarget)>) {
+        let urls = store.repos().read().unwrap().keys().cloned().collect::<Vec<_>>();
+        let mut packages = vec![];
+        let mut unknown = vec![];
+
+        for url in urls.iter() {
+            for target in targets.iter() {
+                for (id, status) in store.all_statuses(url, *target) {
+                    let status = match status {
+                        Ok(v) if v != PackageStatus::NotInstalled => v,
+                        _ => continue,
+                    };
+
+                    let key = PackageKey::new_unchecked(url.clone(), id.clone(), None);
+                    let descriptor: Option<Descriptor> =
+                        store.find_package_by_key(&key).and_then(|x| x.try_into().ok());
+
+                    let version = match store.installed_version(&key, *target) {
+                        Some(v) => v,
+                        // An up to date package has the latest release installed.
+                        None if status == PackageStatus::UpToDate => {
+                            let repos = store.repos();
+                            let repos = repos.read().unwrap();
+                            let query = crate::repo::ReleaseQuery::new(&key, &*repos);
+                            match crate::repo::resolve_payload(&key, &query, &*repos) {
+                                Ok((_, release, _)) => release.version.to_string(),
+                                Err(_) => {
+                                    unknown.push((key, *target));
+                                    continue;
+                                }
+                            }
+                        }
+                        None => {
+                            unknown.push((key, *target));
+                            continue;
+                        }
+                    };
+
+                    let channel = descriptor.and_then(|descriptor| {
+                        descriptor
+                            .release
+                            .into_iter()
+                            .find(|x| x.version.to_string() == version)
+                            .and_then(|x| x.channel)
+                    });
+
+                    packages.push(LockedPackage {
+                        repository: url.clone(),
+                        id,
+                        version,
+                        channel,
+                        target: *target,
+                    });
+                }
+            }
+        }
+
+        packages.sort_by(|a, b| {
+            (a.repository.as_str(), &a.id, a.target).cmp(&(b.repository.as_str(), &b.id, b.target))
+        });
+
+        (Lockfile { packages }, unknown)
+    }
+
+    fn targets(&self) -> Vec<InstallTarget> {
+        let mut targets = self.packages.iter().map(|x| x.target).collect::<Vec<_>>();
+        targets.sort();
+        targets.dedup();
+
+        if targets.is_empty() {
+            targets.push(InstallTarget::default());
+        }
+
+        targets
+    }
+
+    /// Compares the installed packages with this lockfile.
+    pub fn drift(&self, store: &dyn PackageStore) -> Result<Vec<Drift>, LockfileError> {
+        let (installed, unknown) = Lockfile::installed(store, &self.targets());
+        let mut drift = vec![];
+
+        for locked in self.packages.iter() {
+            let is_unknown = unknown.iter().any(|(key, target)| {
+                key.repository_url == locked.repository && key.id == locked.id && *target == locked.target
+            });
+            if is_unknown {
+                continue;
+            }
+
+            match installed.packages.iter().find(|x| x.is_same_package(locked)) {
+                None => drift.push(Drift::Missing(locked.clone())),
+                Some(x) if x.version != locked.version => drift.push(Drift::Changed {
+                    locked: locked.clone(),
+                    installed: x.version.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+
+        for package in installed.packages.into_iter() {
+            if !self.packages.iter().any(|x| x.is_same_package(&package)) {
+                drift.push(Drift::Unlocked(package));
+            }
+        }
+
+        drift.extend(
+            unknown
+                .into_iter()
+                .map(|(key, target)| Drift::UnknownVersion(key, target)),
+        );
+
+        Ok(drift)
+    }
+
+    /// A transaction installing exactly the locked versions, including
+    /// downgrades. Packages already at their locked version are left alone,
+    /// and packages missing from the lockfile are not uninstalled.
+    pub fn transaction(
+        &self,
+        store: Arc<dyn PackageStore>,
+    ) -> Result<PackageTransaction, PackageCandidateError> {
+        let actions = self
+            .packages
+            .iter()
+            .map(|x| PackageAction::install(x.key(), x.target))
+            .collect::<Vec<_>>();
+
+        PackageTransaction::new(store, actions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{descriptor, payload, MemoryPackageStore, RepositoryBuilder};
+
+    fn repo_url() -> Url {
+        Url::parse("https://pahkat.test/repo/").unwrap()
+    }
+
+    fn key(id: &str) -> PackageKey {
+        PackageKey::new_unchecked(repo_url(), id.to_string(), None)
+    }
+
+    fn locked(id: &str, version: &str) -> LockedPackage {
+        LockedPackage {
+            repository: repo_url(),
+            id: id.to_string(),
+            version: version.to_string(),
+            channel: None,
+            target: InstallTarget::System,
+        }
+    }
+
+    fn store() -> MemoryPackageStore {
+        let mut builder = RepositoryBuilder::new(repo_url());
+        for (id, version) in &[("a", "1.0.0"), ("b", "2.0.0"), ("c", "1.0.0"), ("d", "1.0.0")] {
+            let url = repo_url().join(&format!("{}.bin", id)).unwrap();
+            builder = builder.package(descriptor(id, version, payload(url)));
+        }
+
+        let store = MemoryPackageStore::new();
+        store.add_repo(builder.build());
+        store
+    }
+
+    #[test]
+    fn round_trips_through_toml() {
+        let mut beta = locked("b", "2.0.0-beta.1");
+        beta.channel = Some("beta".into());
+        beta.target = InstallTarget::User;
+
+        let lockfile = Lockfile {
+            packages: vec![locked("a", "1.0.0"), beta],
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pahkat.lock");
+        lockfile.save(&path).unwrap();
+        assert_eq!(Lockfile::load(&path).unwrap(), lockfile);
+
+        let file = std::fs::read_to_string(&path).unwrap();
+        assert!(file.contains("[[package]]"), "{}", file);
+        assert_eq!(toml::from_str::<Lockfile>("").unwrap(), Lockfile::default());
+    }
+
+    #[test]
+    fn exports_installed_versions() {
+        let store = store();
+        store.set_installed(&key("b"), InstallTarget::System, "1.5.0");
+        store.set_installed(&key("a"), InstallTarget::System, "1.0.0");
+
+        let lockfile = Lockfile::export(&store, &[InstallTarget::System]).unwrap();
+        assert_eq!(lockfile.packages, vec![locked("a", "1.0.0"), locked("b", "1.5.0")]);
+    }
+
+    #[test]
+    fn classifies_drift() {
+        let store = store();
+        store.set_installed(&key("a"), InstallTarget::System, "1.0.0");
+        store.set_installed(&key("b"), InstallTarget::System, "1.5.0");
+        store.set_installed(&key("c"), InstallTarget::System, "1.0.0");
+
+        let lockfile = Lockfile {
+            packages: vec![locked("a", "1.0.0"), locked("b", "2.0.0"), locked("d", "1.0.0")],
+        };
+
+        assert_eq!(
+            lockfile.drift(&store).unwrap(),
+            vec![
+                Drift::Changed {
+                    locked: locked("b", "2.0.0"),
+                    installed: "1.5.0".into(),
+                },
+                Drift::Missing(locked("d", "1.0.0")),
+                Drift::Unlocked(locked("c", "1.0.0")),
+            ]
+        );
+    }
+}
@@ -15,9 +15,9 @@ use serde::de::{self, Deserializer};
 use serde::Deserialize;
 use url::Url;
 
-use super::{PackageStore, SharedRepos, SharedStoreConfig};
+use super::{PackageStore, SharedRepoErrors, SharedRepos, SharedStoreConfig};
 use crate::package_store::{ImportError, InstallTarget, LocalizedStrings};
-use crate::repo::{PackageQuery, RepoDownloadError};
+use crate::repo::{PackageQuery, RefreshError, SearchOptions};
 use crate::transaction::{install::InstallError, install::ProcessError, uninstall::UninstallError};
 use crate::transaction::{PackageStatus, PackageStatusError, ResolvedDescriptor, ResolvedPackageQuery};
 use crate::{cmp, Config, PackageKey};
@@ -52,6 +52,7 @@ struct BundlePlistInfo {
 
 pub struct MacOSPackageStore {
     repos: SharedRepos,
+    repo_errors: SharedRepoErrors,
     config: SharedStoreConfig,
 }
 
@@ -60,10 +61,21 @@ impl PackageStore for MacOSPackageStore {
         Arc::clone(&self.repos)
     }
 
+    fn repo_errors(&self) -> SharedRepoErrors {
+        Arc::clone(&self.repo_errors)
+    }
+
     fn config(&self) -> SharedStoreConfig {
         Arc::clone(&self.config)
     }
 
+    fn install_dir(&self, target: InstallTarget) -> Option<PathBuf> {
+        match target {
+            InstallTarget::System => Some(PathBuf::from("/Applications")),
+            InstallTarget::User => dirs::home_dir(),
+        }
+    }
+
     fn install(
         &self,
         key: &PackageKey,
@@ -123,9 +135,10 @@ impl PackageStore for MacOSPackageStore {
         crate::repo::import(&self.config, key, &query, &*repos, installer_path)
     }
 
-    fn download(
+    fn download_with_options(
         &self,
         key: &PackageKey,
+        options: crate::package_store::DownloadOptions,
     ) -> std::pin::Pin<
         Box<
             dyn futures::stream::Stream<Item = crate::package_store::DownloadEvent>
@@ -136,7 +149,7 @@ impl PackageStore for MacOSPackageStore {
     > {
         let repos = self.repos.read().unwrap();
         let query = crate::repo::ReleaseQuery::new(key, &*repos);
-        crate::repo::download(&self.config, key, &query, &*repos)
+        crate::repo::download(&self.config, key, &query, &*repos, options)
     }
 
     fn status(
@@ -157,6 +170,14 @@ impl PackageStore for MacOSPackageStore {
         self.status_impl(&descriptor, &release, install_target)
     }
 
+    /// The version in the pkgutil receipt of any of the package's pkg ids.
+    fn installed_version(&self, key: &PackageKey, target: InstallTarget) -> Option<String> {
+        let repos = self.repos.read().unwrap();
+        let descriptor = crate::repo::resolve_package(key, &*repos).ok()?;
+
+        installed_package_info(&descriptor, target).map(|x| x.pkg_version)
+    }
+
     fn all_statuses(
         &self,
         repo_url: &Url,
@@ -170,30 +191,31 @@ impl PackageStore for MacOSPackageStore {
         crate::repo::find_package_by_key(key, &*repos)
     }
 
-    fn find_package_by_id(&self, package_id: &str) -> Option<(PackageKey, Package)> {
+    fn find_package_by_id(
+        &self,
+        package_id: &str,
+    ) -> Result<(PackageKey, Package), crate::repo::FindPackageError> {
         let repos = self.repos.read().unwrap();
         crate::repo::find_package_by_id(self, package_id, &*repos)
     }
 
-    fn refresh_repos(&self) -> crate::package_store::Future<Result<(), RepoDownloadError>> {
-        let config = self.config().read().unwrap().clone();
-        let repos = self.repos();
-        Box::pin(async move {
-            let result = crate::repo::refresh_repos(config).await?;
-            *repos.write().unwrap() = result;
-            Ok(())
-        })
+    fn refresh_repos(&self) -> crate::package_store::Future<Result<(), RefreshError>> {
+        crate::repo::refresh_store(self, true)
     }
 
-    fn clear_cache(&self) {
+    fn clear_cache(&self) -> crate::CacheReport {
         crate::repo::clear_cache(&self.config)
     }
 
+    fn prune_cache(&self) -> crate::CacheReport {
+        crate::repo::prune_cache(self)
+    }
+
     fn strings(&self, language: String) -> crate::package_store::Future<HashMap<Url, LocalizedStrings>> {
         let repos = self.repos.read().unwrap();
         let urls = repos.keys().cloned().collect::<Vec<_>>();
 
-        Box::pin(crate::repo::strings(urls, language))
+        Box::pin(crate::repo::strings(Arc::clone(&self.config), urls, language))
     }
 
     fn resolve_package_query(&self, query: PackageQuery, install_target: &[InstallTarget]) -> ResolvedPackageQuery {
@@ -201,6 +223,17 @@ impl PackageStore for MacOSPackageStore {
         let repos = repos.read().unwrap();
         crate::repo::resolve_package_query(self, &query, install_target, &*repos)
     }
+
+    fn search(
+        &self,
+        query: &str,
+        options: &SearchOptions,
+        install_target: &[InstallTarget],
+    ) -> Vec<ResolvedDescriptor> {
+        let repos = self.repos();
+        let repos = repos.read().unwrap();
+        crate::repo::search(self, query, options, install_target, &*repos)
+    }
 }
 
 impl MacOSPackageStore {
@@ -208,10 +241,11 @@ impl MacOSPackageStore {
     pub async fn new(config: Config) -> MacOSPackageStore {
         let store = MacOSPackageStore {
             repos: Arc::new(RwLock::new(HashMap::new())),
+            repo_errors: Default::default(),
             config: Arc::new(RwLock::new(config)),
         };
 
-        // We ignore errors here.
+        // Failures are available through `repo_errors`.
         let _ = store.refresh_repos().await;
 
         store
@@ -223,37 +257,7 @@ impl MacOSPackageStore {
         release: &pahkat_types::package::Release,
         target: InstallTarget,
     ) -> Result<PackageStatus, PackageStatusError> {
-        // Map over all targets to find pkg_ids
-        let pkg_ids: Vec<&str> = descriptor.release.iter().fold(vec![], |acc, release| {
-            release.target.iter().fold(acc, |mut acc, target| {
-                let payload = match &target.payload {
-                    pahkat_types::payload::Payload::MacOSPackage(v) => v,
-                    _ => return acc,
-                };
-                if !acc.contains(&&*payload.pkg_id) {
-                    acc.push(&*payload.pkg_id);
-                }
-                acc
-            })
-        });
-
-        let pkg_info = pkg_ids
-            .iter()
-            .find_map(|pkg_id| match get_package_info(&pkg_id, target) {
-                Ok(v) => Some(v),
-                Err(e) => {
-                    match e {
-                        ProcessError::NotFound => {}
-                        _ => {
-                            log::error!("{:?}", e);
-                        }
-                    };
-
-                    None
-                }
-            });
-
-        let pkg_info = match pkg_info {
+        let pkg_info = match installed_package_info(descriptor, target) {
             Some(v) => v,
             None => return Ok(PackageStatus::NotInstalled),
         };
@@ -264,6 +268,43 @@ impl MacOSPackageStore {
     }
 }
 
+/// The pkgutil receipt of the first of the package's pkg ids that is
+/// installed for `target`.
+fn installed_package_info(
+    descriptor: &pahkat_types::package::Descriptor,
+    target: InstallTarget,
+) -> Option<MacOSPackageExportPlist> {
+    // Map over all targets to find pkg_ids
+    let pkg_ids: Vec<&str> = descriptor.release.iter().fold(vec![], |acc, release| {
+        release.target.iter().fold(acc, |mut acc, target| {
+            let payload = match &target.payload {
+                pahkat_types::payload::Payload::MacOSPackage(v) => v,
+                _ => return acc,
+            };
+            if !acc.contains(&&*payload.pkg_id) {
+                acc.push(&*payload.pkg_id);
+            }
+            acc
+        })
+    });
+
+    pkg_ids
+        .iter()
+        .find_map(|pkg_id| match get_package_info(&pkg_id, target) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                match e {
+                    ProcessError::NotFound => {}
+                    _ => {
+                        log::error!("{:?}", e);
+                    }
+                };
+
+                None
+            }
+        })
+}
+
 #[derive(Debug, Deserialize)]
 struct MacOSPackageExportPath {
     pub gid: u64,
@@ -8,6 +8,7 @@ pub mod windows;
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::fmt::Debug;
 
@@ -16,14 +17,16 @@ use pahkat_types::package::Package;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::cache::CacheReport;
 use crate::config::Config;
-use crate::repo::{PackageQuery, RepoDownloadError};
+use crate::repo::{FindPackageError, PackageQuery, RefreshError, RepoDownloadError, SearchOptions};
 use crate::transaction::{install::InstallError, uninstall::UninstallError};
 use crate::transaction::{PackageStatus, PackageStatusError, ResolvedDescriptor, ResolvedPackageQuery};
 use crate::{LoadedRepository, PackageKey};
 
 pub type SharedStoreConfig = Arc<RwLock<Config>>;
 pub type SharedRepos = Arc<RwLock<HashMap<Url, LoadedRepository>>>;
+pub type SharedRepoErrors = Arc<RwLock<HashMap<Url, Arc<RepoDownloadError>>>>;
 
 #[derive(Debug, thiserror::Error)]
 pub enum ImportError {
@@ -44,7 +47,124 @@ pub enum ProgressEvent<P: Debug, C: Debug, E: Debug> {
     Error(E),
 }
 
-pub type DownloadEvent = ProgressEvent<(u64, u64), PathBuf, crate::download::DownloadError>;
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadPhase {
+    Connecting,
+    Downloading,
+    /// Checking the payload is complete.
+    Verifying,
+    /// Moving the payload into the package cache.
+    Moving,
+}
+
+impl DownloadPhase {
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            DownloadPhase::Connecting => 0,
+            DownloadPhase::Downloading => 1,
+            DownloadPhase::Verifying => 2,
+            DownloadPhase::Moving => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadProgress {
+    pub current: u64,
+    /// Zero if the server did not say how large the payload is.
+    pub total: u64,
+    /// Average speed since the current attempt started.
+    pub bytes_per_second: u64,
+    /// `None` if the total size or the speed is not yet known.
+    pub eta: Option<std::time::Duration>,
+    /// Bytes already on disk from an earlier attempt when this one started.
+    pub resumed_from: u64,
+}
+
+#[derive(Debug)]
+pub enum DownloadEvent {
+    Phase(DownloadPhase),
+    Progress(DownloadProgress),
+    /// A transient error interrupted the download, which will be resumed
+    /// after `delay`.
+    Retrying {
+        attempt: u32,
+        delay: std::time::Duration,
+        error: crate::download::DownloadError,
+    },
+    Complete(PathBuf),
+    Error(crate::download::DownloadError),
+}
+
+/// Which bandwidth limit from the network settings a download is subject to.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferPriority {
+    /// Downloads the user is waiting on.
+    Foreground,
+    /// Downloads started without user interaction, such as automatic updates.
+    Background,
+}
+
+impl Default for TransferPriority {
+    fn default() -> Self {
+        TransferPriority::Foreground
+    }
+}
+
+/// Pauses and resumes in-progress downloads. Clones share the same state, so
+/// one handle can control every download it was passed to.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadControl {
+    paused: Arc<AtomicBool>,
+}
+
+impl DownloadControl {
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+}
+
+/// Asks downloads, installs and transactions to stop at the next point where
+/// stopping leaves everything in a consistent state. Clones share the same
+/// state, so cancelling one cancels them all.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        Default::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    pub priority: TransferPriority,
+    pub control: DownloadControl,
+    /// A cancelled download stops with `DownloadError::UserCancelled`, keeping
+    /// what was received so far to resume from.
+    pub cancel: CancellationToken,
+}
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(rename_all = "lowercase")]
@@ -72,6 +192,16 @@ impl From<u8> for InstallTarget {
     }
 }
 
+/// An installed package with a newer release available.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageUpdate {
+    pub key: PackageKey,
+    pub target: InstallTarget,
+    /// `None` if the store does not know which version is installed.
+    pub installed_version: Option<String>,
+    pub version: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 #[non_exhaustive]
@@ -91,10 +221,28 @@ pub type Future<T> = Pin<Box<dyn std::future::Future<Output = T> + Send + Sync +
 
 pub trait PackageStore: Send + Sync {
     fn repos(&self) -> SharedRepos;
+
+    /// Repositories that failed during the last refresh.
+    fn repo_errors(&self) -> SharedRepoErrors;
     fn config(&self) -> SharedStoreConfig;
 
+    /// The directory packages are installed into for `target`, used to check
+    /// there is enough free space before a transaction. `None` if unknown.
+    fn install_dir(&self, _target: InstallTarget) -> Option<PathBuf> {
+        None
+    }
+
+    #[must_use]
+    fn download(&self, key: &PackageKey) -> Stream<DownloadEvent> {
+        self.download_with_options(key, DownloadOptions::default())
+    }
+
     #[must_use]
-    fn download(&self, key: &PackageKey) -> Stream<DownloadEvent>;
+    fn download_with_options(
+        &self,
+        key: &PackageKey,
+        options: DownloadOptions,
+    ) -> Stream<DownloadEvent>;
 
     fn import(&self, key: &PackageKey, installer_path: &Path) -> Result<PathBuf, ImportError>;
 
@@ -104,37 +252,85 @@ pub trait PackageStore: Send + Sync {
         target: InstallTarget,
     ) -> Result<PackageStatus, InstallError>;
 
+    /// Installs unless `cancel` has been cancelled. An install that has
+    /// started always runs to completion, so a package is never left half
+    /// installed.
+    fn install_cancellable(
+        &self,
+        key: &PackageKey,
+        target: InstallTarget,
+        cancel: &CancellationToken,
+    ) -> Result<PackageStatus, InstallError> {
+        if cancel.is_cancelled() {
+            return Err(InstallError::UserCancelled);
+        }
+        self.install(key, target)
+    }
+
     fn uninstall(
         &self,
         key: &PackageKey,
         target: InstallTarget,
     ) -> Result<PackageStatus, UninstallError>;
 
+    /// Uninstalls unless `cancel` has been cancelled, with the same
+    /// guarantee as `install_cancellable`.
+    fn uninstall_cancellable(
+        &self,
+        key: &PackageKey,
+        target: InstallTarget,
+        cancel: &CancellationToken,
+    ) -> Result<PackageStatus, UninstallError> {
+        if cancel.is_cancelled() {
+            return Err(UninstallError::UserCancelled);
+        }
+        self.uninstall(key, target)
+    }
+
     fn status(
         &self,
         key: &PackageKey,
         target: InstallTarget,
     ) -> Result<PackageStatus, PackageStatusError>;
 
+    /// The installed version of a package, used to put it back if a
+    /// transaction fails. `None` if not installed or not known.
+    fn installed_version(&self, _key: &PackageKey, _target: InstallTarget) -> Option<String> {
+        None
+    }
+
     fn all_statuses(
         &self,
         repo_url: &Url,
         target: InstallTarget,
     ) -> BTreeMap<String, Result<PackageStatus, PackageStatusError>>;
 
-    fn find_package_by_id(&self, package_id: &str) -> Option<(PackageKey, Package)>;
+    /// Every package installed for any of `targets` with a newer release in
+    /// its repository's channel. Packages pinned in the repository's record
+    /// are left out.
+    fn updates(&self, targets: &[InstallTarget]) -> Vec<PackageUpdate> {
+        crate::repo::updates(self, targets)
+    }
+
+    fn find_package_by_id(&self, package_id: &str) -> Result<(PackageKey, Package), FindPackageError>;
 
     fn find_package_by_key(&self, key: &PackageKey) -> Option<Package>;
 
     #[must_use]
-    fn refresh_repos(&self) -> Future<Result<(), RepoDownloadError>>;
+    fn refresh_repos(&self) -> Future<Result<(), RefreshError>>;
+
+    fn clear_cache(&self) -> CacheReport;
 
-    fn clear_cache(&self);
+    /// Evicts the least recently used payloads until the package cache is
+    /// within the configured size limit.
+    fn prune_cache(&self) -> CacheReport;
 
+    /// Downloads every repository again, even if the remote reports it as
+    /// unchanged. The cached copies are only replaced once downloaded, so a
+    /// repository that cannot be reached still loads from the cache.
     #[must_use]
-    fn force_refresh_repos(&self) -> Future<Result<(), RepoDownloadError>> {
-        self.clear_cache();
-        self.refresh_repos()
+    fn force_refresh_repos(&self) -> Future<Result<(), RefreshError>> {
+        crate::repo::refresh_store(self, false)
     }
 
     fn strings(&self, language: String) -> Future<HashMap<Url, LocalizedStrings>>;
@@ -142,4 +338,11 @@ pub trait PackageStore: Send + Sync {
     // #[export::experimental]
     fn resolve_package_query(&self, query: PackageQuery, install_target: &[InstallTarget]) -> ResolvedPackageQuery;
 
+    fn search(
+        &self,
+        query: &str,
+        options: &SearchOptions,
+        install_target: &[InstallTarget],
+    ) -> Vec<ResolvedDescriptor>;
+
 }
@@ -13,13 +13,13 @@ use url::Url;
 use xz2::read::XzDecoder;
 
 use super::InstallTarget;
-use crate::repo::RepoDownloadError;
+use crate::repo::RefreshError;
 use crate::transaction::{
     install::InstallError, uninstall::UninstallError, PackageDependencyError, ResolvedPackageQuery,
 };
 use crate::{
     cmp, download::Download, download::DownloadManager, package_store::ImportError,
-    repo::{PackageQuery, LoadedRepository}, transaction::{ResolvedDescriptor, PackageStatus}, transaction::PackageStatusError, Config,
+    repo::{PackageQuery, LoadedRepository, SearchOptions}, transaction::{ResolvedDescriptor, PackageStatus}, transaction::PackageStatusError, Config,
     PackageKey, PackageStore,
 };
 
@@ -31,9 +31,21 @@ pub struct PrefixPackageStore {
     pool: r2d2::Pool<SqliteConnectionManager>,
     prefix: PathBuf,
     repos: Arc<RwLock<HashMap<Url, LoadedRepository>>>,
+    repo_errors: super::SharedRepoErrors,
     config: Arc<RwLock<Config>>,
 }
 
+/// Configuration a prefix reads besides its own. Both are off by default, so
+/// that a prefix works the same on any machine and for anyone.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrefixOptions {
+    /// Read the system configuration, such as `/etc/pahkat`, under the prefix
+    /// configuration.
+    pub system_config: bool,
+    /// Override settings with `PAHKAT_*` environment variables.
+    pub env_overrides: bool,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Provided path was not a valid prefix destination")]
@@ -56,7 +68,14 @@ impl PrefixPackageStore {
     pub async fn open_or_create<P: AsRef<Path>>(
         prefix_path: P,
     ) -> Result<PrefixPackageStore, Error> {
-        match Self::open(prefix_path.as_ref()).await {
+        Self::open_or_create_with(prefix_path, PrefixOptions::default()).await
+    }
+
+    pub async fn open_or_create_with<P: AsRef<Path>>(
+        prefix_path: P,
+        options: PrefixOptions,
+    ) -> Result<PrefixPackageStore, Error> {
+        match Self::open_with(prefix_path.as_ref(), options).await {
             Ok(v) => return Ok(v),
             Err(e) => match e {
                 Error::InvalidPrefixPath(_) => {}
@@ -64,10 +83,17 @@ impl PrefixPackageStore {
             },
         };
 
-        Self::create(prefix_path).await
+        Self::create_with(prefix_path, options).await
     }
 
     pub async fn create<P: AsRef<Path>>(prefix_path: P) -> Result<PrefixPackageStore, Error> {
+        Self::create_with(prefix_path, PrefixOptions::default()).await
+    }
+
+    pub async fn create_with<P: AsRef<Path>>(
+        prefix_path: P,
+        options: PrefixOptions,
+    ) -> Result<PrefixPackageStore, Error> {
         create_dir_all(&prefix_path).map_err(Error::CreateDirFailed)?;
         let prefix_path = prefix_path
             .as_ref()
@@ -75,7 +101,7 @@ impl PrefixPackageStore {
             .map_err(Error::InvalidPrefixPath)?;
         create_dir_all(&prefix_path.join("pkg")).map_err(Error::CreateDirFailed)?;
 
-        let config = Config::load(&prefix_path, crate::config::Permission::ReadWrite)?;
+        let config = Self::load_config(&prefix_path, options)?;
 
         let db_file_path = PrefixPackageStore::package_db_path(&config);
         let manager = SqliteConnectionManager::file(&db_file_path);
@@ -87,22 +113,30 @@ impl PrefixPackageStore {
             pool,
             prefix: prefix_path,
             repos: Arc::new(RwLock::new(HashMap::new())),
+            repo_errors: Default::default(),
             config: Arc::new(RwLock::new(config)),
         };
 
-        // We ignore failures here.
+        // Failures are available through `repo_errors`.
         let _ = store.refresh_repos().await;
 
         Ok(store)
     }
 
     pub async fn open<P: AsRef<Path>>(prefix_path: P) -> Result<PrefixPackageStore, Error> {
+        Self::open_with(prefix_path, PrefixOptions::default()).await
+    }
+
+    pub async fn open_with<P: AsRef<Path>>(
+        prefix_path: P,
+        options: PrefixOptions,
+    ) -> Result<PrefixPackageStore, Error> {
         let prefix_path = prefix_path
             .as_ref()
             .canonicalize()
             .map_err(Error::InvalidPrefixPath)?;
         log::debug!("{:?}", &prefix_path);
-        let config = Config::load(&prefix_path, crate::config::Permission::ReadWrite)?;
+        let config = Self::load_config(&prefix_path, options)?;
 
         let db_file_path = PrefixPackageStore::package_db_path(&config);
         log::debug!("{:?}", &db_file_path);
@@ -113,15 +147,43 @@ impl PrefixPackageStore {
             pool,
             prefix: prefix_path,
             repos: Arc::new(RwLock::new(HashMap::new())),
+            repo_errors: Default::default(),
             config: Arc::new(RwLock::new(config)),
         };
 
-        // We ignore failures here.
+        // Failures are available through `repo_errors`.
         let _ = store.refresh_repos().await;
 
         Ok(store)
     }
 
+    /// The prefix configuration, with the system configuration and environment
+    /// overrides only if `options` asks for them. The user configuration is
+    /// always left out so that a prefix works the same for anyone.
+    fn load_config(
+        prefix_path: &Path,
+        options: PrefixOptions,
+    ) -> Result<Config, crate::config::Error> {
+        let mut loader = crate::config::ConfigLoader::new();
+
+        #[cfg(not(target_os = "android"))]
+        {
+            if options.system_config {
+                if let Some(system_path) = crate::defaults::system_config_path() {
+                    loader = loader.system(system_path);
+                }
+            }
+        }
+
+        loader = loader.prefix(prefix_path, crate::config::Permission::ReadWrite);
+
+        if options.env_overrides {
+            loader = loader.env();
+        }
+
+        loader.load()
+    }
+
     #[inline(always)]
     fn make_pool(
         manager: SqliteConnectionManager,
@@ -166,10 +228,18 @@ impl PackageStore for PrefixPackageStore {
         Arc::clone(&self.repos)
     }
 
+    fn repo_errors(&self) -> super::SharedRepoErrors {
+        Arc::clone(&self.repo_errors)
+    }
+
     fn config(&self) -> super::SharedStoreConfig {
         Arc::clone(&self.config)
     }
 
+    fn install_dir(&self, _target: InstallTarget) -> Option<PathBuf> {
+        Some(self.prefix.clone())
+    }
+
     fn import(&self, key: &PackageKey, installer_path: &Path) -> Result<PathBuf, ImportError> {
         log::debug!("IMPORTING");
         let repos = self.repos.read().unwrap();
@@ -177,9 +247,10 @@ impl PackageStore for PrefixPackageStore {
         crate::repo::import(&self.config, key, &query, &*repos, installer_path)
     }
 
-    fn download(
+    fn download_with_options(
         &self,
         key: &PackageKey,
+        options: crate::package_store::DownloadOptions,
     ) -> std::pin::Pin<
         Box<
             dyn futures::stream::Stream<Item = crate::package_store::DownloadEvent>
@@ -190,7 +261,7 @@ impl PackageStore for PrefixPackageStore {
     > {
         let repos = self.repos.read().unwrap();
         let query = crate::repo::ReleaseQuery::new(key, &*repos);
-        crate::repo::download(&self.config, key, &query, &*repos)
+        crate::repo::download(&self.config, key, &query, &*repos, options)
     }
 
     fn install(
@@ -249,7 +320,6 @@ impl PackageStore for PrefixPackageStore {
 
         {
             let record = PackageDbRecord {
-                id: 0,
                 url: key.clone().without_query_params().to_string(),
                 version: release.version.to_string(),
                 files,
@@ -341,6 +411,11 @@ impl PackageStore for PrefixPackageStore {
         status
     }
 
+    fn installed_version(&self, key: &PackageKey, _target: InstallTarget) -> Option<String> {
+        let mut conn = self.pool.get().ok()?;
+        PackageDbRecord::find_by_id(&mut conn, &key).map(|x| x.version)
+    }
+
     fn all_statuses(
         &self,
         repo_url: &Url,
@@ -354,30 +429,31 @@ impl PackageStore for PrefixPackageStore {
         crate::repo::find_package_by_key(key, &*repos)
     }
 
-    fn find_package_by_id(&self, package_id: &str) -> Option<(PackageKey, Package)> {
+    fn find_package_by_id(
+        &self,
+        package_id: &str,
+    ) -> Result<(PackageKey, Package), crate::repo::FindPackageError> {
         let repos = self.repos.read().unwrap();
         crate::repo::find_package_by_id(self, package_id, &*repos)
     }
 
-    fn refresh_repos(&self) -> crate::package_store::Future<Result<(), RepoDownloadError>> {
-        let config = self.config().read().unwrap().clone();
-        let repos = self.repos();
-        Box::pin(async move {
-            let result = crate::repo::refresh_repos(config).await?;
-            *repos.write().unwrap() = result;
-            Ok(())
-        })
+    fn refresh_repos(&self) -> crate::package_store::Future<Result<(), RefreshError>> {
+        crate::repo::refresh_store(self, true)
     }
 
-    fn clear_cache(&self) {
+    fn clear_cache(&self) -> crate::CacheReport {
         crate::repo::clear_cache(&self.config)
     }
 
+    fn prune_cache(&self) -> crate::CacheReport {
+        crate::repo::prune_cache(self)
+    }
+
     fn strings(&self, language: String) -> crate::package_store::Future<HashMap<Url, crate::package_store::LocalizedStrings>> {
         let repos = self.repos.read().unwrap();
         let urls = repos.keys().cloned().collect::<Vec<_>>();
 
-        Box::pin(crate::repo::strings(urls, language))
+        Box::pin(crate::repo::strings(Arc::clone(&self.config), urls, language))
     }
 
     fn resolve_package_query(&self, query: PackageQuery, install_target: &[InstallTarget]) -> ResolvedPackageQuery {
@@ -385,11 +461,21 @@ impl PackageStore for PrefixPackageStore {
         let repos = repos.read().unwrap();
         crate::repo::resolve_package_query(self, &query, install_target, &*repos)
     }
+
+    fn search(
+        &self,
+        query: &str,
+        options: &SearchOptions,
+        install_target: &[InstallTarget],
+    ) -> Vec<ResolvedDescriptor> {
+        let repos = self.repos();
+        let repos = repos.read().unwrap();
+        crate::repo::search(self, query, options, install_target, &*repos)
+    }
 }
 
 #[derive(Debug)]
 struct PackageDbRecord {
-    id: i64,
     url: String,
     version: String,
     files: Vec<String>,
@@ -402,7 +488,7 @@ impl<'a> PackageDbConnection<'a> {
     fn dependencies(&self, url: &str) -> Vec<String> {
         let mut stmt = self
             .0
-            .prepare("SELECT dependency_id FROM packages_dependencies WHERE package_id = (SELECT id FROM packages WHERE url = ?)")
+            .prepare("SELECT url FROM packages WHERE id IN (SELECT dependency_id FROM packages_dependencies WHERE package_id = (SELECT id FROM packages WHERE url = ?))")
             .unwrap();
 
         let res = stmt
@@ -454,7 +540,6 @@ impl<'a> PackageDbConnection<'a> {
                 version=excluded.version,
                 updated_on=excluded.updated_on",
             &[
-                // (":id", &pkg.id),
                 (":url", &pkg.url),
                 (":version", &pkg.version),
                 (":installed_on", &utc),
@@ -506,15 +591,16 @@ impl<'a> PackageDbConnection<'a> {
     fn remove_pkg(&mut self, pkg: &PackageDbRecord) -> rusqlite::Result<()> {
         let tx = self.0.transaction().unwrap();
 
-        tx.execute("DELETE FROM packages WHERE id = ?", &[&pkg.id])?;
+        // Records are found by URL, so their id is not known.
         tx.execute(
-            "DELETE FROM packages_dependencies WHERE package_id = ?",
-            &[&pkg.id],
+            "DELETE FROM packages_dependencies WHERE package_id = (SELECT id FROM packages WHERE url = ?)",
+            &[&pkg.url],
         )?;
         tx.execute(
-            "DELETE FROM packages_files WHERE package_id = ?",
-            &[&pkg.id],
+            "DELETE FROM packages_files WHERE package_id = (SELECT id FROM packages WHERE url = ?)",
+            &[&pkg.url],
         )?;
+        tx.execute("DELETE FROM packages WHERE url = ?", &[&pkg.url])?;
 
         tx.commit()
     }
@@ -537,7 +623,6 @@ impl PackageDbRecord {
         let dependencies = conn.dependencies(&url);
 
         Some(PackageDbRecord {
-            id: 0,
             url,
             version,
             files,
@@ -556,54 +641,78 @@ impl PackageDbRecord {
     }
 }
 
-// #[test]
-// fn test_create_database() {
-//     let conn = rusqlite::Connection::open_in_memory().unwrap();
-//     let store = TarballPackageStore::new(conn, Path::new("/"));
-//     store.init_sqlite_database().unwrap();
-// }
-
-// #[test]
-// fn test_create_record() {
-//     let mut conn = {
-//         let mut conn = rusqlite::Connection::open_in_memory().unwrap();
-//         let store = TarballPackageStore::new(conn, Path::new("/"));
-//         store.init_sqlite_database().unwrap();
-//         store.conn.into_inner()
-//     };
-
-//     let pkg = PackageDbRecord {
-//         id: "test-pkg".to_owned(),
-//         version: "1.0.0".to_owned(),
-//         files: vec![Path::new("bin/test").to_path_buf()],
-//         dependencies: vec!["one-thing".to_owned()]
-//     };
-
-//     {
-//         pkg.save(conn.transaction().unwrap()).unwrap();
-//     }
-
-//     let found = PackageDbRecord::find_by_id(&mut conn, "test-pkg");
-//     assert!(found.is_some());
-// }
-
-// #[test]
-// fn test_download_repo() {
-//     let repo = download_repository("http://localhost:8000").unwrap();
-//     assert_eq!(repo.meta.base, "localhost");
-// }
-
-// #[test]
-// fn test_extract_files() {
-//     let tmpdir = Path::new("/tmp");
-//     let conn = rusqlite::Connection::open_in_memory().unwrap();
-
-//     let pkgstore = TarballPackageStore::new(conn, tmpdir);
-//     pkgstore.init_sqlite_database();
-//     let repo = Repository::from_url("http://localhost:8000").unwrap();
-
-//     let test_pkg = repo.package("test-pkg").unwrap();
-//     let inst_path = test_pkg.download(&tmpdir).expect("a download");
-//     pkgstore.install(test_pkg, &inst_path).unwrap();
-//     pkgstore.uninstall(test_pkg).unwrap();
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection() -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(SQL_INIT).unwrap();
+        conn
+    }
+
+    fn key(id: &str) -> PackageKey {
+        PackageKey::new_unchecked(
+            Url::parse("https://pahkat.test/repo/").unwrap(),
+            id.to_string(),
+            None,
+        )
+    }
+
+    fn record(id: &str, version: &str, files: &[&str], dependencies: &[&str]) -> PackageDbRecord {
+        PackageDbRecord {
+            url: key(id).to_string(),
+            version: version.to_string(),
+            files: files.iter().map(|x| x.to_string()).collect(),
+            dependencies: dependencies.iter().map(|x| key(x).to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn saves_and_finds_records() {
+        let mut conn = connection();
+        record("lib", "1.0.0", &["lib/liblib.so"], &[]).save(&mut conn).unwrap();
+        record("app", "2.0.0", &["bin/app", "share/app"], &["lib"])
+            .save(&mut conn)
+            .unwrap();
+
+        let found = PackageDbRecord::find_by_id(&mut conn, &key("app")).unwrap();
+        assert_eq!(found.version, "2.0.0");
+        assert_eq!(found.files, vec!["bin/app", "share/app"]);
+        assert_eq!(found.dependencies, vec![key("lib").to_string()]);
+
+        assert!(PackageDbRecord::find_by_id(&mut conn, &key("missing")).is_none());
+    }
+
+    #[test]
+    fn saving_again_replaces_the_record() {
+        let mut conn = connection();
+        record("app", "1.0.0", &["bin/app", "share/old"], &[]).save(&mut conn).unwrap();
+        record("app", "1.1.0", &["bin/app"], &[]).save(&mut conn).unwrap();
+
+        let found = PackageDbRecord::find_by_id(&mut conn, &key("app")).unwrap();
+        assert_eq!(found.version, "1.1.0");
+        assert_eq!(found.files, vec!["bin/app"]);
+    }
+
+    #[test]
+    fn deletes_a_found_record() {
+        let mut conn = connection();
+        record("lib", "1.0.0", &[], &[]).save(&mut conn).unwrap();
+        record("app", "1.0.0", &["bin/app"], &["lib"]).save(&mut conn).unwrap();
+
+        let found = PackageDbRecord::find_by_id(&mut conn, &key("app")).unwrap();
+        found.delete(&mut conn).unwrap();
+        assert!(PackageDbRecord::find_by_id(&mut conn, &key("app")).is_none());
+
+        let orphans: i64 = conn
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM packages_files) + (SELECT COUNT(*) FROM packages_dependencies)",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(orphans, 0);
+        assert!(PackageDbRecord::find_by_id(&mut conn, &key("lib")).is_some());
+    }
+}
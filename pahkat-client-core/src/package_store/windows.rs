@@ -14,7 +14,7 @@ use winreg::enums::*;
 use winreg::RegKey;
 
 use crate::package_store::{ImportError, InstallTarget};
-use crate::repo::{PackageQuery, RepoDownloadError};
+use crate::repo::{PackageQuery, RefreshError, SearchOptions};
 use crate::transaction::{
     install::InstallError, install::ProcessError, uninstall::UninstallError, PackageStatus,
     PackageStatusError, ResolvedDescriptor, ResolvedPackageQuery,
@@ -31,11 +31,12 @@ const DISPLAY_VERSION: &'static str = "DisplayVersion";
 const QUIET_UNINSTALL_STRING: &'static str = "QuietUninstallString";
 
 use super::LocalizedStrings;
-use crate::package_store::{SharedRepos, SharedStoreConfig};
+use crate::package_store::{SharedRepoErrors, SharedRepos, SharedStoreConfig};
 
 #[derive(Debug)]
 pub struct WindowsPackageStore {
     repos: SharedRepos,
+    repo_errors: SharedRepoErrors,
     config: SharedStoreConfig,
 }
 
@@ -56,13 +57,25 @@ impl PackageStore for WindowsPackageStore {
         Arc::clone(&self.config)
     }
 
+    fn install_dir(&self, target: InstallTarget) -> Option<PathBuf> {
+        match target {
+            InstallTarget::System => std::env::var_os("ProgramFiles").map(PathBuf::from),
+            InstallTarget::User => dirs::data_local_dir(),
+        }
+    }
+
     fn repos(&self) -> SharedRepos {
         Arc::clone(&self.repos)
     }
 
-    fn download(
+    fn repo_errors(&self) -> SharedRepoErrors {
+        Arc::clone(&self.repo_errors)
+    }
+
+    fn download_with_options(
         &self,
         key: &PackageKey,
+        options: crate::package_store::DownloadOptions,
     ) -> std::pin::Pin<
         Box<
             dyn futures::stream::Stream<Item = crate::package_store::DownloadEvent>
@@ -73,7 +86,7 @@ impl PackageStore for WindowsPackageStore {
     > {
         let repos = self.repos.read().unwrap();
         let query = crate::repo::ReleaseQuery::new(key, &*repos);
-        crate::repo::download(&self.config, key, &query, &*repos)
+        crate::repo::download(&self.config, key, &query, &*repos, options)
     }
 
     fn install(
@@ -256,30 +269,49 @@ impl PackageStore for WindowsPackageStore {
         self.status_impl(key, &descriptor, install_target)
     }
 
+    fn installed_version(&self, key: &PackageKey, _target: InstallTarget) -> Option<String> {
+        let repos = self.repos.read().unwrap();
+        let descriptor = crate::repo::resolve_package(key, &*repos).ok()?;
+
+        let mut query = crate::repo::ReleaseQuery::default();
+        query.arch = None;
+
+        let inst_key = query
+            .iter(&descriptor)
+            .filter_map(|x| match x.target.payload {
+                pahkat_types::payload::Payload::WindowsExecutable(ref v) => Some(v),
+                _ => None,
+            })
+            .find_map(|v| uninstall_regkey(&v))?;
+
+        inst_key.get_value(DISPLAY_VERSION).ok()
+    }
+
     fn find_package_by_key(&self, key: &PackageKey) -> Option<Package> {
         let repos = self.repos.read().unwrap();
         crate::repo::find_package_by_key(key, &*repos)
     }
 
-    fn find_package_by_id(&self, package_id: &str) -> Option<(PackageKey, Package)> {
+    fn find_package_by_id(
+        &self,
+        package_id: &str,
+    ) -> Result<(PackageKey, Package), crate::repo::FindPackageError> {
         let repos = self.repos.read().unwrap();
         crate::repo::find_package_by_id(self, package_id, &*repos)
     }
 
-    fn refresh_repos(&self) -> crate::package_store::Future<Result<(), RepoDownloadError>> {
-        let config = self.config().read().unwrap().clone();
-        let repos = self.repos();
-        Box::pin(async move {
-            let result = crate::repo::refresh_repos(config).await?;
-            *repos.write().unwrap() = result;
-            Ok(())
-        })
+    fn refresh_repos(&self) -> crate::package_store::Future<Result<(), RefreshError>> {
+        crate::repo::refresh_store(self, true)
     }
 
-    fn clear_cache(&self) {
+    fn clear_cache(&self) -> crate::CacheReport {
         crate::repo::clear_cache(&self.config)
     }
 
+    fn prune_cache(&self) -> crate::CacheReport {
+        crate::repo::prune_cache(self)
+    }
+
     fn import(&self, key: &PackageKey, installer_path: &Path) -> Result<PathBuf, ImportError> {
         let repos = self.repos.read().unwrap();
         let query = crate::repo::ReleaseQuery::new(key, &*repos);
@@ -301,7 +333,7 @@ impl PackageStore for WindowsPackageStore {
         let repos = self.repos.read().unwrap();
         let urls = repos.keys().cloned().collect::<Vec<_>>();
 
-        Box::pin(crate::repo::strings(urls, language))
+        Box::pin(crate::repo::strings(Arc::clone(&self.config), urls, language))
     }
 
     fn resolve_package_query(&self, query: PackageQuery, install_target: &[InstallTarget]) -> ResolvedPackageQuery {
@@ -309,16 +341,28 @@ impl PackageStore for WindowsPackageStore {
         let repos = repos.read().unwrap();
         crate::repo::resolve_package_query(self, &query, install_target, &*repos)
     }
+
+    fn search(
+        &self,
+        query: &str,
+        options: &SearchOptions,
+        install_target: &[InstallTarget],
+    ) -> Vec<ResolvedDescriptor> {
+        let repos = self.repos();
+        let repos = repos.read().unwrap();
+        crate::repo::search(self, query, options, install_target, &*repos)
+    }
 }
 
 impl WindowsPackageStore {
     pub async fn new(config: Config) -> WindowsPackageStore {
         let store = WindowsPackageStore {
             repos: Default::default(),
+            repo_errors: Default::default(),
             config: Arc::new(RwLock::new(config)),
         };
 
-        // We ignore errors here.
+        // Failures are available through `repo_errors`.
         let _ = store.refresh_repos().await;
 
         store
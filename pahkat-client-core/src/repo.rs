@@ -1,7 +1,9 @@
 mod repository;
+mod search;
 
 pub use pahkat_types::PackageKey;
-pub use repository::{LoadedRepository, RepoDownloadError};
+pub use repository::{CacheValidators, LoadedRepository, LoadedRepositoryMeta, RepoDownloadError};
+pub use search::SearchOptions;
 
 use std::collections::BTreeMap;
 use std::convert::{TryFrom, TryInto};
@@ -17,13 +19,18 @@ use thiserror::Error;
 use url::Url;
 
 use crate::package_store::DownloadEvent;
-use crate::config::Config;
+use crate::cache::CacheReport;
+use crate::config::{Config, ResolutionPolicy};
 use crate::defaults;
 use crate::fbs::PackagesExt;
-use crate::package_store::PackageStore;
+use crate::http::HttpClient;
+use crate::package_store::{PackageStore, PackageUpdate};
 use crate::transaction::{ResolvedDescriptor, ResolvedPackageQuery, PackageStatus, PackageStatusError};
 use pahkat_types::package::{Package, Release, Version, Descriptor};
+use pahkat_types::package_key::ShortKeyError;
 use pahkat_types::payload::Target;
+use pahkat_types::repo::UnknownChannel;
+use pahkat_types::ShortPackageKey;
 
 #[derive(Debug, Clone, Error)]
 pub enum PayloadError {
@@ -35,6 +42,8 @@ pub enum PayloadError {
     NoPayloadFound,
     #[error("Some criteria is not met for the current payload")]
     CriteriaUnmet(String),
+    #[error(transparent)]
+    UnknownChannel(UnknownChannel),
 }
 
 #[derive(Debug, Clone)]
@@ -44,6 +53,9 @@ pub struct ReleaseQuery<'a> {
     pub channels: Vec<&'a str>,
     pub versions: Vec<VersionQuery<'a>>,
     pub payloads: Vec<&'a str>,
+    /// Set when the key asks for a channel its repository does not declare,
+    /// in which case nothing matches and `resolve_payload` fails with it.
+    unknown_channel: Option<UnknownChannel>,
 }
 
 impl<'a> ReleaseQuery<'a> {
@@ -61,6 +73,7 @@ impl<'a> Default for ReleaseQuery<'a> {
             channels: vec![],
             versions: vec![],
             payloads: defaults::payloads().to_vec(),
+            unknown_channel: None,
         }
     }
 }
@@ -80,14 +93,13 @@ impl<'a> VersionQuery<'a> {
 
     fn matches(&self, version: &Version) -> bool {
         match (self, version) {
-            (VersionQuery::Semantic(mask), Version::Semantic(v)) => {
-                if *mask == "*" {
-                    true
-                } else {
-                    todo!()
-                }
+            (VersionQuery::Semantic(mask), Version::Semantic(_)) => {
+                *mask == "*" || version.to_string() == *mask
+            }
+            (VersionQuery::Match(v), _) | (VersionQuery::Timestamp(v), _) => {
+                version.to_string() == *v
             }
-            _ => todo!(),
+            _ => false,
         }
     }
 }
@@ -100,6 +112,9 @@ fn empty_payloads() -> &'static [&'static str] {
 pub(crate) struct ReleaseQueryIter<'a> {
     query: &'a ReleaseQuery<'a>,
     descriptor: &'a pahkat_types::package::Descriptor,
+    /// Indexes of the releases from the newest version to the oldest, so that
+    /// the newest release of any accepted channel comes first.
+    order: Vec<usize>,
     next_release: usize,
 }
 
@@ -114,23 +129,30 @@ impl<'a> ReleaseQueryIter<'a> {
     fn next_release(&mut self) -> Option<ReleaseQueryResponse<'a>> {
         log::trace!("Beginning release query iter: {:#?}", &self.query);
 
-        while let Some(release) = self.descriptor.release.get(self.next_release) {
+        while let Some(release) = self
+            .order
+            .get(self.next_release)
+            .map(|index| &self.descriptor.release[*index])
+        {
             log::trace!(
                 "Candidate release: version:{:?}, channel:{:?}",
                 &release.version.to_string(),
                 &release.channel
             );
 
-            // eprintln!("release: {:?}", &release);
-            // If query is empty, it means search only for the main empty channel
+            // Releases without a channel are stable, which every channel accepts.
             if let Some(channel) = release.channel.as_ref().map(|x| x.as_str()) {
                 if !self.query.channels.contains(&channel) {
                     log::trace!("Skipping (not accepted channel)");
                     self.next_release += 1;
                     continue;
                 }
-            } else if release.channel.is_some() && !self.query.channels.is_empty() {
-                log::trace!("Skipping (query channels not empty and no match)");
+            }
+
+            if !self.query.versions.is_empty()
+                && !self.query.versions.iter().any(|x| x.matches(&release.version))
+            {
+                log::trace!("Skipping (version does not match)");
                 self.next_release += 1;
                 continue;
             }
@@ -148,9 +170,12 @@ impl<'a> ReleaseQueryIter<'a> {
         None
     }
 
+    /// The target of `release` best suited to the query's architecture.
     #[inline(always)]
     fn next_payload(&mut self, release: &'a Release) -> Option<ReleaseQueryResponse<'a>> {
-        for ref target in release.target.iter() {
+        let mut best: Option<(usize, &'a Target)> = None;
+
+        for target in release.target.iter() {
             log::trace!(
                 "Candidate target: platform:{} arch:{:?}",
                 &target.platform,
@@ -162,22 +187,24 @@ impl<'a> ReleaseQueryIter<'a> {
                 continue;
             }
 
-            if let Some(arch) = self.query.arch {
-                if let Some(ref target_arch) = target.arch {
-                    if target_arch != arch {
-                        log::trace!("Skipping (arch does not match)");
-                        continue;
-                    }
+            let rank = match defaults::arch_rank(
+                self.query.platform,
+                self.query.arch,
+                target.arch.as_ref().map(|x| &**x),
+            ) {
+                Some(v) => v,
+                None => {
+                    log::trace!("Skipping (arch not compatible)");
+                    continue;
                 }
-            } else if target.arch.is_some() {
-                log::trace!("Skipping (no arch in query but arch in target)");
-                continue;
-            }
+            };
 
-            return Some(ReleaseQueryResponse { release, target });
+            if best.map(|(best_rank, _)| rank < best_rank).unwrap_or(true) {
+                best = Some((rank, target));
+            }
         }
 
-        None
+        best.map(|(_, target)| ReleaseQueryResponse { release, target })
     }
 }
 
@@ -203,36 +230,47 @@ impl<'a> ReleaseQuery<'a> {
         &'a self,
         descriptor: &'a pahkat_types::package::Descriptor,
     ) -> ReleaseQueryIter<'a> {
+        let mut order = (0..descriptor.release.len()).collect::<Vec<_>>();
+        if self.unknown_channel.is_some() {
+            order.clear();
+        }
+        order.sort_by(|a, b| {
+            descriptor.release[*b]
+                .version
+                .partial_cmp(&descriptor.release[*a].version)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
         ReleaseQueryIter {
             query: self,
             descriptor,
+            order,
             next_release: 0,
         }
     }
 
     pub fn new(key: &'a PackageKey, repos: &'a HashMap<Url, LoadedRepository>) -> Self {
-        let channels = key
+        let repo = repos.get(&key.repository_url);
+        let channel = key
             .query
             .channel
             .as_ref()
-            .map(|x| vec![&**x])
-            .unwrap_or_else(|| {
-                repos
-                    .iter()
-                    .find_map(|(url, repo)| {
-                        log::trace!("ReleaseQuery::new() {} {}", &key.repository_url, url);
-                        if &key.repository_url == url {
-                            log::trace!("{:?}", repo.meta());
-                            let channel = repo.meta().channel.as_ref().map(|x| &**x);
-                            log::trace!("Channel? {:?}", &channel);
-                            channel
-                        } else {
-                            None
-                        }
-                    })
-                    .map(|x| vec![x])
-                    .unwrap_or_else(|| vec![])
-            });
+            .map(|x| &**x)
+            .or_else(|| repo.and_then(|x| x.meta().channel.as_ref().map(|x| &**x)));
+        log::trace!("ReleaseQuery::new() {} channel: {:?}", &key.repository_url, &channel);
+
+        let mut unknown_channel = None;
+        let channels = match (channel, repo) {
+            (Some(channel), Some(repo)) => match repo.info().repository.channel_chain(channel) {
+                Ok(v) => v,
+                Err(e) => {
+                    unknown_channel = Some(e);
+                    vec![]
+                }
+            },
+            (Some(channel), None) => vec![channel],
+            (None, _) => vec![],
+        };
 
         ReleaseQuery {
             platform: key
@@ -255,6 +293,7 @@ impl<'a> ReleaseQuery<'a> {
                 .map(|v| vec![VersionQuery::Match(&*v)])
                 .unwrap_or_else(|| vec![]),
             payloads: defaults::payloads().to_vec(),
+            unknown_channel,
         }
     }
 }
@@ -311,26 +350,7 @@ pub(crate) fn resolve_package_query<'a>(
                     })
                     .filter_map(move |pkg| {
                         let key = PackageKey::new_unchecked(repo_url.clone(), pkg.id().unwrap().to_string(), None);
-                        let status = install_target.iter().fold(None, |acc, cur| {
-                            match acc {
-                                Some(v) if v != PackageStatus::NotInstalled => Some(v),
-                                _ => store.status(&key, *cur).ok(),
-                            }
-                        })?;
-
-                        let descriptor = Descriptor::try_from(&pkg).ok()?;
-
-                        ReleaseQuery::new(&key, repos)
-                            .iter(&descriptor)
-                            .next()
-                            .map(|x| ResolvedDescriptor {
-                                key: key.clone(),
-                                status,
-                                tags: descriptor.package.tags.clone(),
-                                name: descriptor.name.clone(),
-                                description: descriptor.description.clone(),
-                                release: crate::transaction::ResolvedRelease::new(x.release.clone(), x.target.clone())
-                            })
+                        resolve_descriptor(store, key, &pkg, install_target, repos)
                     }).collect::<Vec<_>>()
             }).collect();
         let status = descriptors.iter().fold(PackageStatus::UpToDate, |acc, cur| {
@@ -366,6 +386,74 @@ pub(crate) fn resolve_package_query<'a>(
     }
 }
 
+fn resolve_descriptor(
+    store: &dyn PackageStore,
+    key: PackageKey,
+    pkg: &crate::pahkat_fbs::Descriptor<&[u8]>,
+    install_target: &[InstallTarget],
+    repos: &HashMap<Url, LoadedRepository>,
+) -> Option<ResolvedDescriptor> {
+    let status = install_target.iter().fold(None, |acc, cur| {
+        match acc {
+            Some(v) if v != PackageStatus::NotInstalled => Some(v),
+            _ => store.status(&key, *cur).ok(),
+        }
+    })?;
+
+    let descriptor = crate::fbs::descriptor(pkg, Some(&key.repository_url)).ok()?;
+
+    ReleaseQuery::new(&key, repos)
+        .iter(&descriptor)
+        .next()
+        .map(|x| ResolvedDescriptor {
+            key: key.clone(),
+            status,
+            tags: descriptor.package.tags.clone(),
+            name: descriptor.name.clone(),
+            description: descriptor.description.clone(),
+            release: crate::transaction::ResolvedRelease::new(x.release.clone(), x.target.clone())
+        })
+}
+
+pub(crate) fn search(
+    store: &dyn PackageStore,
+    query: &str,
+    options: &SearchOptions,
+    install_target: &[InstallTarget],
+    repos: &HashMap<Url, LoadedRepository>,
+) -> Vec<ResolvedDescriptor> {
+    log::debug!("search {:?} {:?} {:?}", query, options, install_target);
+
+    let mut hits = repos
+        .values()
+        .flat_map(|repo| {
+            repo.search_index()
+                .search(query, options)
+                .into_iter()
+                .map(move |hit| (repo, hit))
+        })
+        .collect::<Vec<_>>();
+
+    hits.sort_by(|(a_repo, a), (b_repo, b)| {
+        b.score
+            .cmp(&a.score)
+            .then_with(|| a.id.cmp(b.id))
+            .then_with(|| a_repo.info().repository.url.cmp(&b_repo.info().repository.url))
+    });
+
+    let resolved = hits.into_iter().filter_map(|(repo, hit)| {
+        let packages = repo.packages();
+        let pkg = packages.packages()?.get(hit.id)?;
+        let key = PackageKey::new_unchecked(repo.info().repository.url.clone(), hit.id.to_string(), None);
+        resolve_descriptor(store, key, &pkg, install_target, repos)
+    });
+
+    match options.limit {
+        Some(limit) => resolved.take(limit).collect(),
+        None => resolved.collect(),
+    }
+}
+
 pub(crate) fn resolve_payload<'a>(
     package_key: &PackageKey,
     query: &ReleaseQuery<'a>,
@@ -379,6 +467,9 @@ pub(crate) fn resolve_payload<'a>(
     PayloadError,
 > {
     log::trace!("Resolving payload");
+    if let Some(e) = query.unknown_channel.as_ref() {
+        return Err(PayloadError::UnknownChannel(e.clone()));
+    }
     let descriptor = resolve_package(package_key, repos)?;
     log::trace!("Package found");
     query
@@ -414,6 +505,7 @@ pub(crate) fn download<'a>(
     package_key: &PackageKey,
     query: &ReleaseQuery<'a>,
     repos: &HashMap<Url, LoadedRepository>,
+    options: crate::package_store::DownloadOptions,
 ) -> std::pin::Pin<
     Box<
         dyn futures::stream::Stream<Item = crate::package_store::DownloadEvent>
@@ -444,14 +536,32 @@ pub(crate) fn download<'a>(
 
     let config = config.read().unwrap();
     let settings = config.settings();
+    let client = match HttpClient::new(settings.network()) {
+        Ok(v) => v,
+        Err(e) => {
+            return Box::pin(async_stream::stream! {
+                yield DownloadEvent::Error(e.into());
+            });
+        }
+    };
     let dm = crate::download::DownloadManager::new(
+        client,
         settings.download_cache_dir().to_path_buf(),
-        settings.max_concurrent_downloads(),
+        settings.progress_interval(),
     );
 
+    let credential = match config.credential_for(&package_key.repository_url, &url) {
+        Ok(v) => v,
+        Err(e) => {
+            return Box::pin(async_stream::stream! {
+                yield DownloadEvent::Error(e.into());
+            });
+        }
+    };
+
     let output_path = crate::repo::download_dir(&*config, &url);
     let stream = async_stream::stream! {
-        match dm.download(&url, output_path).await {
+        match dm.download(&url, output_path, credential, options).await {
             Ok(mut v) => {
                 while let Some(value) = v.next().await {
                     yield value;
@@ -531,36 +641,63 @@ pub(crate) fn all_statuses<'a>(
 }
 
 pub(crate) async fn strings<'p>(
+    config: Arc<RwLock<Config>>,
     repo_urls: Vec<Url>,
     language: String
 ) -> HashMap<Url, crate::package_store::LocalizedStrings> {
-    let futures = repo_urls
+    let (client, requests) = {
+        let config = config.read().unwrap();
+
+        let client = match HttpClient::new(config.settings().network()) {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("{:?}", e);
+                return HashMap::new();
+            }
+        };
+
+        let requests = repo_urls
+            .into_iter()
+            .map(|url| {
+                let strings_url = url
+                    .join("strings/").unwrap()
+                    .join(&format!("{}.toml", language))
+                    .unwrap();
+                let credential = config.credential_for(&url, &strings_url).ok().flatten();
+                (url, strings_url, credential)
+            })
+            .collect::<Vec<_>>();
+
+        (client, requests)
+    };
+
+    let futures = requests
         .into_iter()
-        .map(|url| {
-            let strings_url = url
-                .join("strings/").unwrap()
-                .join(&format!("{}.toml", language))
-                .unwrap();
-            (url, strings_url)
-        })
-        .map(|(url, strings_url)| async move {
-            let (tx, rx) = tokio::sync::oneshot::channel();
-            tokio::spawn(async move {
-                let response = match reqwest::get(strings_url).await {
-                    Ok(v) => match v.text().await {
-                        Ok(v) => match toml::from_str(&v) {
-                            Ok(v) => Some(v),
+        .map(|(url, strings_url, credential)| {
+            let client = client.clone();
+            async move {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                tokio::spawn(async move {
+                    let mut request = client.get(strings_url.as_str());
+                    if let Some(credential) = credential.as_ref() {
+                        request = credential.apply(request);
+                    }
+                    let response = match request.send().await {
+                        Ok(v) => match v.text().await {
+                            Ok(v) => match toml::from_str(&v) {
+                                Ok(v) => Some(v),
+                                Err(_) => None,
+                            },
                             Err(_) => None,
                         },
                         Err(_) => None,
-                    },
-                    Err(_) => None,
-                };
-                tx.send(response).unwrap();
-            });
-            let result = rx.await.unwrap();
+                    };
+                    tx.send(response).unwrap();
+                });
+                let result = rx.await.unwrap();
 
-            (url, result)
+                (url, result)
+            }
         })
         .collect::<Vec<_>>();
     let results = futures::future::join_all(futures).await;
@@ -601,50 +738,262 @@ pub(crate) fn find_package_by_key<'p>(
         };
         log::trace!("Found pkg");
 
-        (&pkg).try_into().map(Package::Concrete).ok()
+        crate::fbs::descriptor(&pkg, Some(&package_key.repository_url))
+            .map(Package::Concrete)
+            .ok()
     })
 }
 
+#[derive(Debug, Clone, Error)]
+pub enum FindPackageError {
+    #[error("Could not find package for: `{0}`")]
+    NotFound(String),
+
+    /// The id is provided by more than one repository, with a key naming the
+    /// package in each of them.
+    #[error("Package `{0}` is provided by more than one repository, did you mean one of: {}", .1.join(", "))]
+    Ambiguous(String, Vec<String>),
+
+    #[error("No repository has the alias `{0}`")]
+    UnknownAlias(String),
+
+    #[error("Invalid package key `{0}`")]
+    InvalidKey(String, #[source] ShortKeyError),
+
+    #[error("Invalid channel in package key `{0}`")]
+    UnknownChannel(String, #[source] UnknownChannel),
+}
+
+/// Rejects a key asking for a channel its repository does not declare.
+fn check_channel(
+    key: &PackageKey,
+    repos: &HashMap<Url, LoadedRepository>,
+) -> Result<(), UnknownChannel> {
+    match (key.query.channel.as_ref(), repos.get(&key.repository_url)) {
+        (Some(channel), Some(repo)) => repo.info().repository.channel_chain(channel).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// How to name each of `keys` unambiguously: by the alias of its repository
+/// where it has one, and by URL otherwise.
+fn suggest_keys(config: &Config, keys: &[PackageKey]) -> Vec<String> {
+    keys.iter()
+        .map(|key| {
+            let alias = config
+                .repos()
+                .get(&key.repository_url)
+                .and_then(|x| x.alias.as_ref());
+
+            match alias {
+                Some(alias) => format!("{}:{}", alias, key.id),
+                None => key.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Orders repositories by precedence: highest `priority` first, then in the
+/// order they are listed in repos.toml. Linked repositories that were not
+/// configured directly always come last.
+pub(crate) fn ordered_repos<'a>(
+    config: &Config,
+    repos: &'a HashMap<Url, LoadedRepository>,
+) -> Vec<&'a LoadedRepository> {
+    let configured = config.repos();
+
+    let mut ordered = repos
+        .iter()
+        .map(|(url, repo)| {
+            let rank = match configured.get_full(url) {
+                Some((index, _, record)) => (false, -(record.priority as i64), index),
+                None => (true, 0, 0),
+            };
+            (rank, url, repo)
+        })
+        .collect::<Vec<_>>();
+
+    ordered.sort_by(|(a_rank, a_url, _), (b_rank, b_url, _)| {
+        a_rank.cmp(b_rank).then_with(|| a_url.cmp(b_url))
+    });
+
+    ordered.into_iter().map(|(_, _, repo)| repo).collect()
+}
+
 pub(crate) fn find_package_by_id(
     store: &dyn PackageStore,
     package_id: &str,
     repos: &HashMap<Url, LoadedRepository>,
-) -> Option<(PackageKey, Package)> {
+) -> Result<(PackageKey, Package), FindPackageError> {
+    let unknown_channel = |e| FindPackageError::UnknownChannel(package_id.to_string(), e);
+
     match PackageKey::try_from(package_id) {
-        Ok(k) => return store.find_package_by_key(&k).map(|pkg| (k, pkg)),
+        Ok(k) => {
+            check_channel(&k, repos).map_err(unknown_channel)?;
+            return store
+                .find_package_by_key(&k)
+                .map(|pkg| (k, pkg))
+                .ok_or_else(|| FindPackageError::NotFound(package_id.to_string()))
+        }
         Err(_) => {}
     };
 
-    repos.iter().find_map(|(key, repo)| {
-        let packages = repo.packages();
-        let packages = match packages.packages() {
-            Some(v) => v,
-            None => {
-                log::error!("No packages map in fbs for {:?}!", &key);
-                return None;
-            }
-        };
+    let short = package_id
+        .parse::<ShortPackageKey>()
+        .map_err(|e| FindPackageError::InvalidKey(package_id.to_string(), e))?;
+
+    let config = store.config();
+    let config = config.read().unwrap();
+
+    if let Some(alias) = short.alias.as_ref() {
+        let url = config
+            .repos()
+            .find_alias(alias)
+            .cloned()
+            .ok_or_else(|| FindPackageError::UnknownAlias(alias.to_string()))?;
+        drop(config);
+
+        let key = short.into_key(url);
+        check_channel(&key, repos).map_err(unknown_channel)?;
+        return store
+            .find_package_by_key(&key)
+            .map(|pkg| (key, pkg))
+            .ok_or_else(|| FindPackageError::NotFound(package_id.to_string()));
+    }
 
-        packages.get(package_id).map(|x| {
-            let key = PackageKey::new_unchecked(
-                repo.info().repository.url.clone(),
+    let mut candidates = ordered_repos(&config, repos)
+        .into_iter()
+        .filter_map(|repo| {
+            let packages = repo.packages();
+            let packages = match packages.packages() {
+                Some(v) => v,
+                None => {
+                    log::error!("No packages map in fbs for {:?}!", &repo.info().repository.url);
+                    return None;
+                }
+            };
+
+            packages.get(&short.id).map(|x| {
+                let key = PackageKey::new_unchecked(
+                    repo.info().repository.url.clone(),
+                    short.id.clone(),
+                    Some(short.query.clone()),
+                );
+
+                crate::fbs::descriptor(&x, Some(&key.repository_url))
+                    .map(|p| (key, p))
+                    .ok()
+            })?
+        })
+        .collect::<Vec<_>>();
+
+    if candidates.len() <= 1 {
+        let (key, descriptor) = candidates
+            .pop()
+            .ok_or_else(|| FindPackageError::NotFound(package_id.to_string()))?;
+        check_channel(&key, repos).map_err(unknown_channel)?;
+        return Ok((key, Package::Concrete(descriptor)));
+    }
+
+    let keys = candidates.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+
+    let index = match config.settings().resolution_policy() {
+        ResolutionPolicy::Strict => {
+            return Err(FindPackageError::Ambiguous(
                 package_id.to_string(),
-                None,
+                suggest_keys(&config, &keys),
+            ));
+        }
+        ResolutionPolicy::FirstMatch => {
+            log::warn!(
+                "Package `{}` is provided by more than one repository, using {}: {}",
+                package_id,
+                &keys[0],
+                suggest_keys(&config, &keys).join(", ")
             );
+            0
+        }
+        ResolutionPolicy::HighestVersion => {
+            // Candidates are in priority order, so the first of equal versions wins.
+            let mut best: Option<(usize, Version)> = None;
+
+            for (i, (key, descriptor)) in candidates.iter().enumerate() {
+                let version = match ReleaseQuery::new(key, repos).iter(descriptor).next() {
+                    Some(v) => v.release.version.clone(),
+                    None => continue,
+                };
+
+                let is_newer = match best.as_ref() {
+                    Some((_, current)) => version > *current,
+                    None => true,
+                };
 
-            (&x).try_into().map(|p| (key, Package::Concrete(p))).ok()
-        })?
+                if is_newer {
+                    best = Some((i, version));
+                }
+            }
+
+            best.map(|(i, _)| i).unwrap_or(0)
+        }
+    };
+
+    let (key, descriptor) = candidates.swap_remove(index);
+    check_channel(&key, repos).map_err(unknown_channel)?;
+    Ok((key, Package::Concrete(descriptor)))
+}
+
+/// Repositories that could not be refreshed, along with the reason why.
+///
+/// Repositories listed here may still have been loaded from the cache, in
+/// which case their meta is marked as stale.
+#[derive(Debug, Error)]
+#[error("{} repositories could not be refreshed", .errors.len())]
+pub struct RefreshError {
+    pub errors: HashMap<Url, Arc<RepoDownloadError>>,
+}
+
+/// Replaces the repositories of `store` with freshly refreshed ones. See
+/// `refresh_repos` for `conditional`.
+pub(crate) fn refresh_store<S: PackageStore + ?Sized>(
+    store: &S,
+    conditional: bool,
+) -> crate::package_store::Future<Result<(), RefreshError>> {
+    let config = store.config().read().unwrap().clone();
+    let repos = store.repos();
+    let repo_errors = store.repo_errors();
+
+    Box::pin(async move {
+        let (result, errors) = refresh_repos(config, conditional).await;
+        *repos.write().unwrap() = result;
+        *repo_errors.write().unwrap() = errors.clone();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(RefreshError { errors })
+        }
     })
 }
 
+/// Loads every configured repository. A `conditional` refresh only downloads
+/// the files the remote reports as changed since they were cached.
 #[must_use]
 pub(crate) async fn refresh_repos(
     config: Config,
-) -> Result<HashMap<Url, LoadedRepository>, RepoDownloadError> {
+    conditional: bool,
+) -> (HashMap<Url, LoadedRepository>, HashMap<Url, Arc<RepoDownloadError>>) {
     let config = Arc::new(config);
 
     log::debug!("Refreshing repos...");
 
+    let client = match HttpClient::new(config.settings().network()) {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("{:?}", e);
+            return load_cached_repos(&config, Arc::new(e.into()));
+        }
+    };
+
     let repo_data = {
         let repo_keys =
             config
@@ -656,26 +1005,39 @@ pub(crate) async fn refresh_repos(
                 });
 
         workqueue::work(config, repo_keys, |url, queue, config| {
+            let client = client.clone();
             Box::pin(async move {
                 log::trace!("Downloading repo at {:?}…", &url);
 
                 let cache_dir = config.settings().repo_cache_dir();
                 let channel = config.repos().get(&url).and_then(|r| r.channel.clone());
-
-                match LoadedRepository::from_cache_or_url(url, channel, cache_dir).await {
-                    Ok(repo) => {
+                let credential = config.credential_for(&url, &url);
+
+                let result = LoadedRepository::refresh(
+                    client,
+                    url,
+                    channel,
+                    cache_dir,
+                    credential,
+                    conditional,
+                )
+                .await;
+
+                match result {
+                    (Some(repo), error) => {
                         for url in repo.info().repository.linked_repositories.iter() {
                             log::trace!("Queuing linked repo: {:?}", &url);
                             queue.push(url.clone());
                             // recurse_repo(url.clone(), Arc::clone(&repos), Arc::clone(&config)).await?;
                         }
 
-                        Ok(repo)
+                        Ok((repo, error))
                     }
-                    Err(e) => {
+                    (None, Some(e)) => {
                         log::error!("{:?}", e);
                         Err(e)
                     }
+                    (None, None) => unreachable!(),
                 }
             })
         })
@@ -684,23 +1046,124 @@ pub(crate) async fn refresh_repos(
     };
 
     let mut map = HashMap::new();
+    let mut errors = HashMap::new();
 
     for (key, value) in repo_data.into_iter() {
         log::debug!("Resolved repository: {:?}", &key);
 
         match value {
-            Ok(v) => {
-                map.insert(key, v);
+            Ok((repo, error)) => {
+                if let Some(e) = error {
+                    errors.insert(key.clone(), Arc::new(e));
+                }
+                map.insert(key, repo);
+            }
+            Err(e) => {
+                errors.insert(key, Arc::new(e));
+            }
+        }
+    }
+
+    (map, errors)
+}
+
+/// Loads every configured repository, and those they link to, from the cache
+/// alone, reporting `error` for each of them.
+fn load_cached_repos(
+    config: &Config,
+    error: Arc<RepoDownloadError>,
+) -> (HashMap<Url, LoadedRepository>, HashMap<Url, Arc<RepoDownloadError>>) {
+    let cache_dir = config.settings().repo_cache_dir();
+    let mut pending = config.repos().keys().cloned().collect::<Vec<_>>();
+    let mut map = HashMap::new();
+    let mut errors = HashMap::new();
+
+    while let Some(url) = pending.pop() {
+        if errors.contains_key(&url) {
+            continue;
+        }
+        errors.insert(url.clone(), Arc::clone(&error));
+
+        let channel = config.repos().get(&url).and_then(|r| r.channel.clone());
+        match LoadedRepository::from_cache(&url, channel, &cache_dir) {
+            Ok(mut repo) => {
+                repo.meta.is_stale = true;
+                pending.extend(repo.info().repository.linked_repositories.iter().cloned());
+                map.insert(url, repo);
+            }
+            Err(e) => {
+                log::trace!("No usable cache for {}: {:?}", &url, e);
             }
-            Err(e) => return Err(e),
         }
     }
 
-    Ok(map)
+    (map, errors)
+}
+
+pub(crate) fn clear_cache(config: &Arc<RwLock<Config>>) -> CacheReport {
+    let config = config.read().unwrap();
+    let settings = config.settings();
+
+    let mut report = crate::cache::purge(&settings.repo_cache_dir());
+    report += crate::cache::purge(&settings.download_cache_dir());
+    report
+}
+
+/// Payload directories for the current release of every installed package.
+fn installed_payload_dirs(store: &dyn PackageStore) -> hashbrown::HashSet<std::path::PathBuf> {
+    use pahkat_types::payload::AsDownloadUrl;
+
+    let repos = store.repos();
+    let repos = repos.read().unwrap();
+
+    let urls = repos
+        .values()
+        .flat_map(|repo| {
+            let packages = repo.packages();
+            let ids = match packages.packages() {
+                Some(v) => v.keys().map(|x| x.to_string()).collect::<Vec<_>>(),
+                None => vec![],
+            };
+
+            ids.into_iter().map(move |id| {
+                PackageKey::new_unchecked(repo.info().repository.url.clone(), id, None)
+            })
+        })
+        .filter(|key| {
+            [InstallTarget::System, InstallTarget::User].iter().any(|target| {
+                match store.status(key, *target) {
+                    Ok(PackageStatus::UpToDate) | Ok(PackageStatus::RequiresUpdate) => true,
+                    _ => false,
+                }
+            })
+        })
+        .filter_map(|key| {
+            let query = ReleaseQuery::new(&key, &*repos);
+            resolve_payload(&key, &query, &*repos)
+                .ok()
+                .map(|(target, _, _)| target.payload.as_download_url().to_owned())
+        })
+        .collect::<Vec<_>>();
+
+    let config = store.config();
+    let config = config.read().unwrap();
+    urls.iter().map(|url| download_dir(&config, url)).collect()
 }
 
-pub(crate) fn clear_cache(config: &Arc<RwLock<Config>>) {
-    // todo!()
+pub(crate) fn prune_cache(store: &dyn PackageStore) -> CacheReport {
+    let (limit, package_cache_dir) = {
+        let config = store.config();
+        let config = config.read().unwrap();
+        let settings = config.settings();
+        (settings.cache_size_limit(), settings.package_cache_dir())
+    };
+
+    if limit == 0 {
+        return CacheReport::default();
+    }
+
+    let protected = installed_payload_dirs(store);
+    crate::cache::evict(&package_cache_dir, limit, &protected)
 }
 
 #[derive(Debug, Clone)]
@@ -726,6 +1189,9 @@ pub enum PackageCandidateError {
 
     #[error("Attempting to uninstall package required by installation set: `{0}`")]
     UninstallConflict(PackageKey),
+
+    #[error("Could not resolve dependency")]
+    FindPackage(#[source] FindPackageError),
 }
 
 use crate::package_store::InstallTarget;
@@ -748,6 +1214,21 @@ fn resolve_package_candidate(
         }
     }).unwrap_or_else(|| Err(PackageCandidateError::UnresolvedId(package_key.to_string())))?;
 
+    // A pinned version must be installed exactly, so an installed version
+    // newer than it needs replacing too.
+    let status = match package_key.query.version.as_ref() {
+        Some(version) if status == PackageStatus::UpToDate => {
+            let installed = install_target
+                .iter()
+                .find_map(|x| store.installed_version(package_key, *x));
+            match installed {
+                Some(installed) if &installed != version => PackageStatus::RequiresUpdate,
+                _ => status,
+            }
+        }
+        _ => status,
+    };
+
     let (target, release, descriptor) = resolve_payload(package_key, &query, &*repos)
         .map_err(|e| PackageCandidateError::Payload(package_key.to_owned(), e))?;
 
@@ -789,12 +1270,15 @@ fn recurse_package_set(
     package_candidate: &PackageCandidate,
     install_target: &[InstallTarget],
     repos: &HashMap<Url, LoadedRepository>,
-    set: &mut HashMap<PackageKey, PackageCandidate>
+    set: &mut HashMap<PackageKey, PackageCandidate>,
+    added: &mut Vec<PackageCandidate>,
 ) -> Result<(), PackageCandidateError> {
     package_candidate.target.dependencies.keys().try_fold((), |_, key| {
         let key = if !key.starts_with("https://") && !key.starts_with("http://") {
-            store.find_package_by_id(key).map(|x| x.0)
-                .ok_or_else(|| PackageCandidateError::UnresolvedId(key.to_string()))?
+            store.find_package_by_id(key).map(|x| x.0).map_err(|e| match e {
+                FindPackageError::NotFound(id) => PackageCandidateError::UnresolvedId(id),
+                e => PackageCandidateError::FindPackage(e),
+            })?
         } else {
             PackageKey::try_from(&**key).map_err(|_| PackageCandidateError::UnresolvedId(key.to_string()))?
         };
@@ -804,15 +1288,87 @@ fn recurse_package_set(
         }
 
         let candidate = resolve_package_candidate(store, &key, install_target, repos)?;
+        added.push(candidate.clone());
         set.insert(key, candidate);
         Ok(())
     })
 }
 
+/// The updates available for `targets`, as `PackageStore::updates`.
+pub(crate) fn updates<S: PackageStore + ?Sized>(
+    store: &S,
+    targets: &[InstallTarget],
+) -> Vec<PackageUpdate> {
+    let pinned = {
+        let config = store.config();
+        let config = config.read().unwrap();
+        config
+            .repos()
+            .iter()
+            .map(|(url, record)| (url.clone(), record.pinned.clone()))
+            .collect::<HashMap<_, _>>()
+    };
+
+    let mut urls = store.repos().read().unwrap().keys().cloned().collect::<Vec<_>>();
+    urls.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+
+    let mut updates = vec![];
+
+    for url in urls.iter() {
+        for target in targets.iter() {
+            for (id, status) in store.all_statuses(url, *target) {
+                match status {
+                    Ok(PackageStatus::RequiresUpdate) => {}
+                    _ => continue,
+                }
+
+                if pinned.get(url).map(|x| x.contains(&id)).unwrap_or(false) {
+                    log::debug!("Not updating pinned package {} from {}", &id, url);
+                    continue;
+                }
+
+                let key = PackageKey::new_unchecked(url.clone(), id, None);
+                let version = {
+                    let repos = store.repos();
+                    let repos = repos.read().unwrap();
+                    let query = ReleaseQuery::new(&key, &*repos);
+                    match resolve_payload(&key, &query, &*repos) {
+                        Ok((_, release, _)) => release.version.to_string(),
+                        Err(_) => continue,
+                    }
+                };
+
+                updates.push(PackageUpdate {
+                    installed_version: store.installed_version(&key, *target),
+                    key,
+                    target: *target,
+                    version,
+                });
+            }
+        }
+    }
+
+    updates
+}
+
 pub(crate) fn resolve_package_set(
     store: &dyn PackageStore,
     install_candidates: &[PackageKey],
     install_target: &[InstallTarget],
+) -> Result<Vec<PackageCandidate>, PackageCandidateError> {
+    // Take our candidate set and resolve it down to a mutation set
+    Ok(resolve_package_closure(store, install_candidates, install_target)?
+        .into_iter()
+        .filter(|candidate| candidate.status != PackageStatus::UpToDate)
+        .collect())
+}
+
+/// The packages and all of their dependencies, whether or not they are
+/// already installed.
+pub(crate) fn resolve_package_closure(
+    store: &dyn PackageStore,
+    install_candidates: &[PackageKey],
+    install_target: &[InstallTarget],
 ) -> Result<Vec<PackageCandidate>, PackageCandidateError> {
     let repos = store.repos();
     let repos = repos.read().unwrap();
@@ -822,22 +1378,130 @@ pub(crate) fn resolve_package_set(
         resolve_package_candidate(store, &key, install_target, &*repos).map(|v| (key.to_owned(), v))
     }).collect::<Result<HashMap<_, _>, _>>()?;
 
-    // Iterate all dependencies until we achieve victory
-    let values = candidate_set.values().cloned().collect::<Vec<_>>();
+    // Iterate all dependencies, and theirs, until we achieve victory
+    let mut pending = candidate_set.values().cloned().collect::<Vec<_>>();
 
-    values.iter().try_fold((), |_, candidate| {
-        recurse_package_set(store, candidate, install_target, &*repos, &mut candidate_set)
-    })?;
+    while let Some(candidate) = pending.pop() {
+        recurse_package_set(store, &candidate, install_target, &*repos, &mut candidate_set, &mut pending)?;
+    }
 
-    // Take our candidate set and resolve it down to a mutation set
-    Ok(candidate_set
-        .into_iter()
-        .filter_map(|(key, candidate)| {
-            if candidate.status == PackageStatus::UpToDate {
-                None
-            } else {
-                Some(candidate)
+    Ok(candidate_set.into_iter().map(|(_, candidate)| candidate).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package_store::InstallTarget;
+    use crate::testing::{descriptor, payload, MemoryPackageStore, RepositoryBuilder};
+
+    fn repo_url() -> Url {
+        Url::parse("https://pahkat.test/repo/").unwrap()
+    }
+
+    fn package(id: &str, version: &str, dependencies: &[&str]) -> Descriptor {
+        let url = repo_url().join(&format!("download/{}.bin", id)).unwrap();
+        let mut descriptor = descriptor(id, version, payload(url));
+        for dependency in dependencies {
+            descriptor.release[0].target[0]
+                .dependencies
+                .insert(dependency.to_string(), "*".to_string());
+        }
+        descriptor
+    }
+
+    fn key(id: &str) -> PackageKey {
+        PackageKey::new_unchecked(repo_url(), id.to_string(), None)
+    }
+
+    fn store() -> MemoryPackageStore {
+        let store = MemoryPackageStore::new();
+        store.add_repo(
+            RepositoryBuilder::new(repo_url())
+                .package(package("app", "1.0.0", &["lib"]))
+                .package(package("lib", "1.0.0", &["base"]))
+                .package(package("base", "1.0.0", &[]))
+                .build(),
+        );
+        store
+    }
+
+    fn ids(candidates: Vec<PackageCandidate>) -> Vec<String> {
+        let mut ids = candidates
+            .into_iter()
+            .map(|x| x.package_key.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn resolve_package_set_includes_transitive_dependencies() {
+        let store = store();
+        let set = resolve_package_set(&store, &[key("app")], &[InstallTarget::System]).unwrap();
+        assert_eq!(ids(set), vec!["app", "base", "lib"]);
+    }
+
+    #[test]
+    fn resolve_package_set_skips_up_to_date_dependencies() {
+        let store = store();
+        store.set_installed(&key("base"), InstallTarget::System, "1.0.0");
+
+        let set = resolve_package_set(&store, &[key("app")], &[InstallTarget::System]).unwrap();
+        assert_eq!(ids(set), vec!["app", "lib"]);
+
+        let closure =
+            resolve_package_closure(&store, &[key("app")], &[InstallTarget::System]).unwrap();
+        assert_eq!(ids(closure), vec!["app", "base", "lib"]);
+    }
+
+    #[test]
+    fn resolve_package_set_reports_unknown_dependencies() {
+        let store = MemoryPackageStore::new();
+        store.add_repo(
+            RepositoryBuilder::new(repo_url())
+                .package(package("app", "1.0.0", &["missing"]))
+                .build(),
+        );
+
+        match resolve_package_set(&store, &[key("app")], &[InstallTarget::System]) {
+            Err(PackageCandidateError::UnresolvedId(id)) => assert_eq!(id, "missing"),
+            other => panic!("unexpected result: {:?}", other.map(ids)),
+        }
+    }
+
+    #[test]
+    fn undeclared_channels_are_rejected() {
+        let store = MemoryPackageStore::new();
+        store.add_repo(
+            RepositoryBuilder::new(repo_url())
+                .channels(vec!["beta", "nightly"])
+                .package(package("app", "1.0.0", &[]))
+                .build(),
+        );
+
+        let repos = store.repos();
+        let repos = repos.read().unwrap();
+
+        let key = PackageKey::try_from("https://pahkat.test/repo/packages/app?channel=alpha")
+            .unwrap();
+        let query = ReleaseQuery::new(&key, &*repos);
+        match resolve_payload(&key, &query, &*repos) {
+            Err(PayloadError::UnknownChannel(e)) => assert_eq!(e.0, "alpha"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let key = PackageKey::try_from("https://pahkat.test/repo/packages/app?channel=beta")
+            .unwrap();
+        let query = ReleaseQuery::new(&key, &*repos);
+        assert!(resolve_payload(&key, &query, &*repos).is_ok());
+
+        match find_package_by_id(&store, "app#alpha", &*repos) {
+            Err(FindPackageError::UnknownChannel(id, e)) => {
+                assert_eq!(id, "app#alpha");
+                assert_eq!(e.0, "alpha");
             }
-        })
-        .collect())
+            other => panic!("unexpected result: {:?}", other.map(|x| x.0)),
+        }
+        assert!(find_package_by_id(&store, "app#nightly", &*repos).is_ok());
+    }
 }
@@ -1,10 +1,18 @@
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
+use reqwest::header::{self, HeaderMap};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
 use pahkat_types::PackageKey;
+use crate::config::{Credential, CredentialError, NetworkSettings};
+use crate::ext::PathExt;
+use crate::http::{HttpClient, HttpClientError};
 use crate::pahkat_fbs;
+use super::search::SearchIndex;
 
 #[derive(Debug, thiserror::Error)]
 pub enum RepoDownloadError {
@@ -16,93 +24,561 @@ pub enum RepoDownloadError {
 
     #[error("I/O error")]
     IoError(#[from] std::io::Error),
+
+    #[error("Invalid package index")]
+    InvalidPackageIndex,
+
+    #[error("Authentication failed")]
+    Credentials(#[from] CredentialError),
+
+    #[error("Could not create HTTP client")]
+    HttpClient(#[from] HttpClientError),
+
+    #[error("Not a valid local path: {0}")]
+    InvalidFileUrl(String),
+
+    #[error("Loading the repository stopped unexpectedly: {0}")]
+    Aborted(String),
+}
+
+const INDEX_FILE: &str = "index.toml";
+const PACKAGES_FILE: &str = "index.bin";
+const META_FILE: &str = "meta.toml";
+
+/// HTTP validators used to make conditional requests for a cached file.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct CacheValidators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl CacheValidators {
+    fn from_headers(headers: &HeaderMap) -> CacheValidators {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string())
+        };
+
+        CacheValidators {
+            etag: get(header::ETAG),
+            last_modified: get(header::LAST_MODIFIED),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct LoadedRepositoryMeta {
     pub channel: Option<String>,
     // pub hash_id: String,
-    // TODO: last update
+    /// The last time the repository was successfully checked against its remote.
+    #[serde(default)]
+    pub last_update: Option<DateTime<Utc>>,
+    /// Set when the latest refresh failed and this copy was loaded from the cache.
+    #[serde(skip)]
+    pub is_stale: bool,
+
+    // Tables have to come last in TOML
+    #[serde(default)]
+    pub index: CacheValidators,
+    #[serde(default)]
+    pub packages: CacheValidators,
 }
 
 #[derive(Debug, Clone)]
 pub struct LoadedRepository {
     pub info: pahkat_types::repo::Index,
-    pub packages: Box<[u8]>,
+    /// Checked to be a valid package index by `new`, and never changed after.
+    packages: Box<[u8]>,
     pub meta: LoadedRepositoryMeta,
+    search_index: Arc<SearchIndex>,
+}
+
+/// A freshly downloaded file, or `None` if the server reported it unchanged.
+type Fetched = Option<(Vec<u8>, CacheValidators)>;
+
+/// Reads a file from a repository on disk or on a network share. These are
+/// cheap to read, so the file is always treated as changed.
+fn read_local(url: &str) -> Result<Fetched, RepoDownloadError> {
+    let path = Url::parse(url)
+        .ok()
+        .and_then(|x| x.to_file_path().ok())
+        .ok_or_else(|| RepoDownloadError::InvalidFileUrl(url.to_string()))?;
+
+    let bytes = std::fs::read(&path)?;
+    Ok(Some((bytes, CacheValidators::default())))
+}
+
+/// A local copy of a repository still carries the URL it is published at.
+/// Package keys must point at where it was loaded from instead, or they would
+/// not resolve to this repository.
+fn use_local_url(info: &mut pahkat_types::repo::Index, url: &Url) {
+    if url.scheme() == "file" {
+        info.repository.url = url.clone();
+    }
+}
+
+async fn fetch(
+    client: &HttpClient,
+    url: &str,
+    validators: Option<&CacheValidators>,
+    credential: Option<&Credential>,
+) -> Result<Fetched, RepoDownloadError> {
+    if url.starts_with("file:") {
+        return read_local(url);
+    }
+
+    let mut request = client.get(url);
+
+    if let Some(credential) = credential {
+        request = credential.apply(request);
+    }
+
+    if let Some(validators) = validators {
+        if let Some(etag) = validators.etag.as_ref() {
+            request = request.header(header::IF_NONE_MATCH, etag.as_str());
+        }
+        if let Some(last_modified) = validators.last_modified.as_ref() {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified.as_str());
+        }
+    }
+
+    let response = request.send().await?;
+
+    if validators.is_some() && response.status() == StatusCode::NOT_MODIFIED {
+        log::trace!("Not modified: {}", url);
+        return Ok(None);
+    }
+
+    if response.status() == StatusCode::UNAUTHORIZED || response.status() == StatusCode::FORBIDDEN {
+        let url = response.url().clone();
+        return Err(match credential {
+            Some(_) => CredentialError::Rejected(url),
+            None => CredentialError::Required(url),
+        }
+        .into());
+    }
+
+    let response = response.error_for_status()?;
+    let validators = CacheValidators::from_headers(response.headers());
+    let bytes = response.bytes().await?.to_vec();
+
+    Ok(Some((bytes, validators)))
 }
 
 impl LoadedRepository {
+    /// Fails with `RepoDownloadError::InvalidPackageIndex` if `packages` is
+    /// not a valid package index.
+    pub fn new(
+        info: pahkat_types::repo::Index,
+        packages: Box<[u8]>,
+        meta: LoadedRepositoryMeta,
+    ) -> Result<LoadedRepository, RepoDownloadError> {
+        let root = pahkat_fbs::Packages::get_root(&*packages)
+            .map_err(|_| RepoDownloadError::InvalidPackageIndex)?;
+        let search_index = Arc::new(SearchIndex::new(&root));
+
+        Ok(LoadedRepository {
+            info,
+            packages,
+            meta,
+            search_index,
+        })
+    }
+
+    /// The directory the given repository is cached in.
+    pub(crate) fn cache_path(url: &Url, cache_dir: &Path) -> PathBuf {
+        cache_dir.join_sha256(url.as_str().as_bytes())
+    }
+
+    /// Refreshes the repository from `url`, only downloading files that have
+    /// changed since they were cached. If the remote cannot be reached, the
+    /// cached copy is returned instead.
     pub async fn from_cache_or_url(
         url: Url,
         channel: Option<String>,
         cache_dir: PathBuf,
+        credential: Option<Credential>,
+        network: &NetworkSettings,
     ) -> Result<LoadedRepository, RepoDownloadError> {
-        Self::from_url(url, channel).await
+        let client = HttpClient::new(network)?;
+
+        match Self::refresh(client, url, channel, cache_dir, Ok(credential), true).await {
+            (Some(repo), _) => Ok(repo),
+            (None, Some(e)) => Err(e),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    /// Like `from_cache_or_url`, but also returns the error that caused a
+    /// fallback to the cache. A repository loaded from the cache after a
+    /// failed refresh is marked as stale.
+    ///
+    /// A credential that could not be looked up is treated like any other
+    /// failed refresh. Unless `conditional`, the files are downloaded even if
+    /// the cached copies are current.
+    pub(crate) async fn refresh(
+        client: HttpClient,
+        url: Url,
+        channel: Option<String>,
+        cache_dir: PathBuf,
+        credential: Result<Option<Credential>, CredentialError>,
+        conditional: bool,
+    ) -> (Option<LoadedRepository>, Option<RepoDownloadError>) {
+        let cache_path = Self::cache_path(&url, &cache_dir);
+
+        let cached = match Self::from_cache_path(&url, &cache_path, channel.clone()) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::trace!("No usable cache for {}: {:?}", &url, e);
+                None
+            }
+        };
+
+        let result = match credential {
+            Ok(credential) => {
+                let validated = cached.clone().filter(|_| conditional);
+                Self::from_url(client, url.clone(), channel, validated, cache_path, credential)
+                    .await
+            }
+            Err(e) => Err(e.into()),
+        };
+
+        match result {
+            Ok(v) => (Some(v), None),
+            Err(e) => match cached {
+                Some(mut v) => {
+                    log::warn!("Could not refresh {}, using cached copy: {:?}", &url, e);
+                    v.meta.is_stale = true;
+                    (Some(v), Some(e))
+                }
+                None => (None, Some(e)),
+            },
+        }
+    }
+
+    /// Loads the repository from the on-disk cache only.
+    pub fn from_cache(
+        url: &Url,
+        channel: Option<String>,
+        cache_dir: &Path,
+    ) -> Result<LoadedRepository, RepoDownloadError> {
+        Self::from_cache_path(url, &Self::cache_path(url, cache_dir), channel)
+    }
+
+    fn from_cache_path(
+        url: &Url,
+        cache_path: &Path,
+        channel: Option<String>,
+    ) -> Result<LoadedRepository, RepoDownloadError> {
+        let info = std::fs::read(cache_path.join(INDEX_FILE))?;
+        let mut info: pahkat_types::repo::Index = toml::from_slice(&info)?;
+        use_local_url(&mut info, url);
+
+        let packages = std::fs::read(cache_path.join(PACKAGES_FILE))?.into_boxed_slice();
+
+        let meta = std::fs::read(cache_path.join(META_FILE))?;
+        let mut meta: LoadedRepositoryMeta = toml::from_slice(&meta)?;
+
+        // The configured channel may have changed since this was cached.
+        meta.channel = channel;
+
+        LoadedRepository::new(info, packages, meta)
     }
 
     async fn from_url(
+        client: HttpClient,
         url: Url,
         channel: Option<String>,
+        cached: Option<LoadedRepository>,
+        cache_path: PathBuf,
+        credential: Option<Credential>,
     ) -> Result<LoadedRepository, RepoDownloadError> {
-        let (tx, rx) = tokio::sync::oneshot::channel();
-
-        tokio::spawn(async move {
-            let result = async move {
-                let client = reqwest::Client::new();
-
-                log::trace!("Loading repo: {} channel:{:?}", &url, &channel);
-
-                let info = client
-                    .get(&format!("{}/index.toml", url))
-                    .send()
-                    .await?
-                    .text()
-                    .await?;
-                let info: pahkat_types::repo::Index = toml::from_str(&info)?;
-
-                let packages = client
-                    .get(&format!("{}/packages/index.bin", url))
-                    .send()
-                    .await?
-                    .bytes()
-                    .await?
-                    .to_vec()
-                    .into_boxed_slice();
-
-                let repo = LoadedRepository {
-                    info,
-                    packages,
-                    meta: LoadedRepositoryMeta {
-                        channel,
-                        // hash_id: "".into(),
-                    },
-                };
-
-                log::trace!("Loaded.");
-                Ok(repo)
+        let result = tokio::spawn(async move {
+            log::trace!("Loading repo: {} channel:{:?}", &url, &channel);
+
+            let index = fetch(
+                &client,
+                &format!("{}/index.toml", url),
+                cached.as_ref().map(|x| &x.meta.index),
+                credential.as_ref(),
+            )
+            .await?;
+
+            let packages = fetch(
+                &client,
+                &format!("{}/packages/index.bin", url),
+                cached.as_ref().map(|x| &x.meta.packages),
+                credential.as_ref(),
+            )
+            .await?;
+
+            let mut meta = LoadedRepositoryMeta {
+                channel,
+                last_update: Some(Utc::now()),
+                ..Default::default()
+            };
+
+            // `fetch` only reports a file as unchanged when a cached copy was given.
+            let mut info: pahkat_types::repo::Index = match index.as_ref() {
+                Some((bytes, validators)) => {
+                    meta.index = validators.clone();
+                    toml::from_slice(bytes)?
+                }
+                None => {
+                    let cached = cached.as_ref().unwrap();
+                    meta.index = cached.meta.index.clone();
+                    cached.info.clone()
+                }
+            };
+            use_local_url(&mut info, &url);
+
+            let packages = match packages {
+                Some((bytes, validators)) => {
+                    pahkat_fbs::Packages::get_root(&*bytes)
+                        .map_err(|_| RepoDownloadError::InvalidPackageIndex)?;
+                    meta.packages = validators;
+                    Some(bytes.into_boxed_slice())
+                }
+                None => {
+                    meta.packages = cached.as_ref().unwrap().meta.packages.clone();
+                    None
+                }
+            };
+
+            if let Err(e) = Self::save_cache(
+                &cache_path,
+                index.as_ref().map(|x| &*x.0),
+                packages.as_ref().map(|x| &**x),
+                &meta,
+            ) {
+                log::warn!("Could not write repository cache {:?}: {:?}", &cache_path, e);
             }
-            .await;
 
-            tx.send(result).unwrap();
-        });
+            let packages = match packages {
+                Some(v) => v,
+                None => cached.unwrap().packages,
+            };
+
+            let repo = LoadedRepository::new(info, packages, meta)?;
 
-        rx.await.unwrap()
+            log::trace!("Loaded.");
+            Ok(repo)
+        })
+        .await;
+
+        match result {
+            Ok(v) => v,
+            Err(e) => Err(RepoDownloadError::Aborted(e.to_string())),
+        }
+    }
+
+    fn save_cache(
+        cache_path: &Path,
+        index: Option<&[u8]>,
+        packages: Option<&[u8]>,
+        meta: &LoadedRepositoryMeta,
+    ) -> Result<(), RepoDownloadError> {
+        std::fs::create_dir_all(cache_path)?;
+
+        // Without its meta the cache is not used, so the files are never
+        // mixed with the validators of another version if this is interrupted.
+        let meta_path = cache_path.join(META_FILE);
+        match std::fs::remove_file(&meta_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        if let Some(index) = index {
+            cache_path.join(INDEX_FILE).write_atomic(index)?;
+        }
+
+        if let Some(packages) = packages {
+            cache_path.join(PACKAGES_FILE).write_atomic(packages)?;
+        }
+
+        let meta = toml::to_vec(meta).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+        })?;
+        meta_path.write_atomic(&meta)?;
+
+        Ok(())
     }
 
     pub fn info(&self) -> &pahkat_types::repo::Index {
         &self.info
     }
 
+    /// The package index. It cannot fail to load here, as `new` rejects an
+    /// invalid index and the bytes are private, so they cannot change after.
     pub fn packages<'a>(&'a self) -> pahkat_fbs::Packages<&'a [u8]> {
-        pahkat_fbs::Packages::get_root(&*self.packages).expect("packages must always exist")
+        pahkat_fbs::Packages::get_root(&*self.packages).expect("validated by LoadedRepository::new")
+    }
+
+    /// The package index as published, such as to send it elsewhere.
+    pub fn packages_bytes(&self) -> &[u8] {
+        &self.packages
     }
 
     pub fn meta(&self) -> &LoadedRepositoryMeta {
         &self.meta
     }
 
+    pub(crate) fn search_index(&self) -> &SearchIndex {
+        &self.search_index
+    }
+
     pub fn package_key(&self, descriptor: &pahkat_types::package::Descriptor) -> PackageKey {
         PackageKey::new_unchecked(self.info.repository.url.to_owned(), descriptor.package.id.clone(), None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pahkat_types::payload::AsDownloadUrl;
+
+    use super::*;
+    use crate::testing::{descriptor, payload, FixtureServer, RepositoryBuilder};
+
+    fn client() -> HttpClient {
+        HttpClient::new(&NetworkSettings::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn refreshes_from_a_published_repository() {
+        let root = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let server = FixtureServer::serve(root.path()).unwrap();
+
+        let payload_url = server.url().join("app.bin").unwrap();
+        RepositoryBuilder::new(server.url())
+            .package(descriptor("app", "1.2.3", payload(payload_url.clone())))
+            .write(root.path())
+            .unwrap();
+
+        let (repo, error) = LoadedRepository::refresh(
+            client(),
+            server.url(),
+            None,
+            cache_dir.path().to_path_buf(),
+            Ok(None),
+            true,
+        )
+        .await;
+        assert!(error.is_none(), "{:?}", error);
+        let repo = repo.unwrap();
+
+        let packages = repo.packages();
+        let pkg = packages.packages().unwrap().get("app").unwrap();
+        let decoded = crate::fbs::descriptor(&pkg, Some(&server.url())).unwrap();
+        assert_eq!(decoded.package.id, "app");
+        assert_eq!(decoded.release[0].version.to_string(), "1.2.3");
+        assert_eq!(
+            decoded.release[0].target[0].payload.as_download_url(),
+            &payload_url
+        );
+
+        // The cached copy is current, so nothing is downloaded again.
+        let (repo, error) = LoadedRepository::refresh(
+            client(),
+            server.url(),
+            None,
+            cache_dir.path().to_path_buf(),
+            Ok(None),
+            true,
+        )
+        .await;
+        assert!(error.is_none(), "{:?}", error);
+        assert!(!repo.unwrap().meta.is_stale);
+
+        let not_modified = server
+            .requests()
+            .iter()
+            .filter(|x| x.ends_with(" 304"))
+            .count();
+        assert_eq!(not_modified, 2);
+
+        // An unconditional refresh downloads everything again.
+        let requests = server.requests().len();
+        let (repo, _) = LoadedRepository::refresh(
+            client(),
+            server.url(),
+            None,
+            cache_dir.path().to_path_buf(),
+            Ok(None),
+            false,
+        )
+        .await;
+        assert!(repo.is_some());
+        assert!(server.requests()[requests..].iter().all(|x| x.ends_with(" 200")));
+    }
+
+    #[test]
+    fn cache_without_meta_is_not_used() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let url = Url::parse("https://pahkat.test/repo/").unwrap();
+        let builder = RepositoryBuilder::new(url.clone());
+        let cache_path = LoadedRepository::cache_path(&url, cache_dir.path());
+
+        let index = toml::to_vec(&builder.index()).unwrap();
+        let packages = crate::testing::build_index(&[]);
+        LoadedRepository::save_cache(
+            &cache_path,
+            Some(&index),
+            Some(&packages),
+            &LoadedRepositoryMeta::default(),
+        )
+        .unwrap();
+
+        let mut files = std::fs::read_dir(&cache_path)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec![PACKAGES_FILE, INDEX_FILE, META_FILE]);
+        assert!(LoadedRepository::from_cache(&url, None, cache_dir.path()).is_ok());
+
+        // As left behind by a write that was interrupted before the meta.
+        std::fs::remove_file(cache_path.join(META_FILE)).unwrap();
+        assert!(LoadedRepository::from_cache(&url, None, cache_dir.path()).is_err());
+    }
+
+    #[test]
+    fn corrupt_package_index_is_an_error() {
+        let url = Url::parse("https://pahkat.test/repo/").unwrap();
+        let info = RepositoryBuilder::new(url).index();
+
+        match LoadedRepository::new(info, vec![1, 2, 3].into_boxed_slice(), Default::default()) {
+            Err(RepoDownloadError::InvalidPackageIndex) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_cache_when_unreachable() {
+        let root = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let server = FixtureServer::serve(root.path()).unwrap();
+        let url = server.url();
+
+        RepositoryBuilder::new(url.clone())
+            .package(descriptor("app", "1.0.0", payload(url.join("app.bin").unwrap())))
+            .write(root.path())
+            .unwrap();
+
+        let cache = cache_dir.path().to_path_buf();
+        let (repo, _) =
+            LoadedRepository::refresh(client(), url.clone(), None, cache.clone(), Ok(None), true)
+                .await;
+        assert!(repo.is_some());
+        drop(server);
+
+        let (repo, error) =
+            LoadedRepository::refresh(client(), url, None, cache, Ok(None), true).await;
+        assert!(error.is_some());
+        assert!(repo.unwrap().meta.is_stale);
+    }
+}
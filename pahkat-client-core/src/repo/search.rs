@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::fbs::{DescriptorExt, PackagesExt};
+use crate::pahkat_fbs;
+
+/// Options narrowing the results of a package search.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchOptions {
+    /// Only return packages carrying at least one of these tags.
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+
+    /// Maximum number of results to return.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Field {
+    Id,
+    Name,
+    Tag,
+    Description,
+}
+
+impl Field {
+    #[inline(always)]
+    fn weight(self) -> u32 {
+        match self {
+            Field::Id => 8,
+            Field::Name => 4,
+            Field::Tag => 2,
+            Field::Description => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    id: String,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Posting {
+    entry: usize,
+    field: Field,
+}
+
+/// An inverted index over the ids, localised names, descriptions and tags
+/// of every package in a repository.
+///
+/// Built once when a repository is loaded so that searching does not need
+/// to walk the flatbuffer for every query.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SearchIndex {
+    entries: Vec<Entry>,
+    tokens: BTreeMap<String, Vec<Posting>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SearchHit<'a> {
+    pub id: &'a str,
+    pub score: u32,
+}
+
+fn tokenize(input: &str) -> impl Iterator<Item = String> + '_ {
+    input
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_lowercase())
+}
+
+impl SearchIndex {
+    pub(crate) fn new(packages: &pahkat_fbs::Packages<&[u8]>) -> SearchIndex {
+        let mut index = SearchIndex::default();
+
+        let packages = match packages.packages() {
+            Some(v) => v,
+            None => return index,
+        };
+
+        for (id, pkg) in packages.iter() {
+            let entry = index.entries.len();
+
+            let tags = pkg
+                .tags()
+                .ok()
+                .flatten()
+                .map(|tags| {
+                    tags.iter()
+                        .filter_map(Result::ok)
+                        .map(|x| x.to_string())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_else(|| vec![]);
+
+            index.insert(entry, Field::Id, id);
+
+            if let Some(name) = pkg.name() {
+                for (_, value) in name.iter() {
+                    index.insert(entry, Field::Name, value);
+                }
+            }
+
+            if let Some(description) = pkg.description() {
+                for (_, value) in description.iter() {
+                    index.insert(entry, Field::Description, value);
+                }
+            }
+
+            for tag in tags.iter() {
+                index.insert(entry, Field::Tag, tag);
+            }
+
+            index.entries.push(Entry {
+                id: id.to_string(),
+                tags,
+            });
+        }
+
+        index
+    }
+
+    fn insert(&mut self, entry: usize, field: Field, text: &str) {
+        for token in tokenize(text) {
+            let postings = self.tokens.entry(token).or_insert_with(|| vec![]);
+            if !postings
+                .iter()
+                .any(|x| x.entry == entry && x.field == field)
+            {
+                postings.push(Posting { entry, field });
+            }
+        }
+    }
+
+    /// Best score per entry for a single query term. Exact token matches are
+    /// worth twice as much as prefix matches.
+    fn score_term(&self, term: &str) -> HashMap<usize, u32> {
+        let mut scores = HashMap::new();
+
+        let matches = self
+            .tokens
+            .range(term.to_string()..)
+            .take_while(|(token, _)| token.starts_with(term));
+
+        for (token, postings) in matches {
+            let multiplier = if token == term { 2 } else { 1 };
+
+            for posting in postings.iter() {
+                let score = posting.field.weight() * multiplier;
+                let current = scores.entry(posting.entry).or_insert(0);
+                if score > *current {
+                    *current = score;
+                }
+            }
+        }
+
+        scores
+    }
+
+    /// Returns every package matching all terms of `query`, with the highest
+    /// scoring packages first.
+    pub(crate) fn search(&self, query: &str, options: &SearchOptions) -> Vec<SearchHit<'_>> {
+        let terms = tokenize(query).collect::<Vec<_>>();
+
+        if terms.is_empty() {
+            return vec![];
+        }
+
+        let mut scores: Option<HashMap<usize, u32>> = None;
+
+        for term in terms.iter() {
+            let term_scores = self.score_term(term);
+
+            scores = Some(match scores {
+                None => term_scores,
+                Some(acc) => acc
+                    .into_iter()
+                    .filter_map(|(entry, score)| {
+                        term_scores.get(&entry).map(|x| (entry, score + x))
+                    })
+                    .collect(),
+            });
+        }
+
+        let query_id = query.trim().to_lowercase();
+
+        let mut hits = scores
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(entry, score)| (&self.entries[entry], score))
+            .filter(|(entry, _)| match options.tags.as_ref() {
+                Some(tags) => entry.tags.iter().any(|x| tags.contains(x)),
+                None => true,
+            })
+            .map(|(entry, score)| {
+                // An exact id match should always be the first result.
+                let bonus = if entry.id.to_lowercase() == query_id {
+                    100
+                } else {
+                    0
+                };
+
+                SearchHit {
+                    id: &entry.id,
+                    score: score + bonus,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        hits.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.id.cmp(b.id)));
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pahkat_types::package::{Descriptor, DescriptorData};
+
+    use super::*;
+
+    fn descriptor(id: &str, name: &str, description: &str, tags: &[&str]) -> Descriptor {
+        let mut names = BTreeMap::new();
+        names.insert("en".to_string(), name.to_string());
+        let mut descriptions = BTreeMap::new();
+        descriptions.insert("en".to_string(), description.to_string());
+
+        Descriptor::builder()
+            .package(
+                DescriptorData::builder()
+                    .id(id.to_string())
+                    .tags(tags.iter().map(|x| x.to_string()).collect())
+                    .build(),
+            )
+            .name(names)
+            .description(descriptions)
+            .build()
+    }
+
+    fn index() -> SearchIndex {
+        let bytes = crate::fbs::builder::build_index(&[
+            descriptor("speller-sme", "North Sámi Speller", "Spell checking for Microsoft Word", &["speller"]),
+            descriptor("keyboard-sme", "North Sámi Keyboard", "Keyboard layouts", &["keyboard"]),
+            descriptor("divvun-manager", "Divvun Manager", "Installs spellers and keyboards", &[]),
+        ]);
+        SearchIndex::new(&pahkat_fbs::Packages::get_root(&*bytes).unwrap())
+    }
+
+    fn ids(hits: Vec<SearchHit<'_>>) -> Vec<&str> {
+        hits.into_iter().map(|x| x.id).collect()
+    }
+
+    #[test]
+    fn tokenizes_on_non_alphanumerics_and_lowercases() {
+        assert_eq!(
+            tokenize("North Sámi, speller-SME!").collect::<Vec<_>>(),
+            vec!["north", "sámi", "speller", "sme"]
+        );
+        assert_eq!(tokenize(" -- ").count(), 0);
+    }
+
+    #[test]
+    fn scores_by_field_and_exact_matches() {
+        let index = index();
+        let options = SearchOptions::default();
+
+        // An exact id match always comes first.
+        let hits = index.search("speller-sme", &options);
+        assert_eq!(hits[0].id, "speller-sme");
+        assert!(hits[0].score >= 100);
+
+        // Id tokens outweigh names, and names outweigh descriptions.
+        assert_eq!(ids(index.search("keyboard", &options)), vec!["keyboard-sme", "divvun-manager"]);
+
+        // Every term must match.
+        assert_eq!(ids(index.search("sámi keyboard", &options)), vec!["keyboard-sme"]);
+        assert!(index.search("sámi manager", &options).is_empty());
+        assert!(index.search("", &options).is_empty());
+    }
+
+    #[test]
+    fn matches_prefixes_below_exact_tokens() {
+        let index = index();
+        let options = SearchOptions::default();
+
+        assert_eq!(ids(index.search("spell", &options)), vec!["speller-sme", "divvun-manager"]);
+
+        let entry = index.entries.iter().position(|x| x.id == "speller-sme").unwrap();
+        let exact = index.score_term("speller");
+        let prefix = index.score_term("spell");
+        assert_eq!(exact[&entry], prefix[&entry] * 2);
+    }
+
+    #[test]
+    fn filters_by_tag() {
+        let index = index();
+        let options = SearchOptions {
+            tags: Some(vec!["keyboard".into()]),
+            ..Default::default()
+        };
+
+        assert_eq!(ids(index.search("sámi", &options)), vec!["keyboard-sme"]);
+    }
+}
@@ -0,0 +1,12 @@
+//! Fixtures for testing code built on this crate without a real package store
+//! or network access. Only available with the `test-support` feature, and
+//! to this crate's own tests.
+
+mod repository;
+mod server;
+mod store;
+
+pub use crate::fbs::builder::build_index;
+pub use self::repository::{descriptor, payload, RepositoryBuilder};
+pub use self::server::FixtureServer;
+pub use self::store::MemoryPackageStore;
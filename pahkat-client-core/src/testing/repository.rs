@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use pahkat_types::package::{Descriptor, DescriptorData, Package, Release, Version};
+use pahkat_types::payload::{Payload, Target};
+use pahkat_types::repo::{Agent, Index, RepositoryData};
+use url::Url;
+
+use crate::defaults;
+use crate::repo::{LoadedRepository, LoadedRepositoryMeta};
+
+/// A descriptor with a single release of `version`, whose only target is
+/// `payload` on the current platform.
+///
+/// Panics if `version` is not a valid version.
+pub fn descriptor(id: &str, version: &str, payload: Payload) -> Descriptor {
+    let target = Target::builder()
+        .platform(defaults::platform().to_string())
+        .payload(payload)
+        .build();
+
+    let release = Release::builder()
+        .version(Version::new(version).expect("valid version"))
+        .target(vec![target])
+        .build();
+
+    Descriptor::builder()
+        .package(DescriptorData::builder().id(id.to_string()).build())
+        .release(vec![release])
+        .build()
+}
+
+/// A payload of the type the enabled package store installs, downloaded
+/// from `url`. With more than one store enabled, the prefix store is picked
+/// first and the Windows store second.
+pub fn payload(url: Url) -> Payload {
+    #[cfg(not(any(feature = "windows", feature = "macos", feature = "prefix")))]
+    compile_error!("test-support requires one of the windows, macos or prefix features");
+
+    #[cfg(all(feature = "windows", not(feature = "prefix")))]
+    {
+        Payload::WindowsExecutable(
+            pahkat_types::payload::windows::Executable::builder()
+                .url(url)
+                .product_code("{00000000-0000-0000-0000-000000000000}".into())
+                .size(0)
+                .installed_size(0)
+                .build(),
+        )
+    }
+    #[cfg(all(feature = "macos", not(feature = "windows"), not(feature = "prefix")))]
+    {
+        Payload::MacOSPackage(
+            pahkat_types::payload::macos::Package::builder()
+                .url(url)
+                .pkg_id("test.pahkat.package".into())
+                .size(0)
+                .installed_size(0)
+                .build(),
+        )
+    }
+    #[cfg(feature = "prefix")]
+    {
+        Payload::TarballPackage(
+            pahkat_types::payload::tarball::Package::builder()
+                .url(url)
+                .size(0)
+                .installed_size(0)
+                .build(),
+        )
+    }
+}
+
+/// Builds a repository from descriptors, either in memory or as the files
+/// `pahkat-repomgr` would publish for it.
+#[derive(Debug, Clone)]
+pub struct RepositoryBuilder {
+    url: Url,
+    channel: Option<String>,
+    channels: Option<Vec<String>>,
+    packages: Vec<Descriptor>,
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl RepositoryBuilder {
+    pub fn new(url: Url) -> RepositoryBuilder {
+        RepositoryBuilder {
+            url,
+            channel: None,
+            channels: None,
+            packages: vec![],
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// The channel the repository is loaded with.
+    pub fn channel<S: Into<String>>(mut self, channel: S) -> Self {
+        self.channel = Some(channel.into());
+        self
+    }
+
+    /// The channels the repository declares, from the most to the least
+    /// stable. Defaults to the channels of its releases in name order.
+    pub fn channels<S: Into<String>>(mut self, channels: Vec<S>) -> Self {
+        self.channels = Some(channels.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn package(mut self, descriptor: Descriptor) -> Self {
+        self.packages.push(descriptor);
+        self
+    }
+
+    /// An extra file such as a payload, written relative to the repository
+    /// root by `write`.
+    pub fn file<S: Into<String>, B: Into<Vec<u8>>>(mut self, path: S, bytes: B) -> Self {
+        self.files.insert(path.into(), bytes.into());
+        self
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// The URL `path` is served at once the repository is published at `url`.
+    pub fn file_url(&self, path: &str) -> Url {
+        let base = self.url.as_str().trim_end_matches('/');
+        Url::parse(&format!("{}/{}", base, path.trim_start_matches('/'))).expect("valid url")
+    }
+
+    pub fn index(&self) -> Index {
+        let channels = self.channels.clone().unwrap_or_else(|| {
+            let mut channels = self
+                .packages
+                .iter()
+                .flat_map(|x| x.release.iter())
+                .filter_map(|x| x.channel.clone())
+                .collect::<Vec<_>>();
+            channels.sort();
+            channels.dedup();
+            channels
+        });
+
+        let data = RepositoryData::builder()
+            .url(self.url.clone())
+            .channels(channels)
+            .build();
+
+        let agent = Agent::builder()
+            .name("pahkat".to_string())
+            .version(env!("CARGO_PKG_VERSION").into())
+            .build();
+
+        Index::builder().repository(data).agent(agent).build()
+    }
+
+    pub fn build(&self) -> LoadedRepository {
+        let meta = LoadedRepositoryMeta {
+            channel: self.channel.clone(),
+            ..Default::default()
+        };
+
+        LoadedRepository::new(
+            self.index(),
+            crate::testing::build_index(&self.packages).into_boxed_slice(),
+            meta,
+        )
+        .expect("built index is valid")
+    }
+
+    /// Writes the repository to `path` in the layout used by `pahkat-repomgr`,
+    /// along with any extra files.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let packages_path = path.join("packages");
+        std::fs::create_dir_all(&packages_path)?;
+        std::fs::create_dir_all(path.join("strings"))?;
+
+        std::fs::write(path.join("index.toml"), to_toml(&self.index())?)?;
+
+        for descriptor in self.packages.iter() {
+            let package_path = packages_path.join(&descriptor.package.id);
+            std::fs::create_dir_all(&package_path)?;
+            let package = Package::Concrete(descriptor.clone());
+            std::fs::write(package_path.join("index.toml"), to_toml(&package)?)?;
+        }
+
+        std::fs::write(packages_path.join("index.bin"), crate::testing::build_index(&self.packages))?;
+
+        for (file_path, bytes) in self.files.iter() {
+            let file_path = path.join(file_path.trim_start_matches('/'));
+            if let Some(parent) = file_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(file_path, bytes)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn to_toml<T: serde::Serialize>(value: &T) -> io::Result<String> {
+    toml::to_string(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
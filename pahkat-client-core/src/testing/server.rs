@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::UNIX_EPOCH;
+
+use url::Url;
+
+/// A minimal HTTP server on localhost serving the files below a directory,
+/// such as a repository written by `RepositoryBuilder::write`.
+///
+/// Only `GET` and `HEAD` are supported. Every file has a strong `ETag`, and
+/// `If-None-Match`, `Range` and `If-Range` are honoured, which is all that
+/// repository refreshes and resumed downloads rely on. The server stops when
+/// dropped.
+pub struct FixtureServer {
+    addr: SocketAddr,
+    root: PathBuf,
+    requests: Arc<Mutex<Vec<String>>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FixtureServer {
+    pub fn serve<P: Into<PathBuf>>(root: P) -> io::Result<FixtureServer> {
+        let root = root.into();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(Mutex::new(vec![]));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let root = root.clone();
+            let requests = Arc::clone(&requests);
+            let shutdown = Arc::clone(&shutdown);
+
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    let stream = match stream {
+                        Ok(v) => v,
+                        Err(_) => continue,
+                    };
+
+                    let root = root.clone();
+                    let requests = Arc::clone(&requests);
+                    std::thread::spawn(move || {
+                        if let Err(e) = handle(stream, &root, &requests) {
+                            log::debug!("Fixture server connection failed: {:?}", e);
+                        }
+                    });
+                }
+            })
+        };
+
+        Ok(FixtureServer {
+            addr,
+            root,
+            requests,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    /// The base URL of the served directory, with a trailing slash.
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("http://{}/", self.addr)).expect("valid url")
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The method, path and response status of every request answered so
+    /// far, in order, such as `GET /packages/index.bin 304`.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FixtureServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+
+        // Wake the listener up so it sees the shutdown flag.
+        let _ = TcpStream::connect(self.addr);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+}
+
+fn read_request(stream: &TcpStream) -> io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(index) = line.find(':') {
+            let (name, value) = line.split_at(index);
+            headers.insert(name.trim().to_lowercase(), value[1..].trim().to_string());
+        }
+    }
+
+    Ok(Request {
+        method,
+        path,
+        headers,
+    })
+}
+
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut resolved = root.to_path_buf();
+
+    // Clients join paths onto URLs with and without trailing slashes, so
+    // empty segments are skipped.
+    for segment in path.split('/').filter(|x| !x.is_empty()) {
+        if segment == "." || segment == ".." {
+            return None;
+        }
+        resolved.push(segment);
+    }
+
+    if resolved.is_file() {
+        Some(resolved)
+    } else {
+        None
+    }
+}
+
+/// Parses a single `bytes=start-` or `bytes=start-end` range.
+fn parse_range(value: &str, len: u64) -> Option<(u64, u64)> {
+    let value = value.trim();
+    if !value.starts_with("bytes=") {
+        return None;
+    }
+    let value = &value["bytes=".len()..];
+    let mut parts = value.splitn(2, '-');
+    let start = parts.next()?.trim().parse::<u64>().ok()?;
+    let end = match parts.next()?.trim() {
+        "" => len.saturating_sub(1),
+        x => x.parse::<u64>().ok()?.min(len.saturating_sub(1)),
+    };
+    Some((start, end))
+}
+
+/// What a request is answered with, worked out before anything is written so
+/// that it can be recorded first.
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    /// The file and the byte range of it to send as the body.
+    body: Option<(PathBuf, u64, u64)>,
+}
+
+impl Response {
+    fn empty(status: &'static str, headers: Vec<(&'static str, String)>) -> Response {
+        Response {
+            status,
+            headers,
+            body: None,
+        }
+    }
+
+    fn write(self, stream: &mut TcpStream) -> io::Result<()> {
+        write!(stream, "HTTP/1.1 {}\r\n", self.status)?;
+        for (name, value) in self.headers.iter() {
+            write!(stream, "{}: {}\r\n", name, value)?;
+        }
+        write!(stream, "Connection: close\r\n\r\n")?;
+
+        if let Some((path, start, len)) = self.body {
+            let mut file = std::fs::File::open(&path)?;
+            file.seek(SeekFrom::Start(start))?;
+            io::copy(&mut file.take(len), stream)?;
+        }
+
+        stream.flush()
+    }
+}
+
+fn handle(mut stream: TcpStream, root: &Path, requests: &Mutex<Vec<String>>) -> io::Result<()> {
+    let request = read_request(&stream)?;
+    let response = serve(root, &request)?;
+
+    // Recorded before answering, so a client that got its answer always
+    // finds its request here. Only the code is kept, such as `304`.
+    let code = response.status.split_whitespace().next().unwrap_or_default();
+    requests
+        .lock()
+        .unwrap()
+        .push(format!("{} {} {}", request.method, request.path, code));
+
+    response.write(&mut stream)
+}
+
+/// Works out the answer to `request`.
+fn serve(root: &Path, request: &Request) -> io::Result<Response> {
+    if request.method != "GET" && request.method != "HEAD" {
+        return Ok(Response::empty(
+            "405 Method Not Allowed",
+            vec![("Content-Length", "0".into())],
+        ));
+    }
+
+    let path = match resolve(root, &request.path) {
+        Some(v) => v,
+        None => {
+            return Ok(Response::empty(
+                "404 Not Found",
+                vec![("Content-Length", "0".into())],
+            ))
+        }
+    };
+
+    let metadata = std::fs::metadata(&path)?;
+    let len = metadata.len();
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_nanos())
+        .unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", len, modified);
+
+    if request.headers.get("if-none-match") == Some(&etag) {
+        return Ok(Response::empty("304 Not Modified", vec![("ETag", etag)]));
+    }
+
+    // A range only applies if the client's copy is still the same file.
+    let range = match request.headers.get("if-range") {
+        Some(v) if v != &etag => None,
+        _ => request.headers.get("range").and_then(|x| parse_range(x, len)),
+    };
+
+    let (status, start, end, mut headers) = match range {
+        Some((start, end)) if start < len && start <= end => (
+            "206 Partial Content",
+            start,
+            end,
+            vec![("Content-Range", format!("bytes {}-{}/{}", start, end, len))],
+        ),
+        Some(_) => {
+            return Ok(Response::empty(
+                "416 Range Not Satisfiable",
+                vec![
+                    ("Content-Range", format!("bytes */{}", len)),
+                    ("Content-Length", "0".into()),
+                ],
+            ))
+        }
+        None => ("200 OK", 0, len.saturating_sub(1), vec![]),
+    };
+
+    let body_len = if len == 0 { 0 } else { end - start + 1 };
+    headers.push(("Content-Length", body_len.to_string()));
+    headers.push(("Accept-Ranges", "bytes".into()));
+    headers.push(("ETag", etag));
+
+    let body = if request.method == "GET" && body_len > 0 {
+        Some((path, start, body_len))
+    } else {
+        None
+    };
+
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::{ETAG, IF_NONE_MATCH, IF_RANGE, RANGE};
+    use reqwest::StatusCode;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn honours_if_none_match_and_range() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("payload.txt"), b"0123456789").unwrap();
+        let server = FixtureServer::serve(dir.path()).unwrap();
+        let url = server.url().join("payload.txt").unwrap();
+        let client = reqwest::Client::new();
+
+        let response = client.get(url.clone()).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+        assert_eq!(&response.bytes().await.unwrap()[..], b"0123456789");
+
+        let response = client
+            .get(url.clone())
+            .header(IF_NONE_MATCH, etag.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = client
+            .get(url.clone())
+            .header(RANGE, "bytes=4-")
+            .header(IF_RANGE, etag.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(&response.bytes().await.unwrap()[..], b"456789");
+
+        // A range for a different version of the file gets all of it.
+        let response = client
+            .get(url.clone())
+            .header(RANGE, "bytes=4-")
+            .header(IF_RANGE, "\"stale\"")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(&response.bytes().await.unwrap()[..], b"0123456789");
+
+        let response = client.get(url).header(RANGE, "bytes=20-").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        assert_eq!(
+            server.requests(),
+            vec![
+                "GET /payload.txt 200",
+                "GET /payload.txt 304",
+                "GET /payload.txt 206",
+                "GET /payload.txt 200",
+                "GET /payload.txt 416",
+            ]
+        );
+    }
+
+    #[test]
+    fn does_not_resolve_outside_its_root() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("root")).unwrap();
+        std::fs::write(dir.path().join("secret.txt"), b"secret").unwrap();
+        std::fs::write(dir.path().join("root").join("index.toml"), b"").unwrap();
+
+        let root = dir.path().join("root");
+        assert_eq!(resolve(&root, "/../secret.txt"), None);
+        assert_eq!(resolve(&root, "//index.toml"), Some(root.join("index.toml")));
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use hashbrown::HashMap;
+use pahkat_types::package::Package;
+use url::Url;
+
+use crate::config::{ConfigPath, SettingsData};
+use crate::package_store::{
+    DownloadEvent, DownloadOptions, ImportError, InstallTarget, LocalizedStrings, SharedRepoErrors,
+    SharedRepos, SharedStoreConfig, Stream,
+};
+use crate::repo::{FindPackageError, PackageQuery, RefreshError, SearchOptions};
+use crate::transaction::{install::InstallError, uninstall::UninstallError};
+use crate::transaction::{PackageStatus, PackageStatusError, ResolvedDescriptor, ResolvedPackageQuery};
+use crate::{cmp, Config, LoadedRepository, PackageKey, PackageStore};
+
+/// A package store that installs nothing, only recording which version of
+/// each package it was asked to install.
+///
+/// Its configuration and caches live in a temporary directory that is
+/// removed when the store is dropped, so downloads and repositories added to
+/// its config work as they would with a real store.
+pub struct MemoryPackageStore {
+    repos: SharedRepos,
+    repo_errors: SharedRepoErrors,
+    config: SharedStoreConfig,
+    installed: RwLock<HashMap<(String, InstallTarget), String>>,
+    dir: tempfile::TempDir,
+}
+
+impl MemoryPackageStore {
+    /// Panics if the temporary directory cannot be set up.
+    pub fn new() -> MemoryPackageStore {
+        let dir = tempfile::tempdir().expect("temporary directory");
+
+        let settings = SettingsData {
+            cache_dir: ConfigPath::from_path(dir.path().join("cache")).expect("cache path"),
+            tmp_dir: ConfigPath::from_path(dir.path().join("tmp")).expect("tmp path"),
+            ..Default::default()
+        };
+        let settings = toml::to_string(&settings).expect("settings serialize");
+        std::fs::write(dir.path().join("settings.toml"), settings).expect("settings written");
+
+        let config = Config::load(dir.path(), crate::config::Permission::ReadWrite)
+            .expect("config loaded");
+
+        MemoryPackageStore {
+            repos: Default::default(),
+            repo_errors: Default::default(),
+            config: Arc::new(RwLock::new(config)),
+            installed: Default::default(),
+            dir,
+        }
+    }
+
+    /// The temporary directory holding the store's configuration and caches.
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Adds a repository directly, without it being in the config.
+    /// Repositories added this way are kept across refreshes.
+    pub fn add_repo(&self, repo: LoadedRepository) {
+        let url = repo.info.repository.url.clone();
+        self.repos.write().unwrap().insert(url, repo);
+    }
+
+    /// Marks `version` of a package as installed, whether or not any
+    /// repository provides it.
+    pub fn set_installed(&self, key: &PackageKey, target: InstallTarget, version: &str) {
+        self.installed
+            .write()
+            .unwrap()
+            .insert(Self::record_key(key, target), version.to_string());
+    }
+
+    fn refresh(&self, conditional: bool) -> crate::package_store::Future<Result<(), RefreshError>> {
+        let config = self.config.read().unwrap().clone();
+        let repos = Arc::clone(&self.repos);
+        let repo_errors = Arc::clone(&self.repo_errors);
+        Box::pin(async move {
+            let (result, errors) = crate::repo::refresh_repos(config, conditional).await;
+            repos.write().unwrap().extend(result);
+            *repo_errors.write().unwrap() = errors.clone();
+
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(RefreshError { errors })
+            }
+        })
+    }
+
+    fn record_key(key: &PackageKey, target: InstallTarget) -> (String, InstallTarget) {
+        (key.clone().without_query_params().to_string(), target)
+    }
+}
+
+impl Default for MemoryPackageStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PackageStore for MemoryPackageStore {
+    fn repos(&self) -> SharedRepos {
+        Arc::clone(&self.repos)
+    }
+
+    fn repo_errors(&self) -> SharedRepoErrors {
+        Arc::clone(&self.repo_errors)
+    }
+
+    fn config(&self) -> SharedStoreConfig {
+        Arc::clone(&self.config)
+    }
+
+    fn install_dir(&self, _target: InstallTarget) -> Option<PathBuf> {
+        Some(self.dir.path().join("install"))
+    }
+
+    fn download_with_options(
+        &self,
+        key: &PackageKey,
+        options: DownloadOptions,
+    ) -> Stream<DownloadEvent> {
+        let repos = self.repos.read().unwrap();
+        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        crate::repo::download(&self.config, key, &query, &*repos, options)
+    }
+
+    fn import(&self, key: &PackageKey, installer_path: &Path) -> Result<PathBuf, ImportError> {
+        let repos = self.repos.read().unwrap();
+        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        crate::repo::import(&self.config, key, &query, &*repos, installer_path)
+    }
+
+    /// Records the resolved release as installed. The payload does not need
+    /// to have been downloaded.
+    fn install(
+        &self,
+        key: &PackageKey,
+        target: InstallTarget,
+    ) -> Result<PackageStatus, InstallError> {
+        let repos = self.repos.read().unwrap();
+        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        let (_, release, _) =
+            crate::repo::resolve_payload(key, &query, &*repos).map_err(InstallError::Payload)?;
+
+        self.set_installed(key, target, &release.version.to_string());
+        Ok(PackageStatus::UpToDate)
+    }
+
+    fn uninstall(
+        &self,
+        key: &PackageKey,
+        target: InstallTarget,
+    ) -> Result<PackageStatus, UninstallError> {
+        match self
+            .installed
+            .write()
+            .unwrap()
+            .remove(&Self::record_key(key, target))
+        {
+            Some(_) => Ok(PackageStatus::NotInstalled),
+            None => Err(UninstallError::NotInstalled),
+        }
+    }
+
+    fn status(
+        &self,
+        key: &PackageKey,
+        target: InstallTarget,
+    ) -> Result<PackageStatus, PackageStatusError> {
+        let version = match self.installed_version(key, target) {
+            Some(v) => v,
+            None => return Ok(PackageStatus::NotInstalled),
+        };
+
+        let repos = self.repos.read().unwrap();
+        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        let (_, release, _) = crate::repo::resolve_payload(key, &query, &*repos)
+            .map_err(PackageStatusError::Payload)?;
+
+        cmp::cmp(&version, &release.version)
+    }
+
+    fn installed_version(&self, key: &PackageKey, target: InstallTarget) -> Option<String> {
+        self.installed
+            .read()
+            .unwrap()
+            .get(&Self::record_key(key, target))
+            .cloned()
+    }
+
+    fn all_statuses(
+        &self,
+        repo_url: &Url,
+        target: InstallTarget,
+    ) -> BTreeMap<String, Result<PackageStatus, PackageStatusError>> {
+        crate::repo::all_statuses(self, repo_url, target)
+    }
+
+    fn find_package_by_id(
+        &self,
+        package_id: &str,
+    ) -> Result<(PackageKey, Package), FindPackageError> {
+        let repos = self.repos.read().unwrap();
+        crate::repo::find_package_by_id(self, package_id, &*repos)
+    }
+
+    fn find_package_by_key(&self, key: &PackageKey) -> Option<Package> {
+        let repos = self.repos.read().unwrap();
+        crate::repo::find_package_by_key(key, &*repos)
+    }
+
+    fn refresh_repos(&self) -> crate::package_store::Future<Result<(), RefreshError>> {
+        self.refresh(true)
+    }
+
+    fn force_refresh_repos(&self) -> crate::package_store::Future<Result<(), RefreshError>> {
+        self.refresh(false)
+    }
+
+    fn clear_cache(&self) -> crate::CacheReport {
+        crate::repo::clear_cache(&self.config)
+    }
+
+    fn prune_cache(&self) -> crate::CacheReport {
+        crate::repo::prune_cache(self)
+    }
+
+    fn strings(&self, language: String) -> crate::package_store::Future<HashMap<Url, LocalizedStrings>> {
+        let repos = self.repos.read().unwrap();
+        let urls = repos.keys().cloned().collect::<Vec<_>>();
+
+        Box::pin(crate::repo::strings(Arc::clone(&self.config), urls, language))
+    }
+
+    fn resolve_package_query(
+        &self,
+        query: PackageQuery,
+        install_target: &[InstallTarget],
+    ) -> ResolvedPackageQuery {
+        let repos = self.repos();
+        let repos = repos.read().unwrap();
+        crate::repo::resolve_package_query(self, &query, install_target, &*repos)
+    }
+
+    fn search(
+        &self,
+        query: &str,
+        options: &SearchOptions,
+        install_target: &[InstallTarget],
+    ) -> Vec<ResolvedDescriptor> {
+        let repos = self.repos();
+        let repos = repos.read().unwrap();
+        crate::repo::search(self, query, options, install_target, &*repos)
+    }
+}
@@ -2,15 +2,25 @@ use std::fmt;
 use std::sync::Arc;
 use std::collections::HashSet;
 
+use futures::stream::StreamExt;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::package_store::PackageStore;
+use crate::package_store::{CancellationToken, DownloadEvent, DownloadOptions, PackageStore};
 use pahkat_types::PackageKey;
 
+pub mod hooks;
 pub mod install;
+pub mod journal;
+pub mod plan;
 pub mod uninstall;
 
+pub use self::hooks::{Hook, HookError, HookEvent, HookStage};
+pub use self::journal::{JournalError, JournalStep, RollbackError, StepState};
+pub use self::plan::{PlanError, PlannedAction, TransactionPlan};
+
+use self::journal::Journal;
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum PackageStatus {
     NotInstalled,
@@ -32,6 +42,7 @@ pub fn status_to_i8(result: Result<PackageStatus, PackageStatusError>) -> i8 {
                 PayloadError::NoPackage | PayloadError::NoConcretePackage => -1,
                 PayloadError::NoPayloadFound => -2,
                 PayloadError::CriteriaUnmet(_) => -5,
+                PayloadError::UnknownChannel(_) => -6,
             },
             PackageStatusError::WrongPayloadType => -3,
             PackageStatusError::ParsingVersion => -4,
@@ -177,6 +188,14 @@ pub enum TransactionError {
     UserCancelled,
     Uninstall(UninstallError),
     Install(InstallError),
+    Journal(JournalError),
+    /// A hook vetoed the transaction or one of its actions.
+    Hook(HookError),
+    /// The transaction did not finish before the process exited.
+    Interrupted,
+    /// Undoing the steps before a failure also failed, so the journal is
+    /// kept for another attempt.
+    Rollback(Box<TransactionError>, RollbackError),
 }
 
 impl std::error::Error for TransactionError {}
@@ -190,6 +209,10 @@ impl std::fmt::Display for TransactionError {
             UserCancelled => write!(f, "User cancelled"),
             Uninstall(e) => write!(f, "{:?}", e),
             Install(e) => write!(f, "{:?}", e),
+            Journal(e) => write!(f, "{}", e),
+            Hook(e) => write!(f, "{}", e),
+            Interrupted => write!(f, "Interrupted"),
+            Rollback(e, rollback) => write!(f, "{}; rolling back also failed: {}", e, rollback),
         }
     }
 }
@@ -198,7 +221,12 @@ impl std::fmt::Display for TransactionError {
 pub enum TransactionEvent {
     Installing(PackageKey),
     Uninstalling(PackageKey),
+    /// A step is being undone after a later one failed.
+    RollingBack(PackageKey),
     Progress(PackageKey, String),
+    /// A hook failed after its action or the transaction, which does not
+    /// stop the transaction. `None` for transaction hooks.
+    HookFailed(Option<PackageKey>, HookError),
     Error(PackageKey, TransactionError),
     Complete,
 }
@@ -209,14 +237,22 @@ use pahkat_types::{payload::Target, package::{Descriptor, Release}};
 pub struct ResolvedAction {
     pub action: PackageAction,
     pub descriptor: Descriptor,
-    pub release: Release,
-    pub target: Target,
+    /// `None` when uninstalling a package its repository no longer lists, in
+    /// which case `descriptor` only has its id.
+    pub release: Option<Release>,
+    /// `None` under the same conditions as `release`.
+    pub target: Option<Target>,
 }
 
+/// Used when `max_concurrent_downloads` is not set.
+const DEFAULT_CONCURRENT_DOWNLOADS: usize = 3;
+
 pub struct PackageTransaction {
     store: Arc<dyn PackageStore>,
     actions: Arc<Vec<ResolvedAction>>,
     is_reboot_required: bool,
+    cancel: CancellationToken,
+    hooks: Vec<Arc<dyn Hook>>,
 }
 
 use crate::repo::PackageCandidateError;
@@ -274,6 +310,21 @@ pub struct ResolvedPackageQuery {
 }
 
 impl PackageTransaction {
+    /// Installs every update from `PackageStore::updates`, each for the target
+    /// it was found for.
+    pub fn upgrade_all(
+        store: Arc<dyn PackageStore>,
+        targets: &[InstallTarget],
+    ) -> Result<PackageTransaction, PackageCandidateError> {
+        let actions = store
+            .updates(targets)
+            .into_iter()
+            .map(|update| PackageAction::install(update.key, update.target))
+            .collect::<Vec<_>>();
+
+        PackageTransaction::new(store, actions)
+    }
+
     pub fn new(
         store: Arc<dyn PackageStore>,
         actions: Vec<PackageAction>,
@@ -302,13 +353,13 @@ impl PackageTransaction {
         let is_reboot_required = install_set.iter().any(|x| x.is_reboot_required);
 
         // Create a list of resolved actions to be processed.
-        let new_actions = install_set.into_iter().map(|candidate| {
+        let mut new_actions = install_set.into_iter().map(|candidate| {
             let key = candidate.package_key;
 
             ResolvedAction {
                 descriptor: candidate.descriptor,
-                release: candidate.release,
-                target: candidate.target,
+                release: Some(candidate.release),
+                target: Some(candidate.target),
                 action: actions.iter().find(|x| &x.id == &key).cloned().unwrap_or_else(|| {
                     PackageAction {
                         id: key,
@@ -324,14 +375,49 @@ impl PackageTransaction {
             if new_actions.iter().any(|x| x.action.id == action.id) {
                 return Err(PackageCandidateError::UninstallConflict(action.id.clone()));
             }
+
+            let query = crate::repo::ReleaseQuery::new(&action.id, &*repos);
+            let resolved = match crate::repo::resolve_payload(&action.id, &query, &*repos) {
+                Ok((target, release, descriptor)) => ResolvedAction {
+                    action: action.clone(),
+                    descriptor,
+                    release: Some(release),
+                    target: Some(target),
+                },
+                // A package that was removed from its repository can still be
+                // uninstalled, as long as the store knows it is installed.
+                Err(PayloadError::NoPackage)
+                    if store.installed_version(&action.id, action.target).is_some() =>
+                {
+                    ResolvedAction {
+                        action: action.clone(),
+                        descriptor: Descriptor::builder()
+                            .package(
+                                pahkat_types::package::DescriptorData::builder()
+                                    .id(action.id.id.clone())
+                                    .build(),
+                            )
+                            .build(),
+                        release: None,
+                        target: None,
+                    }
+                }
+                Err(e) => return Err(PackageCandidateError::Payload(action.id.clone(), e)),
+            };
+
+            new_actions.push(resolved);
         }
 
         log::debug!("Processed actions: {:#?}", &new_actions);
 
+        let hooks = hooks::from_config(&*store);
+
         Ok(PackageTransaction {
             store,
             actions: Arc::new(new_actions),
             is_reboot_required,
+            cancel: CancellationToken::new(),
+            hooks,
         })
     }
 
@@ -343,6 +429,118 @@ impl PackageTransaction {
         self.is_reboot_required
     }
 
+    /// Adds a hook to run after those from the hooks directory.
+    pub fn add_hook(&mut self, hook: Arc<dyn Hook>) {
+        self.hooks.push(hook);
+    }
+
+    /// Stops downloads promptly, and processing before the next step. Steps
+    /// already processed are undone, and the transaction ends with
+    /// `TransactionError::UserCancelled`. A step being processed is always
+    /// finished first.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// A token that cancels this transaction, for cancelling it from another
+    /// task.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Works out how much will be downloaded and installed, and checks there
+    /// is enough free space for it. Call this before `download` to fail
+    /// early instead of part way through.
+    pub fn plan(&self) -> Result<TransactionPlan, PlanError> {
+        let plan = TransactionPlan::new(&*self.store, &self.actions);
+        plan.check_free_space(&*self.store)?;
+        Ok(plan)
+    }
+
+    /// Downloads the payloads of every install action, up to the configured
+    /// `max_concurrent_downloads` at a time. Events are tagged with the package
+    /// they belong to. If any download fails, the error is the last event and
+    /// all other downloads are aborted.
+    ///
+    /// Free space is not checked here; call `plan` first for that.
+    pub fn download(
+        &self,
+    ) -> (
+        stream_cancel::Trigger,
+        crate::package_store::Stream<(PackageKey, DownloadEvent)>,
+    ) {
+        self.download_with_options(DownloadOptions::default())
+    }
+
+    /// Like `download`, with every download sharing the given priority and
+    /// pause control. Downloads are cancelled with the transaction, so the
+    /// token in `options` is replaced.
+    pub fn download_with_options(
+        &self,
+        mut options: DownloadOptions,
+    ) -> (
+        stream_cancel::Trigger,
+        crate::package_store::Stream<(PackageKey, DownloadEvent)>,
+    ) {
+        let (canceler, valve) = stream_cancel::Valve::new();
+        options.cancel = self.cancel.clone();
+
+        let store = Arc::clone(&self.store);
+        let keys = self
+            .actions
+            .iter()
+            .filter(|x| x.action.is_install())
+            .map(|x| x.action.id.clone())
+            .collect::<Vec<_>>();
+
+        let limit = match store.config().read().unwrap().settings().max_concurrent_downloads() {
+            0 => DEFAULT_CONCURRENT_DOWNLOADS,
+            n => n as usize,
+        };
+
+        let stream = async_stream::stream! {
+            use futures::stream::{self, SelectAll, StreamExt};
+
+            // Each download ends with `None` so a finished slot can be refilled.
+            let start = |key: PackageKey| {
+                let events = store.download_with_options(&key, options.clone());
+                let done = key.clone();
+                events
+                    .map(move |event| (key.clone(), Some(event)))
+                    .chain(stream::once(async move { (done, None) }))
+            };
+
+            let mut pending = keys.into_iter();
+            let mut active = SelectAll::new();
+
+            for key in pending.by_ref().take(limit) {
+                active.push(start(key));
+            }
+
+            while let Some((key, event)) = active.next().await {
+                match event {
+                    Some(DownloadEvent::Error(e)) => {
+                        log::error!("Download of {} failed: {:?}", &key, &e);
+                        // Dropping the remaining streams aborts their downloads.
+                        yield (key, DownloadEvent::Error(e));
+                        return;
+                    }
+                    Some(event) => yield (key, event),
+                    None => {
+                        if let Some(key) = pending.next() {
+                            active.push(start(key));
+                        }
+                    }
+                }
+            }
+        };
+
+        (canceler, Box::pin(valve.wrap(stream)))
+    }
+
+    /// Installs and uninstalls in order, recording each step in the journal.
+    /// If a step fails, every step before it is undone before the error is
+    /// reported, so the error is always the last event.
     pub fn process(
         &self,
     ) -> (
@@ -355,43 +553,507 @@ impl PackageTransaction {
 
         let store = Arc::clone(&self.store);
         let actions: Arc<Vec<ResolvedAction>> = Arc::clone(&self.actions);
+        let cancel = self.cancel.clone();
+        let hooks = Arc::new(self.hooks.clone());
 
         let stream = async_stream::stream! {
-            for record in actions.iter() {
-                let action = &record.action;
-                log::debug!("processing action: {}", &action);
-
-                match action.action {
-                    PackageActionType::Install => {
-                        yield TransactionEvent::Installing(action.id.clone());
-
-                        match store.install(&action.id, action.target) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("{:?}", &e);
-                                yield TransactionEvent::Error(action.id.clone(), TransactionError::Install(e));
-                                return;
-                            }
-                        };
-                    }
-                    PackageActionType::Uninstall => {
-                        yield TransactionEvent::Uninstalling(action.id.clone());
-
-                        match store.uninstall(&action.id, action.target) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("{:?}", &e);
-                                yield TransactionEvent::Error(action.id.clone(), TransactionError::Uninstall(e));
-                                return;
-                            }
-                        };
+            let journal = match Journal::create(&*store, &actions) {
+                Ok(v) => v,
+                Err(e) => {
+                    let key = actions.first().map(|x| x.action.id.clone());
+                    if let Some(key) = key {
+                        yield TransactionEvent::Error(key, TransactionError::Journal(e));
                     }
+                    return;
                 }
+            };
+
+            let mut events = run(store, journal, cancel, hooks);
+            while let Some(event) = events.next().await {
+                yield event;
             }
+        };
+
+        (canceler, Box::pin(valve.wrap(stream)))
+    }
+}
+
+/// A transaction whose journal was left behind, because the process exited
+/// part way through or undoing a failed transaction also failed.
+pub struct InterruptedTransaction {
+    store: Arc<dyn PackageStore>,
+    journal: Journal,
+    cancel: CancellationToken,
+    hooks: Vec<Arc<dyn Hook>>,
+}
 
-            yield TransactionEvent::Complete;
+impl InterruptedTransaction {
+    pub fn load(
+        store: Arc<dyn PackageStore>,
+    ) -> Result<Option<InterruptedTransaction>, JournalError> {
+        Ok(Journal::load(&*store)?.map(|journal| InterruptedTransaction {
+            hooks: hooks::from_config(&*store),
+            store,
+            journal,
+            cancel: CancellationToken::new(),
+        }))
+    }
+
+    pub fn steps(&self) -> &[JournalStep] {
+        self.journal.steps()
+    }
+
+    /// A token that cancels a resumed transaction, as `PackageTransaction::cancel`
+    /// does. Rolling back cannot be cancelled.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Processes the steps that were not completed, undoing everything if
+    /// one of them fails.
+    pub fn resume(
+        self,
+    ) -> (
+        stream_cancel::Trigger,
+        crate::package_store::Stream<TransactionEvent>,
+    ) {
+        let (canceler, valve) = stream_cancel::Valve::new();
+        let hooks = Arc::new(self.hooks);
+        let stream = run(self.store, self.journal, self.cancel, hooks);
+        (canceler, Box::pin(valve.wrap(stream)))
+    }
+
+    /// Undoes every step that was started.
+    pub fn rollback(
+        self,
+    ) -> (
+        stream_cancel::Trigger,
+        crate::package_store::Stream<TransactionEvent>,
+    ) {
+        let (canceler, valve) = stream_cancel::Valve::new();
+        let store = self.store;
+        let journal = self.journal;
+
+        let stream = async_stream::stream! {
+            let mut journal = journal;
+            let mut events = rollback(&*store, &mut journal);
+            let mut failed = None;
+
+            while let Some(event) = events.next().await {
+                match event {
+                    Ok(event) => yield event,
+                    Err(e) => failed = Some(e),
+                }
+            }
+            drop(events);
+
+            match failed {
+                Some((key, e)) => {
+                    yield TransactionEvent::Error(
+                        key,
+                        TransactionError::Rollback(Box::new(TransactionError::Interrupted), e),
+                    );
+                }
+                None => {
+                    if let Err(e) = journal.finish() {
+                        log::error!("{:?}", &e);
+                    }
+                    yield TransactionEvent::Complete;
+                }
+            }
         };
 
         (canceler, Box::pin(valve.wrap(stream)))
     }
 }
+
+/// Undoes applied steps in reverse order, yielding `RollingBack` for each.
+/// Stops at the first step that cannot be undone, yielding its error.
+fn rollback<'a>(
+    store: &'a dyn PackageStore,
+    journal: &'a mut Journal,
+) -> std::pin::Pin<
+    Box<
+        dyn futures::stream::Stream<Item = Result<TransactionEvent, (PackageKey, RollbackError)>>
+            + Send
+            + Sync
+            + 'a,
+    >,
+> {
+    Box::pin(async_stream::stream! {
+        for index in (0..journal.steps().len()).rev() {
+            let step = journal.steps()[index].clone();
+            if !step.is_applied() {
+                continue;
+            }
+
+            let key = step.action.action.id.clone();
+            yield Ok(TransactionEvent::RollingBack(key.clone()));
+
+            if let Err(e) = journal::undo(store, &step).await {
+                log::error!("{:?}", &e);
+                yield Err((key, e));
+                return;
+            }
+
+            // Failing to record this only means it may be undone again.
+            if let Err(e) = journal.set_state(index, StepState::RolledBack) {
+                log::error!("{:?}", &e);
+            }
+        }
+    })
+}
+
+/// Processes every step of the journal that has not completed, stopping
+/// before the next step once `cancel` is cancelled.
+fn run(
+    store: Arc<dyn PackageStore>,
+    journal: Journal,
+    cancel: CancellationToken,
+    hooks: Arc<Vec<Arc<dyn Hook>>>,
+) -> crate::package_store::Stream<TransactionEvent> {
+    Box::pin(async_stream::stream! {
+        let mut journal = journal;
+        let mut failure = None;
+
+        let actions = Arc::new(journal.steps().iter().map(|x| x.action.clone()).collect::<Vec<_>>());
+
+        // A resumed transaction was already allowed to start.
+        let is_fresh = journal.steps().iter().all(|x| x.state == StepState::Pending);
+        let mut is_vetoed = false;
+        if let (true, Some(first)) = (is_fresh, actions.first()) {
+            let event_actions = Arc::clone(&actions);
+            let errors = hooks::run_blocking(&hooks, move |hooks| {
+                hooks::run(hooks, &HookEvent::PreTransaction(&event_actions))
+            }).await;
+            if let Some(e) = errors.into_iter().next() {
+                failure = Some((first.action.id.clone(), TransactionError::Hook(e)));
+                is_vetoed = true;
+            }
+        }
+
+        for index in 0..journal.steps().len() {
+            if failure.is_some() {
+                break;
+            }
+
+            let step = journal.steps()[index].clone();
+            if step.state == StepState::Completed || step.state == StepState::RolledBack {
+                continue;
+            }
+
+            let action = &step.action.action;
+            log::debug!("processing action: {}", &action);
+
+            if cancel.is_cancelled() {
+                failure = Some((action.id.clone(), TransactionError::UserCancelled));
+                break;
+            }
+
+            let event_action = step.action.clone();
+            let errors = hooks::run_blocking(&hooks, move |hooks| {
+                hooks::run(hooks, &HookEvent::PreAction(&event_action))
+            }).await;
+            if let Some(e) = errors.into_iter().next() {
+                failure = Some((action.id.clone(), TransactionError::Hook(e)));
+                break;
+            }
+
+            if let Err(e) = journal.set_state(index, StepState::Started) {
+                failure = Some((action.id.clone(), TransactionError::Journal(e)));
+                break;
+            }
+
+            let result = match action.action {
+                PackageActionType::Install => {
+                    yield TransactionEvent::Installing(action.id.clone());
+                    match store.install_cancellable(&action.id, action.target, &cancel) {
+                        Err(InstallError::UserCancelled) => Err(TransactionError::UserCancelled),
+                        result => result.map_err(TransactionError::Install),
+                    }
+                }
+                PackageActionType::Uninstall => {
+                    yield TransactionEvent::Uninstalling(action.id.clone());
+                    match store.uninstall_cancellable(&action.id, action.target, &cancel) {
+                        // Already uninstalled before an interruption.
+                        Err(UninstallError::NotInstalled) if step.state == StepState::Started => {
+                            Ok(PackageStatus::NotInstalled)
+                        }
+                        Err(UninstallError::UserCancelled) => Err(TransactionError::UserCancelled),
+                        result => result.map_err(TransactionError::Uninstall),
+                    }
+                }
+            };
+
+            if let Err(TransactionError::UserCancelled) = result {
+                // Nothing was changed, so there is nothing of this step to undo.
+                if let Err(e) = journal.set_state(index, step.state) {
+                    log::error!("{:?}", &e);
+                }
+            }
+
+            if let Err(e) = result {
+                log::error!("{:?}", &e);
+                failure = Some((action.id.clone(), e));
+                break;
+            }
+
+            if let Err(e) = journal.set_state(index, StepState::Completed) {
+                failure = Some((action.id.clone(), TransactionError::Journal(e)));
+                break;
+            }
+
+            let event_action = step.action.clone();
+            let errors = hooks::run_blocking(&hooks, move |hooks| {
+                hooks::run(hooks, &HookEvent::PostAction(&event_action))
+            }).await;
+            for e in errors {
+                yield TransactionEvent::HookFailed(Some(action.id.clone()), e);
+            }
+        }
+
+        let (key, error) = match failure {
+            Some(v) => v,
+            None => {
+                if let Err(e) = journal.finish() {
+                    log::error!("{:?}", &e);
+                }
+
+                // Payloads of freshly installed packages are now protected, so
+                // this is the right time to bring the cache back within its limit.
+                let report = store.prune_cache();
+                log::debug!("Pruned package cache: {:?}", &report);
+
+                let event_actions = Arc::clone(&actions);
+                let errors = hooks::run_blocking(&hooks, move |hooks| {
+                    hooks::run(hooks, &HookEvent::PostTransaction(&event_actions))
+                }).await;
+                for e in errors {
+                    yield TransactionEvent::HookFailed(None, e);
+                }
+
+                yield TransactionEvent::Complete;
+                return;
+            }
+        };
+
+        let mut events = rollback(&*store, &mut journal);
+        let mut rollback_error = None;
+
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(event) => yield event,
+                Err((_, e)) => rollback_error = Some(e),
+            }
+        }
+        drop(events);
+
+        // Packages may have changed even though the transaction failed, but
+        // not if it was vetoed before it started.
+        if !is_vetoed {
+            let event_actions = Arc::clone(&actions);
+            let errors = hooks::run_blocking(&hooks, move |hooks| {
+                hooks::run(hooks, &HookEvent::PostTransaction(&event_actions))
+            }).await;
+            for e in errors {
+                yield TransactionEvent::HookFailed(None, e);
+            }
+        }
+
+        match rollback_error {
+            // The journal is kept so the rollback can be retried.
+            Some(e) => {
+                yield TransactionEvent::Error(key, TransactionError::Rollback(Box::new(error), e));
+            }
+            None => {
+                if let Err(e) = journal.finish() {
+                    log::error!("{:?}", &e);
+                }
+                yield TransactionEvent::Error(key, error);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{descriptor, payload, MemoryPackageStore, RepositoryBuilder};
+
+    fn repo_url() -> Url {
+        Url::parse("https://pahkat.test/repo/").unwrap()
+    }
+
+    fn key(id: &str) -> PackageKey {
+        PackageKey::new_unchecked(repo_url(), id.to_string(), None)
+    }
+
+    fn store() -> Arc<MemoryPackageStore> {
+        let mut app = descriptor("app", "1.0.0", payload(repo_url().join("app.bin").unwrap()));
+        app.release[0].target[0]
+            .dependencies
+            .insert("lib".into(), "*".into());
+        let lib = descriptor("lib", "2.0.0", payload(repo_url().join("lib.bin").unwrap()));
+
+        let store = MemoryPackageStore::new();
+        store.add_repo(RepositoryBuilder::new(repo_url()).package(app).package(lib).build());
+        Arc::new(store)
+    }
+
+    async fn process(store: &Arc<MemoryPackageStore>, actions: Vec<PackageAction>) -> Vec<TransactionEvent> {
+        let store: Arc<dyn PackageStore> = Arc::clone(store) as _;
+        let transaction = PackageTransaction::new(store, actions).unwrap();
+        let (_canceler, stream) = transaction.process();
+        stream.collect::<Vec<_>>().await
+    }
+
+    #[tokio::test]
+    async fn installs_dependencies_then_uninstalls() {
+        let store = store();
+
+        let events = process(&store, vec![PackageAction::install(key("app"), InstallTarget::System)]).await;
+        assert!(matches!(events.last(), Some(TransactionEvent::Complete)), "{:?}", events);
+        assert_eq!(store.installed_version(&key("app"), InstallTarget::System), Some("1.0.0".into()));
+        assert_eq!(store.installed_version(&key("lib"), InstallTarget::System), Some("2.0.0".into()));
+
+        let events = process(&store, vec![PackageAction::uninstall(key("app"), InstallTarget::System)]).await;
+        assert!(matches!(events.last(), Some(TransactionEvent::Complete)), "{:?}", events);
+        assert_eq!(store.installed_version(&key("app"), InstallTarget::System), None);
+        assert_eq!(store.installed_version(&key("lib"), InstallTarget::System), Some("2.0.0".into()));
+
+        // The journal is removed once a transaction completes.
+        assert!(InterruptedTransaction::load(Arc::clone(&store) as _).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn uninstalls_packages_removed_from_their_repository() {
+        let store = store();
+        store.set_installed(&key("gone"), InstallTarget::System, "0.1.0");
+
+        let transaction = PackageTransaction::new(
+            Arc::clone(&store) as _,
+            vec![PackageAction::uninstall(key("gone"), InstallTarget::System)],
+        )
+        .unwrap();
+        let actions = transaction.actions();
+        assert_eq!(actions[0].descriptor.package.id, "gone");
+        assert!(actions[0].release.is_none());
+        assert_eq!(transaction.plan().unwrap().installed_size_delta, 0);
+
+        let (_canceler, events) = transaction.process();
+        let events = events.collect::<Vec<_>>().await;
+        assert!(matches!(events.last(), Some(TransactionEvent::Complete)), "{:?}", events);
+        assert_eq!(store.installed_version(&key("gone"), InstallTarget::System), None);
+
+        // Without an installed record there is nothing to fall back to.
+        let result = PackageTransaction::new(
+            Arc::clone(&store) as _,
+            vec![PackageAction::uninstall(key("gone"), InstallTarget::System)],
+        );
+        assert!(matches!(result, Err(PackageCandidateError::Payload(_, PayloadError::NoPackage))));
+    }
+
+    /// Records the stages it runs for, failing those in `fails`.
+    struct RecordingHook {
+        stages: std::sync::Mutex<Vec<HookStage>>,
+        fails: Vec<HookStage>,
+    }
+
+    impl Hook for RecordingHook {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn run(&self, event: &HookEvent<'_>) -> Result<(), HookError> {
+            self.stages.lock().unwrap().push(event.stage());
+            if self.fails.contains(&event.stage()) {
+                return Err(HookError::Failed("recording".into(), "no".into()));
+            }
+            Ok(())
+        }
+    }
+
+    async fn process_with_hook(
+        store: &Arc<MemoryPackageStore>,
+        actions: Vec<PackageAction>,
+        fails: Vec<HookStage>,
+    ) -> (Vec<TransactionEvent>, Vec<HookStage>) {
+        let hook = Arc::new(RecordingHook {
+            stages: Default::default(),
+            fails,
+        });
+        let mut transaction = PackageTransaction::new(Arc::clone(store) as _, actions).unwrap();
+        transaction.add_hook(Arc::clone(&hook) as _);
+        let (_canceler, events) = transaction.process();
+        let events = events.collect::<Vec<_>>().await;
+        let stages = hook.stages.lock().unwrap().clone();
+        (events, stages)
+    }
+
+    #[tokio::test]
+    async fn runs_hooks_around_every_action() {
+        let store = store();
+
+        let (events, stages) = process_with_hook(
+            &store,
+            vec![PackageAction::install(key("lib"), InstallTarget::System)],
+            vec![],
+        )
+        .await;
+        assert!(matches!(events.last(), Some(TransactionEvent::Complete)), "{:?}", events);
+        assert_eq!(stages, HookStage::ALL.to_vec());
+    }
+
+    #[tokio::test]
+    async fn vetoed_transactions_skip_post_transaction_hooks() {
+        let store = store();
+
+        let (events, stages) = process_with_hook(
+            &store,
+            vec![PackageAction::install(key("lib"), InstallTarget::System)],
+            vec![HookStage::PreTransaction],
+        )
+        .await;
+        assert!(
+            matches!(events.last(), Some(TransactionEvent::Error(_, TransactionError::Hook(_)))),
+            "{:?}",
+            events
+        );
+        assert_eq!(stages, vec![HookStage::PreTransaction]);
+        assert_eq!(store.installed_version(&key("lib"), InstallTarget::System), None);
+    }
+
+    #[tokio::test]
+    async fn failed_actions_still_run_post_transaction_hooks() {
+        let store = store();
+
+        let (events, stages) = process_with_hook(
+            &store,
+            vec![PackageAction::install(key("lib"), InstallTarget::System)],
+            vec![HookStage::PreAction],
+        )
+        .await;
+        assert!(
+            matches!(events.last(), Some(TransactionEvent::Error(_, TransactionError::Hook(_)))),
+            "{:?}",
+            events
+        );
+        assert_eq!(
+            stages,
+            vec![HookStage::PreTransaction, HookStage::PreAction, HookStage::PostTransaction]
+        );
+    }
+
+    #[tokio::test]
+    async fn uninstalling_a_package_that_is_not_installed_fails() {
+        let store = store();
+
+        let events = process(&store, vec![PackageAction::uninstall(key("app"), InstallTarget::System)]).await;
+        match events.last() {
+            Some(TransactionEvent::Error(key, TransactionError::Uninstall(UninstallError::NotInstalled))) => {
+                assert_eq!(key.id, "app");
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::ResolvedAction;
+use crate::package_store::PackageStore;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HookError {
+    #[error("Hook {0} could not be run")]
+    Io(String, #[source] std::io::Error),
+
+    #[error("Hook {0} failed: {1}")]
+    Failed(String, String),
+
+    #[error("Hook {0} did not finish within {1:?}")]
+    TimedOut(String, Duration),
+
+    #[error("Could not serialize the input of hook {0}")]
+    Input(String, #[source] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookStage {
+    PreTransaction,
+    PreAction,
+    PostAction,
+    PostTransaction,
+}
+
+impl HookStage {
+    pub const ALL: [HookStage; 4] = [
+        HookStage::PreTransaction,
+        HookStage::PreAction,
+        HookStage::PostAction,
+        HookStage::PostTransaction,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookStage::PreTransaction => "pre-transaction",
+            HookStage::PreAction => "pre-action",
+            HookStage::PostAction => "post-action",
+            HookStage::PostTransaction => "post-transaction",
+        }
+    }
+
+    /// A hook failing before something happens vetoes it. Failing after only
+    /// produces a warning, as there is nothing left to stop.
+    pub fn is_pre(&self) -> bool {
+        match self {
+            HookStage::PreTransaction | HookStage::PreAction => true,
+            HookStage::PostAction | HookStage::PostTransaction => false,
+        }
+    }
+}
+
+/// What a hook is being run for.
+#[derive(Debug, Clone, Copy)]
+pub enum HookEvent<'a> {
+    PreTransaction(&'a [ResolvedAction]),
+    PreAction(&'a ResolvedAction),
+    PostAction(&'a ResolvedAction),
+    /// Run once the transaction has ended, whether or not it succeeded, unless
+    /// a `PreTransaction` hook vetoed it.
+    PostTransaction(&'a [ResolvedAction]),
+}
+
+impl<'a> HookEvent<'a> {
+    pub fn stage(&self) -> HookStage {
+        match self {
+            HookEvent::PreTransaction(_) => HookStage::PreTransaction,
+            HookEvent::PreAction(_) => HookStage::PreAction,
+            HookEvent::PostAction(_) => HookStage::PostAction,
+            HookEvent::PostTransaction(_) => HookStage::PostTransaction,
+        }
+    }
+
+    /// The `ResolvedAction` for action stages, or an array of every action
+    /// in the transaction for transaction stages.
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        match self {
+            HookEvent::PreTransaction(x) | HookEvent::PostTransaction(x) => serde_json::to_vec(x),
+            HookEvent::PreAction(x) | HookEvent::PostAction(x) => serde_json::to_vec(x),
+        }
+    }
+}
+
+/// Site-specific work run around a transaction, such as restarting a service
+/// that uses the installed packages.
+pub trait Hook: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// An error from a pre stage vetoes the transaction or action.
+    fn run(&self, event: &HookEvent<'_>) -> Result<(), HookError>;
+}
+
+/// An executable from the hooks directory, run for a single stage with the
+/// stage as its only argument and the event as JSON on stdin. A non-zero exit
+/// status is a failure, described by what the hook wrote to stderr. A hook
+/// still running after `timeout` is killed, which is also a failure.
+#[derive(Debug, Clone)]
+pub struct ScriptHook {
+    name: String,
+    path: PathBuf,
+    stage: HookStage,
+    timeout: Duration,
+}
+
+impl ScriptHook {
+    pub fn new(path: PathBuf, stage: HookStage, timeout: Duration) -> ScriptHook {
+        let name = format!(
+            "{}/{}",
+            stage.as_str(),
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        ScriptHook {
+            name,
+            path,
+            stage,
+            timeout,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn stage(&self) -> HookStage {
+        self.stage
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+impl Hook for ScriptHook {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self, event: &HookEvent<'_>) -> Result<(), HookError> {
+        if event.stage() != self.stage {
+            return Ok(());
+        }
+
+        let input = event
+            .to_json()
+            .map_err(|e| HookError::Input(self.name.clone(), e))?;
+
+        log::debug!("Running hook {:?}", &self.path);
+
+        let mut child = Command::new(&self.path)
+            .arg(self.stage.as_str())
+            .env("PAHKAT_HOOK_STAGE", self.stage.as_str())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| HookError::Io(self.name.clone(), e))?;
+
+        // Both pipes are handled on their own threads, so that a hook that
+        // neither reads its input nor exits cannot outlast its timeout.
+        let stdin = child.stdin.take().map(|mut stdin| {
+            std::thread::spawn(move || match stdin.write_all(&input) {
+                // A hook that does not read its input closes stdin early.
+                Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e),
+                _ => Ok(()),
+            })
+        });
+        let stderr = child.stderr.take().map(|mut stderr| {
+            std::thread::spawn(move || {
+                let mut buf = vec![];
+                let _ = stderr.read_to_end(&mut buf);
+                buf
+            })
+        });
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() >= deadline => {
+                    log::warn!("Killing hook {:?} after {:?}", &self.path, self.timeout);
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(HookError::TimedOut(self.name.clone(), self.timeout));
+                }
+                Ok(None) => std::thread::sleep(Duration::from_millis(50)),
+                Err(e) => return Err(HookError::Io(self.name.clone(), e)),
+            }
+        };
+
+        if let Some(Ok(Err(e))) = stdin.map(|x| x.join()) {
+            return Err(HookError::Io(self.name.clone(), e));
+        }
+
+        if status.success() {
+            return Ok(());
+        }
+
+        let stderr = stderr.and_then(|x| x.join().ok()).unwrap_or_default();
+        let stderr = String::from_utf8_lossy(&stderr).trim().to_string();
+        let message = if stderr.is_empty() {
+            status.to_string()
+        } else {
+            stderr
+        };
+
+        Err(HookError::Failed(self.name.clone(), message))
+    }
+}
+
+/// Loads the hooks in `dir`, which has a subdirectory for each stage such as
+/// `pre-action`. The hooks of a stage run in file name order, and hidden files
+/// are ignored. Missing subdirectories have no hooks.
+pub fn from_dir(dir: &Path, timeout: Duration) -> Vec<Arc<dyn Hook>> {
+    let mut hooks: Vec<Arc<dyn Hook>> = vec![];
+
+    for stage in HookStage::ALL.iter() {
+        let entries = match std::fs::read_dir(dir.join(stage.as_str())) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+
+        let mut paths = entries
+            .filter_map(Result::ok)
+            .map(|x| x.path())
+            .filter(|x| x.is_file())
+            .filter(|x| {
+                !x.file_name()
+                    .map(|x| x.to_string_lossy().starts_with('.'))
+                    .unwrap_or(true)
+            })
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        for path in paths {
+            hooks.push(Arc::new(ScriptHook::new(path, *stage, timeout)));
+        }
+    }
+
+    hooks
+}
+
+/// The hooks from the store's configured hooks directory.
+pub(crate) fn from_config(store: &dyn PackageStore) -> Vec<Arc<dyn Hook>> {
+    let config = store.config();
+    let config = config.read().unwrap();
+
+    let settings = config.settings();
+    match settings.hooks_dir() {
+        Some(dir) => from_dir(&dir, settings.hook_timeout()),
+        None => vec![],
+    }
+}
+
+/// Runs every hook for `event`, returning their errors. In pre stages the
+/// first error stops the remaining hooks, as the veto has already happened.
+pub(crate) fn run(hooks: &[Arc<dyn Hook>], event: &HookEvent<'_>) -> Vec<HookError> {
+    let mut errors = vec![];
+
+    for hook in hooks {
+        if let Err(e) = hook.run(event) {
+            log::error!("Hook {} failed: {:?}", hook.name(), &e);
+            errors.push(e);
+
+            if event.stage().is_pre() {
+                break;
+            }
+        }
+    }
+
+    errors
+}
+
+/// Runs `f` with the hooks on the blocking thread pool, as hooks may be
+/// external programs that take a while.
+pub(crate) async fn run_blocking<F>(hooks: &Arc<Vec<Arc<dyn Hook>>>, f: F) -> Vec<HookError>
+where
+    F: FnOnce(&[Arc<dyn Hook>]) -> Vec<HookError> + Send + 'static,
+{
+    if hooks.is_empty() {
+        return vec![];
+    }
+
+    let hooks = Arc::clone(hooks);
+    tokio::task::spawn_blocking(move || f(&hooks[..]))
+        .await
+        .expect("hook panicked")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn script(dir: &Path, name: &str, body: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn reports_stderr_of_failed_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = script(
+            dir.path(),
+            "fail",
+            "cat > /dev/null; echo broken >&2; exit 1",
+        );
+        let hook = ScriptHook::new(path, HookStage::PreTransaction, Duration::from_secs(10));
+
+        match hook.run(&HookEvent::PreTransaction(&[])) {
+            Err(HookError::Failed(name, message)) => {
+                assert_eq!(name, "pre-transaction/fail");
+                assert_eq!(message, "broken");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn kills_hooks_that_run_too_long() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = script(dir.path(), "hang", "exec sleep 30");
+        let hook = ScriptHook::new(path, HookStage::PreTransaction, Duration::from_millis(200));
+
+        let started = Instant::now();
+        let result = hook.run(&HookEvent::PreTransaction(&[]));
+        assert!(
+            matches!(result, Err(HookError::TimedOut(_, _))),
+            "{:?}",
+            result
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -11,6 +11,9 @@ pub enum InstallError {
     #[error("Package not found in cache (not downloaded?)")]
     PackageNotInCache,
 
+    #[error("User cancelled the installation")]
+    UserCancelled,
+
     #[error("Installation process failed")]
     InstallerFailure(#[from] ProcessError),
 }
@@ -0,0 +1,421 @@
+use std::path::PathBuf;
+
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use super::install::InstallError;
+use super::uninstall::UninstallError;
+use super::{PackageActionType, PackageStatus, ResolvedAction};
+use crate::ext::PathExt;
+use crate::package_store::{DownloadEvent, PackageStore};
+use crate::PackageKey;
+use pahkat_types::payload::AsDownloadUrl;
+
+const JOURNAL_FILE: &str = "journal.json";
+
+#[derive(Debug, thiserror::Error)]
+pub enum JournalError {
+    #[error("Could not read transaction journal {0:?}")]
+    Read(PathBuf, #[source] std::io::Error),
+
+    #[error("Could not write transaction journal {0:?}")]
+    Write(PathBuf, #[source] std::io::Error),
+
+    #[error("Invalid transaction journal {0:?}")]
+    Invalid(PathBuf, #[source] serde_json::Error),
+
+    /// An interrupted transaction must be resumed or rolled back before
+    /// another can start, or its journal would be lost.
+    #[error("An interrupted transaction must be resumed or rolled back first ({0:?})")]
+    Interrupted(PathBuf),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RollbackError {
+    #[error("The version of {0} installed before the transaction is not known")]
+    UnknownVersion(PackageKey),
+
+    #[error("Could not download the previous version of {0}")]
+    Download(PackageKey, #[source] crate::download::DownloadError),
+
+    #[error("Could not reinstall the previous version of {0}")]
+    Install(PackageKey, #[source] InstallError),
+
+    #[error("Could not uninstall {0}")]
+    Uninstall(PackageKey, #[source] UninstallError),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StepState {
+    Pending,
+    /// The step may have been partially applied.
+    Started,
+    Completed,
+    RolledBack,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JournalStep {
+    pub action: ResolvedAction,
+    pub state: StepState,
+    /// Whether the package was installed before this step.
+    pub was_installed: bool,
+    pub previous_version: Option<String>,
+    /// The cached payload of `previous_version`, if it was cached when the
+    /// transaction started.
+    pub previous_payload: Option<PathBuf>,
+}
+
+impl JournalStep {
+    fn new(store: &dyn PackageStore, record: &ResolvedAction) -> JournalStep {
+        let key = &record.action.id;
+        let target = record.action.target;
+
+        let status = store.status(key, target).ok();
+        let was_installed = matches!(
+            status,
+            Some(PackageStatus::UpToDate) | Some(PackageStatus::RequiresUpdate)
+        );
+
+        let previous_version = if was_installed {
+            store.installed_version(key, target).or_else(|| {
+                // An up to date package has the release that was resolved.
+                match (status, record.release.as_ref()) {
+                    (Some(PackageStatus::UpToDate), Some(release)) => {
+                        Some(release.version.to_string())
+                    }
+                    _ => None,
+                }
+            })
+        } else {
+            None
+        };
+
+        let previous_payload = previous_version
+            .as_ref()
+            .and_then(|version| cached_payload(store, &versioned_key(key, version)));
+
+        JournalStep {
+            action: record.clone(),
+            state: StepState::Pending,
+            was_installed,
+            previous_version,
+            previous_payload,
+        }
+    }
+
+    /// Whether this step may have changed anything that needs undoing.
+    pub fn is_applied(&self) -> bool {
+        match self.state {
+            StepState::Started | StepState::Completed => true,
+            StepState::Pending | StepState::RolledBack => false,
+        }
+    }
+}
+
+fn versioned_key(key: &PackageKey, version: &str) -> PackageKey {
+    let mut key = key.clone();
+    key.query.version = Some(version.to_string());
+    key
+}
+
+fn cached_payload(store: &dyn PackageStore, key: &PackageKey) -> Option<PathBuf> {
+    let repos = store.repos();
+    let repos = repos.read().unwrap();
+    let query = crate::repo::ReleaseQuery::new(key, &*repos);
+    let (target, _, _) = crate::repo::resolve_payload(key, &query, &*repos).ok()?;
+
+    let config = store.config();
+    let config = config.read().unwrap();
+    let path = crate::repo::download_file_path(&config, target.payload.as_download_url());
+
+    if path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalData {
+    steps: Vec<JournalStep>,
+}
+
+/// A record of every step of a transaction, written to disk before and after
+/// each step so that an interrupted transaction can be resumed or undone.
+///
+/// Nothing is written if the store's configuration is read only.
+#[derive(Debug)]
+pub struct Journal {
+    path: Option<PathBuf>,
+    steps: Vec<JournalStep>,
+}
+
+impl Journal {
+    fn path(store: &dyn PackageStore) -> Option<PathBuf> {
+        let config = store.config();
+        let config = config.read().unwrap();
+        let settings = config.settings();
+
+        if settings.is_read_only() {
+            None
+        } else {
+            Some(settings.config_dir().join(JOURNAL_FILE))
+        }
+    }
+
+    /// Fails with `JournalError::Interrupted` if the journal of another
+    /// transaction is still on disk.
+    pub(crate) fn create(
+        store: &dyn PackageStore,
+        actions: &[ResolvedAction],
+    ) -> Result<Journal, JournalError> {
+        if let Some(Journal { path: Some(path), .. }) = Self::load(store)? {
+            return Err(JournalError::Interrupted(path));
+        }
+
+        let journal = Journal {
+            path: Self::path(store),
+            steps: actions.iter().map(|x| JournalStep::new(store, x)).collect(),
+        };
+
+        journal.save()?;
+        Ok(journal)
+    }
+
+    pub(crate) fn load(store: &dyn PackageStore) -> Result<Option<Journal>, JournalError> {
+        let path = match Self::path(store) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        let bytes = match std::fs::read(&path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(JournalError::Read(path, e)),
+        };
+
+        let data: JournalData =
+            serde_json::from_slice(&bytes).map_err(|e| JournalError::Invalid(path.clone(), e))?;
+
+        Ok(Some(Journal {
+            path: Some(path),
+            steps: data.steps,
+        }))
+    }
+
+    pub fn steps(&self) -> &[JournalStep] {
+        &self.steps
+    }
+
+    pub(crate) fn set_state(&mut self, index: usize, state: StepState) -> Result<(), JournalError> {
+        self.steps[index].state = state;
+        self.save()
+    }
+
+    /// Removes the journal once there is nothing left to resume or undo.
+    pub(crate) fn finish(self) -> Result<(), JournalError> {
+        match self.path {
+            Some(path) => match std::fs::remove_file(&path) {
+                Ok(_) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(JournalError::Write(path, e)),
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn save(&self) -> Result<(), JournalError> {
+        let path = match self.path.as_ref() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let data = JournalData {
+            steps: self.steps.clone(),
+        };
+        let bytes = serde_json::to_vec_pretty(&data)
+            .map_err(|e| JournalError::Invalid(path.clone(), e))?;
+
+        path.write_atomic(&bytes).map_err(|e| JournalError::Write(path.clone(), e))
+    }
+}
+
+/// Puts back what a step changed: a fresh install is uninstalled, while an
+/// update or uninstall reinstalls the previous version.
+pub(crate) async fn undo(store: &dyn PackageStore, step: &JournalStep) -> Result<(), RollbackError> {
+    let key = &step.action.action.id;
+    let target = step.action.action.target;
+
+    if !step.was_installed {
+        return match step.action.action.action {
+            PackageActionType::Install => match store.uninstall(key, target) {
+                Ok(_) | Err(UninstallError::NotInstalled) => Ok(()),
+                Err(e) => Err(RollbackError::Uninstall(key.clone(), e)),
+            },
+            PackageActionType::Uninstall => Ok(()),
+        };
+    }
+
+    let version = step
+        .previous_version
+        .as_ref()
+        .ok_or_else(|| RollbackError::UnknownVersion(key.clone()))?;
+    let key = versioned_key(key, version);
+
+    let is_cached = step
+        .previous_payload
+        .as_ref()
+        .map(|x| x.exists())
+        .unwrap_or(false);
+
+    if !is_cached {
+        let mut events = store.download(&key);
+        while let Some(event) = events.next().await {
+            if let DownloadEvent::Error(e) = event {
+                return Err(RollbackError::Download(key, e));
+            }
+        }
+    }
+
+    store
+        .install(&key, target)
+        .map(|_| ())
+        .map_err(|e| RollbackError::Install(key, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use url::Url;
+
+    use super::*;
+    use crate::package_store::InstallTarget;
+    use crate::testing::{descriptor, payload, MemoryPackageStore, RepositoryBuilder};
+    use crate::transaction::{
+        InterruptedTransaction, PackageAction, PackageTransaction, TransactionError,
+        TransactionEvent,
+    };
+
+    fn repo_url() -> Url {
+        Url::parse("https://pahkat.test/repo/").unwrap()
+    }
+
+    fn key(id: &str) -> PackageKey {
+        PackageKey::new_unchecked(repo_url(), id.to_string(), None)
+    }
+
+    /// A store with `a`, `b` and `c` in its repository, of which `a` and `c`
+    /// are installed with their payloads cached.
+    fn store() -> Arc<MemoryPackageStore> {
+        let mut builder = RepositoryBuilder::new(repo_url());
+        for id in &["a", "b", "c"] {
+            let url = repo_url().join(&format!("{}.bin", id)).unwrap();
+            builder = builder.package(descriptor(id, "1.0.0", payload(url)));
+        }
+
+        let store = MemoryPackageStore::new();
+        store.add_repo(builder.build());
+
+        for id in &["a", "c"] {
+            store.set_installed(&key(id), InstallTarget::System, "1.0.0");
+
+            let url = repo_url().join(&format!("{}.bin", id)).unwrap();
+            let config = store.config();
+            let path = crate::repo::download_file_path(&config.read().unwrap(), &url);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"payload").unwrap();
+        }
+
+        Arc::new(store)
+    }
+
+    fn uninstall_all(store: &Arc<MemoryPackageStore>) -> PackageTransaction {
+        let actions = ["a", "b", "c"]
+            .iter()
+            .map(|id| PackageAction::uninstall(key(id), InstallTarget::System))
+            .collect();
+        PackageTransaction::new(Arc::clone(store) as _, actions).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rolls_back_started_steps_when_one_fails() {
+        let store = store();
+        let transaction = uninstall_all(&store);
+        assert_eq!(
+            transaction
+                .actions()
+                .iter()
+                .map(|x| x.action.id.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["a", "b", "c"]
+        );
+
+        // `b` is not installed, so the second step fails after starting.
+        let (_canceler, events) = transaction.process();
+        let events = events.collect::<Vec<_>>().await;
+
+        let rolled_back = events
+            .iter()
+            .filter_map(|x| match x {
+                TransactionEvent::RollingBack(key) => Some(key.id.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(rolled_back, vec!["b", "a"]);
+
+        match events.last() {
+            Some(TransactionEvent::Error(key, TransactionError::Uninstall(_))) => {
+                assert_eq!(key.id, "b")
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        // `a` was reinstalled from its cached payload and `c` never touched.
+        for id in &["a", "c"] {
+            assert_eq!(
+                store.installed_version(&key(id), InstallTarget::System),
+                Some("1.0.0".into())
+            );
+        }
+        assert_eq!(store.installed_version(&key("b"), InstallTarget::System), None);
+
+        // Nothing is left to resume.
+        assert!(Journal::load(&*store).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_to_replace_an_interrupted_journal() {
+        let store = store();
+        let actions = uninstall_all(&store).actions();
+        let mut journal = Journal::create(&*store, &actions).unwrap();
+        journal.set_state(0, StepState::Completed).unwrap();
+
+        let (_canceler, events) = uninstall_all(&store).process();
+        let events = events.collect::<Vec<_>>().await;
+        match events.last() {
+            Some(TransactionEvent::Error(_, TransactionError::Journal(JournalError::Interrupted(_)))) => {}
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        // The interrupted transaction is still there to be rolled back.
+        let interrupted = InterruptedTransaction::load(Arc::clone(&store) as _)
+            .unwrap()
+            .unwrap();
+        assert_eq!(interrupted.steps()[0].state, StepState::Completed);
+    }
+
+    #[test]
+    fn saves_without_leaving_temporary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(JOURNAL_FILE);
+        path.write_atomic(b"one").unwrap();
+        path.write_atomic(b"two").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"two");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,175 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::{PackageAction, PackageActionType, ResolvedAction};
+use crate::package_store::PackageStore;
+use pahkat_types::payload::AsDownloadUrl;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PlanError {
+    #[error("Not enough free space in {path:?}: {required} bytes needed, {available} available")]
+    InsufficientSpace {
+        path: PathBuf,
+        required: u64,
+        available: u64,
+    },
+
+    #[error("Could not determine free space in {0:?}")]
+    FreeSpace(PathBuf, #[source] std::io::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedAction {
+    pub action: PackageAction,
+    /// Zero for uninstalls and payloads that are already cached.
+    pub download_size: u64,
+    /// Change in disk usage once the action is processed, negative for
+    /// uninstalls. Updates count the full size of the new release, as the
+    /// size of the installed release is not known.
+    pub installed_size_delta: i64,
+    pub is_cached: bool,
+}
+
+/// What a transaction will cost, worked out without downloading or
+/// installing anything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionPlan {
+    pub actions: Vec<PlannedAction>,
+    pub download_size: u64,
+    pub installed_size_delta: i64,
+}
+
+impl TransactionPlan {
+    pub(crate) fn new(store: &dyn PackageStore, actions: &[ResolvedAction]) -> TransactionPlan {
+        let config = store.config();
+        let config = config.read().unwrap();
+
+        let actions = actions
+            .iter()
+            .map(|record| {
+                // Only uninstalls of packages no longer in a repository have
+                // no payload, and their size is not known.
+                let payload = match record.target.as_ref() {
+                    Some(target) => &target.payload,
+                    None => {
+                        return PlannedAction {
+                            action: record.action.clone(),
+                            download_size: 0,
+                            installed_size_delta: 0,
+                            is_cached: false,
+                        }
+                    }
+                };
+                let installed_size = payload.installed_size() as i64;
+
+                match record.action.action {
+                    PackageActionType::Install => {
+                        let url = payload.as_download_url();
+                        let is_cached = crate::repo::download_file_path(&config, url).exists();
+
+                        PlannedAction {
+                            action: record.action.clone(),
+                            download_size: if is_cached { 0 } else { payload.size() },
+                            installed_size_delta: installed_size,
+                            is_cached,
+                        }
+                    }
+                    PackageActionType::Uninstall => PlannedAction {
+                        action: record.action.clone(),
+                        download_size: 0,
+                        installed_size_delta: -installed_size,
+                        is_cached: false,
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+
+        TransactionPlan {
+            download_size: actions.iter().map(|x| x.download_size).sum(),
+            installed_size_delta: actions.iter().map(|x| x.installed_size_delta).sum(),
+            actions,
+        }
+    }
+
+    /// Checks the package cache has room for every download, and each install
+    /// directory has room for what is installed into it. Space freed by
+    /// uninstalls is not counted, as they may run after the installs.
+    pub(crate) fn check_free_space(&self, store: &dyn PackageStore) -> Result<(), PlanError> {
+        let cache_dir = store.config().read().unwrap().settings().package_cache_dir();
+
+        let mut required = vec![(cache_dir, self.download_size)];
+
+        for planned in self.actions.iter().filter(|x| x.installed_size_delta > 0) {
+            if let Some(dir) = store.install_dir(planned.action.target) {
+                required.push((dir, planned.installed_size_delta as u64));
+            }
+        }
+
+        // Directories on the same volume share the same free space.
+        let mut volumes: Vec<(PathBuf, Option<String>, u64)> = vec![];
+        for (dir, size) in required {
+            let dir = existing_ancestor(&dir)?;
+            let volume = volume(&dir);
+
+            match volumes
+                .iter_mut()
+                .find(|x| volume.is_some() && x.1 == volume)
+            {
+                Some(entry) => entry.2 += size,
+                None => volumes.push((dir, volume, size)),
+            }
+        }
+
+        for (dir, _, required) in volumes.into_iter().filter(|x| x.2 > 0) {
+            let available =
+                fs2::available_space(&dir).map_err(|e| PlanError::FreeSpace(dir.clone(), e))?;
+
+            if required > available {
+                return Err(PlanError::InsufficientSpace {
+                    path: dir,
+                    required,
+                    available,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Install directories may not exist until something is installed into them.
+fn existing_ancestor(path: &Path) -> Result<PathBuf, PlanError> {
+    path.ancestors()
+        .find(|x| x.exists())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| {
+            PlanError::FreeSpace(
+                path.to_path_buf(),
+                std::io::Error::from(std::io::ErrorKind::NotFound),
+            )
+        })
+}
+
+#[cfg(unix)]
+fn volume(path: &Path) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|x| x.dev().to_string())
+}
+
+#[cfg(windows)]
+fn volume(path: &Path) -> Option<String> {
+    match path.components().next() {
+        Some(std::path::Component::Prefix(prefix)) => {
+            Some(prefix.as_os_str().to_string_lossy().to_lowercase())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn volume(_path: &Path) -> Option<String> {
+    None
+}
@@ -16,4 +16,7 @@ pub enum UninstallError {
 
     #[error("The package is not installed")]
     NotInstalled,
+
+    #[error("User cancelled the uninstallation")]
+    UserCancelled,
 }
@@ -37,12 +37,22 @@ struct StringsCommand {
     language: String,
 }
 
+#[derive(Debug, StructOpt)]
+struct SearchCommand {
+    query: String,
+    #[structopt(short, long)]
+    tag: Vec<String>,
+    #[structopt(short, long, default_value = "0")]
+    limit: u32,
+}
+
 #[derive(Debug, StructOpt)]
 enum Command {
     Status(StatusCommand),
     RepoIndexes(RepoIndexesCommand),
     ProcessTransaction(ProcessTransactionCommand),
     Strings(StringsCommand),
+    Search(SearchCommand),
 }
 
 #[derive(Debug, StructOpt)]
@@ -137,6 +147,16 @@ pub async fn run() -> anyhow::Result<()> {
             let response = client.strings(request).await?;
             println!("{:?}", response);
         }
+        Command::Search(SearchCommand { query, tag, limit }) => {
+            let request = Request::new(pb::SearchRequest {
+                query,
+                tags: tag,
+                limit,
+            });
+
+            let response = client.search(request).await?;
+            println!("{}", response.into_inner().json);
+        }
     }
     Ok(())
 }
@@ -277,6 +297,30 @@ extern "C" fn pahkat_rpc_cancel_callback() {
     }
 }
 
+/// Sends `value` on the stream of the current transaction, if any, leaving it
+/// to be cancelled later.
+fn send_to_current_transaction(value: pb::transaction_request::Value) {
+    let tx = CURRENT_CANCEL_TX.lock().unwrap();
+    if let Some(tx) = tx.borrow().as_ref() {
+        // The transaction may already have ended.
+        let _ = tx.send(pb::TransactionRequest { value: Some(value) });
+    }
+}
+
+#[no_mangle]
+extern "C" fn pahkat_rpc_pause_callback() {
+    send_to_current_transaction(
+        pb::transaction_request::Value::Pause(pb::transaction_request::Pause {})
+    );
+}
+
+#[no_mangle]
+extern "C" fn pahkat_rpc_resume_callback() {
+    send_to_current_transaction(
+        pb::transaction_request::Value::Resume(pb::transaction_request::Resume {})
+    );
+}
+
 #[cthulhu::invoke(return_marshaler = "JsonMarshaler")]
 pub extern "C" fn pahkat_rpc_get_repo_records(
     #[marshal(cursed::ArcRefMarshaler::<RwLock<PahkatClient>>)] client: Arc<RwLock<PahkatClient>>,
@@ -389,6 +433,42 @@ pub extern "C" fn pahkat_rpc_resolve_package_query(
     serde_json::from_str(&response?.json).box_err()
 }
 
+#[cthulhu::invoke(return_marshaler = "JsonMarshaler")]
+pub extern "C" fn pahkat_rpc_clear_cache(
+    #[marshal(cursed::ArcRefMarshaler::<RwLock<PahkatClient>>)] client: Arc<RwLock<PahkatClient>>,
+) -> Result<pb::ClearCacheResponse, Box<dyn Error>> {
+    let request = Request::new(pb::ClearCacheRequest {});
+
+    block_on(async move {
+        let mut client = client.write().await;
+        let response = client.clear_cache(request).await.box_err()?;
+        Ok(response.into_inner())
+    })
+}
+
+#[cthulhu::invoke(return_marshaler = "JsonMarshaler")]
+pub extern "C" fn pahkat_rpc_search(
+    #[marshal(cursed::ArcRefMarshaler::<RwLock<PahkatClient>>)] client: Arc<RwLock<PahkatClient>>,
+    #[marshal(cursed::StrMarshaler::<'_>)]
+    query: &str,
+    #[marshal(JsonRefMarshaler)]
+    options: pahkat_client::repo::SearchOptions,
+) -> Result<Vec<pahkat_client::transaction::ResolvedDescriptor>, Box<dyn Error>> {
+    let request = Request::new(pb::SearchRequest {
+        query: query.to_string(),
+        tags: options.tags.unwrap_or_else(|| vec![]),
+        limit: options.limit.unwrap_or(0) as u32,
+    });
+
+    let response: Result<pb::JsonResponse, Box<dyn Error>> = block_on(async move {
+        let mut client = client.write().await;
+        let response = client.search(request).await.box_err()?;
+        Ok(response.into_inner())
+    });
+
+    serde_json::from_str(&response?.json).box_err()
+}
+
 #[cthulhu::invoke(return_marshaler = "cursed::UnitMarshaler")]
 pub extern "C" fn pahkat_rpc_process_transaction(
     #[marshal(cursed::ArcRefMarshaler::<RwLock<PahkatClient>>)] client: Arc<RwLock<PahkatClient>>,
@@ -6,8 +6,9 @@ pub mod server;
 use futures::stream::{StreamExt, TryStreamExt};
 use log::{error, info, warn};
 use pahkat_client::{
-    config::RepoRecord, package_store::InstallTarget, PackageAction, PackageActionType, PackageKey,
-    PackageStatus, PackageStore, PackageTransaction,
+    config::RepoRecord,
+    package_store::{CancellationToken, DownloadControl, DownloadOptions, InstallTarget},
+    PackageAction, PackageActionType, PackageKey, PackageStore, PackageTransaction,
 };
 use parity_tokio_ipc::{Endpoint, SecurityAttributes};
 use std::collections::HashMap;
@@ -35,6 +36,10 @@ impl From<RepoRecord> for pb::RepoRecord {
     fn from(repo: RepoRecord) -> pb::RepoRecord {
         pb::RepoRecord {
             channel: repo.channel.unwrap_or_else(|| "".into()),
+            priority: repo.priority,
+            credentials: repo.credentials.unwrap_or_else(|| "".into()),
+            pinned: repo.pinned,
+            alias: repo.alias.unwrap_or_else(|| "".into()),
         }
     }
 }
@@ -65,7 +70,10 @@ impl From<pahkat_client::transaction::ResolvedAction> for pb::ResolvedAction {
         pb::ResolvedAction {
             action: Some(record.action.into()),
             name: record.descriptor.name.into_iter().collect(),
-            version: record.release.version.to_string(),
+            version: record
+                .release
+                .map(|x| x.version.to_string())
+                .unwrap_or_default(),
         }
     }
 }
@@ -120,12 +128,27 @@ impl From<pahkat_client::repo::LoadedRepository> for pb::LoadedRepository {
             }),
             meta: Some(pb::loaded_repository::Meta {
                 channel: value.meta.clone().channel.unwrap_or_else(|| "".into()),
+                is_stale: value.meta.is_stale,
             }),
-            packages_fbs: value.packages.to_vec(),
+            packages_fbs: value.packages_bytes().to_vec(),
         }
     }
 }
 
+/// Formats an error along with all of its sources.
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+
+    message
+}
+
 #[derive(Debug, Clone)]
 enum Notification {
     RebootRequired,
@@ -137,10 +160,24 @@ type Result<T> = std::result::Result<Response<T>, Status>;
 type Stream<T> =
     Pin<Box<dyn futures::Stream<Item = std::result::Result<T, Status>> + Send + Sync + 'static>>;
 
+/// The cancellation token of the background update while one is running.
+type BackgroundUpdate = Arc<std::sync::Mutex<Option<CancellationToken>>>;
+
+/// Clears the token of a running background update when it ends, however it
+/// ends, so that cancelling afterwards cannot reach the next one.
+struct BackgroundUpdateGuard(BackgroundUpdate);
+
+impl Drop for BackgroundUpdateGuard {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
 struct Rpc {
     store: Arc<dyn PackageStore>,
     notifications: broadcast::Sender<Notification>,
     current_transaction: Arc<tokio::sync::Mutex<()>>,
+    background_update: BackgroundUpdate,
 }
 
 #[tonic::async_trait]
@@ -226,12 +263,18 @@ impl pb::pahkat_server::Pahkat for Rpc {
     ) -> Result<pb::RepositoryIndexesResponse> {
         let repos = self.store.repos();
         let repos = repos.read().unwrap();
+        let repo_errors = self.store.repo_errors();
+        let repo_errors = repo_errors.read().unwrap();
 
         Ok(Response::new(pb::RepositoryIndexesResponse {
             repositories: repos
                 .values()
                 .map(|x| pb::LoadedRepository::from(x.clone()))
                 .collect(),
+            errors: repo_errors
+                .iter()
+                .map(|(url, e)| (url.to_string(), error_chain(&**e)))
+                .collect(),
         }))
     }
 
@@ -242,12 +285,16 @@ impl pb::pahkat_server::Pahkat for Rpc {
         let request = request.into_inner();
         let store: Arc<dyn PackageStore> = Arc::clone(&self.store as _);
         let current_transaction = Arc::clone(&self.current_transaction);
+        let background_update = Arc::clone(&self.background_update);
 
         let (tx, rx) = mpsc::unbounded_channel();
         // Get messages
         tokio::spawn(async move {
             let mut has_requested = false;
-            let mut has_cancelled = false;
+            let mut cancel_token: Option<CancellationToken> = None;
+            let download_control = DownloadControl::default();
+            // Whether this client's transaction is waiting for the lock.
+            let is_waiting = Arc::new(std::sync::atomic::AtomicBool::new(false));
 
             futures::pin_mut!(request);
             let (escape_catch_tx, _) = tokio::sync::broadcast::channel(1);
@@ -284,13 +331,32 @@ impl pb::pahkat_server::Pahkat for Rpc {
                         v
                     }
                     pb::transaction_request::Value::Cancel(_) => {
-                        if has_requested {
+                        if let Some(token) = cancel_token.take() {
                             // We can cancel this transaction as it is ours.
-                            has_cancelled = true;
+                            token.cancel();
+                        }
+
+                        // A client waiting for the lock may be kept waiting by
+                        // a background update, which it then means to cancel.
+                        // Once it has its own transaction running, the
+                        // background update is none of its business.
+                        if is_waiting.load(std::sync::atomic::Ordering::SeqCst) {
+                            if let Some(token) = background_update.lock().unwrap().as_ref() {
+                                log::info!("Cancelling background update.");
+                                token.cancel();
+                            }
                         }
 
                         return;
                     }
+                    pb::transaction_request::Value::Pause(_) => {
+                        download_control.pause();
+                        continue;
+                    }
+                    pb::transaction_request::Value::Resume(_) => {
+                        download_control.resume();
+                        continue;
+                    }
                 };
 
                 let actions = request
@@ -319,8 +385,13 @@ impl pb::pahkat_server::Pahkat for Rpc {
                     }
                 };
 
+                cancel_token = Some(transaction.cancellation_token());
+
                 let store = Arc::clone(&store);
                 let current_transaction = Arc::clone(&current_transaction);
+                let download_control = download_control.clone();
+                let is_waiting = Arc::clone(&is_waiting);
+                is_waiting.store(true, std::sync::atomic::Ordering::SeqCst);
 
                 let tx = tx.clone();
 
@@ -330,6 +401,7 @@ impl pb::pahkat_server::Pahkat for Rpc {
                     log::debug!("Waiting for transaction lock…");
                     let _guard = current_transaction.lock().await;
                     log::debug!("Transaction lock attained.");
+                    is_waiting.store(false, std::sync::atomic::Ordering::SeqCst);
 
                     let tx1 = tx.clone();
                     let stream = async_stream::try_stream! {
@@ -337,47 +409,81 @@ impl pb::pahkat_server::Pahkat for Rpc {
                         use pahkat_client::package_store::DownloadEvent;
                         use pb::transaction_response::*;
 
+                        let plan = match transaction.plan() {
+                            Ok(v) => v,
+                            Err(e) => {
+                                yield pb::TransactionResponse {
+                                    value: Some(Value::TransactionError(TransactionError {
+                                        package_id: "".to_string(),
+                                        error: format!("{}", e)
+                                    }))
+                                };
+                                return;
+                            }
+                        };
+
                         yield pb::TransactionResponse {
                             value: Some(Value::TransactionStarted(TransactionStarted {
                                 actions: transaction.actions().iter().cloned().map(|x| x.into()).collect(),
                                 is_reboot_required: transaction.is_reboot_required(),
+                                download_size: plan.download_size,
+                                installed_size_delta: plan.installed_size_delta,
                             }))
                         };
 
-                        for record in transaction.actions().iter() {
-                            let tx = tx.clone();
-                            let id = record.action.id.clone();
-                            let mut download = store.download(&record.action.id);
-
-                            // TODO: handle cancel here
-
-                            while let Some(event) = download.next().await {
-                                match event {
-                                    DownloadEvent::Error(e) => {
-                                        yield pb::TransactionResponse {
-                                            value: Some(Value::TransactionError(TransactionError {
-                                                package_id: id.to_string(),
-                                                error: format!("{}", e)
-                                            }))
-                                        };
-                                        return;
-                                    }
-                                    DownloadEvent::Progress((current, total)) => {
-                                        yield pb::TransactionResponse {
-                                            value: Some(Value::DownloadProgress(DownloadProgress {
-                                                package_id: id.to_string(),
-                                                current,
-                                                total,
-                                            }))
-                                        };
-                                    }
-                                    DownloadEvent::Complete(_) => {
-                                        yield pb::TransactionResponse {
-                                            value: Some(Value::DownloadComplete(DownloadComplete {
-                                                package_id: id.to_string(),
-                                            }))
-                                        };
-                                    }
+                        // A cancel request stops the downloads, which ends with an error.
+                        let (_download_canceler, mut download) = transaction.download_with_options(DownloadOptions {
+                            control: download_control,
+                            ..Default::default()
+                        });
+
+                        while let Some((id, event)) = download.next().await {
+                            match event {
+                                DownloadEvent::Error(e) => {
+                                    yield pb::TransactionResponse {
+                                        value: Some(Value::TransactionError(TransactionError {
+                                            package_id: id.to_string(),
+                                            error: format!("{}", e)
+                                        }))
+                                    };
+                                    return;
+                                }
+                                DownloadEvent::Progress(p) => {
+                                    yield pb::TransactionResponse {
+                                        value: Some(Value::DownloadProgress(DownloadProgress {
+                                            package_id: id.to_string(),
+                                            current: p.current,
+                                            total: p.total,
+                                            bytes_per_second: p.bytes_per_second,
+                                            eta_seconds: p.eta.map(|x| x.as_secs()).unwrap_or(0),
+                                            resumed_from: p.resumed_from,
+                                        }))
+                                    };
+                                }
+                                DownloadEvent::Phase(phase) => {
+                                    yield pb::TransactionResponse {
+                                        value: Some(Value::DownloadPhase(DownloadPhase {
+                                            package_id: id.to_string(),
+                                            phase: phase.to_u8() as u32,
+                                        }))
+                                    };
+                                }
+                                DownloadEvent::Retrying { attempt, error, .. } => {
+                                    yield pb::TransactionResponse {
+                                        value: Some(Value::TransactionProgress(TransactionProgress {
+                                            package_id: id.to_string(),
+                                            message: format!("Retrying download (attempt {}): {}", attempt, error),
+                                            current: 0,
+                                            total: 0,
+                                        }))
+                                    };
+                                }
+                                DownloadEvent::Complete(_) => {
+                                    yield pb::TransactionResponse {
+                                        value: Some(Value::DownloadComplete(DownloadComplete {
+                                            package_id: id.to_string(),
+                                        }))
+                                    };
                                 }
                             }
                         }
@@ -389,8 +495,6 @@ impl pb::pahkat_server::Pahkat for Rpc {
                         while let Some(event) = tx_stream.next().await {
                             use pahkat_client::transaction::TransactionEvent;
 
-                            // TODO: handle cancel here
-
                             match event {
                                 TransactionEvent::Installing(id) => {
                                     yield pb::TransactionResponse {
@@ -406,6 +510,26 @@ impl pb::pahkat_server::Pahkat for Rpc {
                                         }))
                                     };
                                 }
+                                TransactionEvent::RollingBack(id) => {
+                                    yield pb::TransactionResponse {
+                                        value: Some(Value::TransactionProgress(TransactionProgress {
+                                            package_id: id.to_string(),
+                                            message: "rolling back".to_string(),
+                                            current: 0,
+                                            total: 0,
+                                        }))
+                                    };
+                                }
+                                TransactionEvent::HookFailed(id, e) => {
+                                    yield pb::TransactionResponse {
+                                        value: Some(Value::TransactionProgress(TransactionProgress {
+                                            package_id: id.map(|x| x.to_string()).unwrap_or_default(),
+                                            message: format!("{}", e),
+                                            current: 0,
+                                            total: 0,
+                                        }))
+                                    };
+                                }
                                 TransactionEvent::Progress(id, msg) => {
                                     yield pb::TransactionResponse {
                                         value: Some(Value::TransactionProgress(TransactionProgress {
@@ -486,6 +610,7 @@ impl pb::pahkat_server::Pahkat for Rpc {
             Url::parse(&request.url).map_err(|e| Status::failed_precondition(format!("{}", e)))?;
 
         let config = self.store.config();
+        let channel;
         {
             let mut config = config.write().unwrap();
             let mut repos = config.repos_mut();
@@ -496,20 +621,44 @@ impl pb::pahkat_server::Pahkat for Rpc {
                 if other_record.channel != "" {
                     record.channel = Some(other_record.channel);
                 }
+                record.priority = other_record.priority;
+                if other_record.credentials != "" {
+                    record.credentials = Some(other_record.credentials);
+                }
+                record.pinned = other_record.pinned;
+                if other_record.alias != "" {
+                    record.alias = Some(other_record.alias);
+                }
             }
 
+            channel = record.channel.clone();
+
             repos
-                .insert(url, record)
+                .insert(url.clone(), record)
                 .map_err(|e| Status::failed_precondition(format!("{}", e)))?;
         }
 
-        self.store
-            .force_refresh_repos()
-            .await
-            .map_err(|e| Status::failed_precondition(format!("{}", e)))?;
+        // Repositories that fail to refresh are reported by `repository_indexes`.
+        if let Err(e) = self.store.force_refresh_repos().await {
+            log::warn!("{}", error_chain(&e));
+        }
 
         let _ = self.notifications.send(Notification::RepositoriesChanged);
 
+        // The record is kept, as the repository may declare the channel later.
+        let error = match channel {
+            Some(channel) => self
+                .store
+                .repos()
+                .read()
+                .unwrap()
+                .get(&url)
+                .and_then(|repo| repo.info().repository.channel_chain(&channel).err())
+                .map(|e| e.to_string())
+                .unwrap_or_default(),
+            None => "".into(),
+        };
+
         let mut config = config.read().unwrap();
         let mut repos = config.repos();
 
@@ -518,7 +667,7 @@ impl pb::pahkat_server::Pahkat for Rpc {
                 .iter()
                 .map(|(k, v)| (k.to_string(), v.to_owned().into()))
                 .collect(),
-            error: "".into(),
+            error,
         }))
     }
 
@@ -558,10 +707,10 @@ impl pb::pahkat_server::Pahkat for Rpc {
                 .map_err(|e| Status::failed_precondition(format!("{}", e)))?
         };
 
-        self.store
-            .force_refresh_repos()
-            .await
-            .map_err(|e| Status::failed_precondition(format!("{}", e)))?;
+        // Repositories that fail to refresh are reported by `repository_indexes`.
+        if let Err(e) = self.store.force_refresh_repos().await {
+            log::warn!("{}", error_chain(&e));
+        }
 
         let _ = self.notifications.send(Notification::RepositoriesChanged);
 
@@ -589,16 +738,83 @@ impl pb::pahkat_server::Pahkat for Rpc {
             json: serde_json::to_string(&results).unwrap(),
         }))
     }
+
+    async fn clear_cache(
+        &self,
+        _request: Request<pb::ClearCacheRequest>,
+    ) -> Result<pb::ClearCacheResponse> {
+        let mut report = self.store.clear_cache();
+        report += self.store.prune_cache();
+
+        Ok(tonic::Response::new(pb::ClearCacheResponse {
+            bytes_freed: report.bytes_freed,
+            files_removed: report.files_removed,
+        }))
+    }
+
+    async fn search(&self, request: Request<pb::SearchRequest>) -> Result<pb::JsonResponse> {
+        log::debug!("Received search request: {:?}", &request);
+        let request = request.into_inner();
+
+        let options = pahkat_client::repo::SearchOptions {
+            tags: if request.tags.is_empty() {
+                None
+            } else {
+                Some(request.tags)
+            },
+            limit: if request.limit == 0 {
+                None
+            } else {
+                Some(request.limit as usize)
+            },
+        };
+
+        let results = self.store.search(
+            &request.query,
+            &options,
+            &[InstallTarget::System, InstallTarget::User],
+        );
+        log::debug!("search results: {:?}", &results);
+        Ok(tonic::Response::new(pb::JsonResponse {
+            json: serde_json::to_string(&results).unwrap(),
+        }))
+    }
 }
 
 use std::path::Path;
 
+/// Undoes a transaction left unfinished by a previous run, so the service
+/// never starts with packages half installed.
+async fn rollback_interrupted(store: &Arc<dyn PackageStore>) {
+    use pahkat_client::transaction::{InterruptedTransaction, TransactionEvent};
+
+    let interrupted = match InterruptedTransaction::load(Arc::clone(store)) {
+        Ok(Some(v)) => v,
+        Ok(None) => return,
+        Err(e) => {
+            log::error!("Could not load transaction journal: {}", e);
+            return;
+        }
+    };
+
+    log::warn!("Rolling back interrupted transaction");
+    let (_canceler, mut events) = interrupted.rollback();
+    while let Some(event) = events.next().await {
+        match event {
+            TransactionEvent::RollingBack(id) => log::info!("Rolling back {}", id),
+            TransactionEvent::Error(id, e) => log::error!("Could not roll back {}: {}", id, e),
+            _ => {}
+        }
+    }
+}
+
 #[inline(always)]
 #[cfg(feature = "prefix")]
 async fn store(config_path: Option<&Path>) -> anyhow::Result<Arc<dyn PackageStore>> {
     let config_path = config_path.ok_or_else(|| anyhow::anyhow!("No prefix path specified"))?;
     let store = pahkat_client::PrefixPackageStore::open(config_path)?;
-    let store = Arc::new(store);
+    let store: Arc<dyn PackageStore> = Arc::new(store);
+    rollback_interrupted(&store).await;
 
     if store.config().read().unwrap().repos().len() == 0 {
         log::warn!("There are no repositories in the given config.");
@@ -617,7 +833,8 @@ async fn store(config_path: Option<&Path>) -> anyhow::Result<Arc<dyn PackageStor
     log::debug!("{:?}", &config);
 
     let store = pahkat_client::MacOSPackageStore::new(config).await;
-    let store = Arc::new(store);
+    let store: Arc<dyn PackageStore> = Arc::new(store);
+    rollback_interrupted(&store).await;
 
     if store.config().read().unwrap().repos().len() == 0 {
         log::warn!("There are no repositories in the given config.");
@@ -635,7 +852,8 @@ async fn store(config_path: Option<&Path>) -> anyhow::Result<Arc<dyn PackageStor
     };
     log::debug!("Loading config...");
     let store = pahkat_client::WindowsPackageStore::new(config).await;
-    let store = Arc::new(store);
+    let store: Arc<dyn PackageStore> = Arc::new(store);
+    rollback_interrupted(&store).await;
 
     if store.config().read().unwrap().repos().len() == 0 {
         log::warn!("There are no repositories in the given config.");
@@ -692,9 +910,54 @@ fn endpoint(path: &Path) -> std::result::Result<UnixListener, anyhow::Error> {
     Ok(tokio::net::UnixListener::from_std(std_listener).unwrap())
 }
 
+/// Reloads the configuration when an administrator edits its files, so that
+/// repositories added or removed by hand take effect without a restart.
+fn create_config_watcher(
+    store: Arc<dyn PackageStore>,
+    notifications: broadcast::Sender<Notification>,
+) {
+    const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+    tokio::spawn(async move {
+        let events = pahkat_client::config::watch(store.config(), POLL_INTERVAL);
+        futures::pin_mut!(events);
+
+        while let Some(event) = events.next().await {
+            let change = match event {
+                pahkat_client::config::ConfigEvent::Reloaded(change) => change,
+                pahkat_client::config::ConfigEvent::Invalid(e) => {
+                    log::error!("Configuration not reloaded: {}", error_chain(&e));
+                    continue;
+                }
+            };
+
+            log::info!("Configuration reloaded.");
+
+            // Settings are read from the shared config whenever they are used,
+            // so a download or refresh started after this uses the new ones.
+            // Nothing that is running is restarted to apply them.
+            if change.settings {
+                log::debug!("Settings changed, applying from the next operation.");
+            }
+
+            if change.repos {
+                // A plain refresh leaves the caches alone, so it is safe to run
+                // alongside a transaction. Repositories that fail to refresh
+                // are reported by `repository_indexes`.
+                if let Err(e) = store.refresh_repos().await {
+                    log::warn!("{}", error_chain(&e));
+                }
+
+                let _ = notifications.send(Notification::RepositoriesChanged);
+            }
+        }
+    });
+}
+
 fn create_background_update_service(
     store: Arc<dyn PackageStore>,
     current_transaction: Arc<tokio::sync::Mutex<()>>,
+    background_update: BackgroundUpdate,
 ) {
     const UPDATE_INTERVAL: Duration = Duration::from_secs(15 * 60); // 15 minutes
 
@@ -724,92 +987,57 @@ fn create_background_update_service(
             
             log::info!("Running update check…");
 
-            // Currently installed packages:
-            log::debug!("Iterating through all known packages...");
-            let updates = {
-                let repos = store.repos();
-                let repos = repos.read().unwrap();
-
-                let mut updates = vec![];
-
-                for (url, repo) in repos.iter() {
-                    log::debug!("## Repo: {:?}", &url);
-                    let statuses = store.all_statuses(url, pahkat_client::InstallTarget::System);
-
-                    for (key, value) in statuses.into_iter() {
-                        log::debug!(" - {:?}: {:?}", &key, &value);
-                        if let Ok(PackageStatus::RequiresUpdate) = value {
-                            updates.push((
-                                pahkat_client::types::PackageKey {
-                                    repository_url: url.clone(),
-                                    id: key,
-                                    query: Default::default(),
-                                },
-                                pahkat_client::InstallTarget::System,
-                            ));
-                        }
-                    }
-                }
+            // The service runs as the system, so packages installed for a
+            // user are left for them to update.
+            let targets = [InstallTarget::System];
+
+            log::debug!("Waiting for transaction lock…");
+            let _guard = current_transaction.lock().await;
+            log::debug!("Transaction lock attained.");
 
-                updates
+            let transaction = match PackageTransaction::upgrade_all(Arc::clone(&store) as _, &targets) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("Not updating: {}", e);
+                    continue 'main;
+                }
             };
 
-            log::debug!("Proposed updates: {:?}", &updates);
+            log::debug!("Proposed updates: {:?}", transaction.actions());
 
-            if updates.is_empty() {
+            if transaction.actions().is_empty() {
                 log::info!("No updates found.");
                 continue;
             }
 
-            let actions = updates
-                .into_iter()
-                .map(|(package_key, target)| PackageAction::install(package_key, target))
-                .collect::<Vec<_>>();
-
-            log::debug!("Waiting for transaction lock…");
-            let _guard = current_transaction.lock().await;
-            log::debug!("Transaction lock attained.");
-
-            let transaction = PackageTransaction::new(Arc::clone(&store) as _, actions).unwrap(); // .map_err(|e| Status::failed_precondition(format!("{}", e)))?;
-
-            for record in transaction.actions().iter() {
-                let action = &record.action;
-                // let tx = tx.clone();
-                // let id = action.id.clone();
-                let mut download = store.download(&action.id);
+            // Cancelling stops the downloads, or the update before its next step.
+            *background_update.lock().unwrap() = Some(transaction.cancellation_token());
+            let _update = BackgroundUpdateGuard(Arc::clone(&background_update));
 
-                // TODO: handle cancel here
+            use pahkat_client::package_store::{DownloadEvent, DownloadOptions, TransferPriority};
 
-                use pahkat_client::package_store::DownloadEvent;
+            if let Err(e) = transaction.plan() {
+                log::error!("Not updating: {}", e);
+                continue 'main;
+            }
 
-                while let Some(event) = download.next().await {
-                    match event {
-                        DownloadEvent::Error(e) => {
-                            log::error!("{:?}", &e);
-                            continue 'main;
-                        }
-                        event => {
-                            log::debug!("{:?}", &event);
-                        }
-                    };
-                    //     DownloadEvent::Progress((current, total)) => {
-                    //         yield pb::TransactionResponse {
-                    //             value: Some(Value::DownloadProgress(DownloadProgress {
-                    //                 package_id: id.to_string(),
-                    //                 current,
-                    //                 total,
-                    //             }))
-                    //         };
-                    //     }
-                    //     DownloadEvent::Complete(_) => {
-                    //         yield pb::TransactionResponse {
-                    //             value: Some(Value::DownloadComplete(DownloadComplete {
-                    //                 package_id: id.to_string(),
-                    //             }))
-                    //         };
-                    //     }
-                    // }
-                }
+            // Updates are not something the user is waiting on, so they must
+            // not compete with other traffic on slow or metered connections.
+            let (_canceler, mut download) = transaction.download_with_options(DownloadOptions {
+                priority: TransferPriority::Background,
+                ..Default::default()
+            });
+
+            while let Some((id, event)) = download.next().await {
+                match event {
+                    DownloadEvent::Error(e) => {
+                        log::error!("{}: {:?}", &id, &e);
+                        continue 'main;
+                    }
+                    event => {
+                        log::debug!("{}: {:?}", &id, &event);
+                    }
+                };
             }
 
             let (_canceler, mut stream) = transaction.process();
@@ -836,24 +1064,37 @@ pub async fn start(
     log::debug!("Created store.");
 
     let current_transaction = Arc::new(tokio::sync::Mutex::new(()));
+    let background_update = BackgroundUpdate::default();
 
     // Create the background updater
-    create_background_update_service(Arc::clone(&store), Arc::clone(&current_transaction));
+    create_background_update_service(
+        Arc::clone(&store),
+        Arc::clone(&current_transaction),
+        Arc::clone(&background_update),
+    );
 
     // Notifications
     let (notifications, mut notif_rx) = broadcast::channel(5);
 
+    create_config_watcher(Arc::clone(&store), notifications.clone());
+
     let rpc = Rpc {
         store: Arc::clone(&store),
         notifications: notifications.clone(),
         current_transaction: Arc::clone(&current_transaction),
+        background_update: Arc::clone(&background_update),
     };
 
     Server::builder()
         .add_service(pb::pahkat_server::PahkatServer::new(rpc))
         .serve_with_incoming_shutdown(
             endpoint.incoming().map_ok(StreamBox),
-            shutdown_handler(shutdown_rx, notifications, Arc::clone(&current_transaction))?,
+            shutdown_handler(
+                shutdown_rx,
+                notifications,
+                Arc::clone(&current_transaction),
+                background_update,
+            )?,
         )
         .await?;
 
@@ -869,6 +1110,7 @@ fn shutdown_handler(
     mut shutdown_rx: mpsc::UnboundedReceiver<()>,
     mut broadcast_tx: broadcast::Sender<Notification>,
     current_transaction: Arc<tokio::sync::Mutex<()>>,
+    background_update: BackgroundUpdate,
 ) -> anyhow::Result<Pin<Box<dyn std::future::Future<Output = ()>>>, anyhow::Error> {
     let sigint_listener = signal(SignalKind::interrupt())?.into_future();
     let sigterm_listener = signal(SignalKind::terminate())?.into_future();
@@ -896,6 +1138,13 @@ fn shutdown_handler(
             }
         };
 
+        // A background update would otherwise keep the service running until
+        // it finished. A transaction a client asked for is left to finish.
+        if let Some(token) = background_update.lock().unwrap().as_ref() {
+            log::info!("Cancelling background update.");
+            token.cancel();
+        }
+
         log::info!("Attempting to attain transaction lock...");
         current_transaction.lock().await;
         log::info!("Lock attained!");
@@ -919,24 +1168,37 @@ pub async fn start(
     log::debug!("Created store.");
 
     let current_transaction = Arc::new(tokio::sync::Mutex::new(()));
+    let background_update = BackgroundUpdate::default();
 
     // Create the background updater
-    create_background_update_service(Arc::clone(&store), Arc::clone(&current_transaction));
+    create_background_update_service(
+        Arc::clone(&store),
+        Arc::clone(&current_transaction),
+        Arc::clone(&background_update),
+    );
 
     // Notifications
     let (notifications, mut notif_rx) = broadcast::channel(5);
 
+    create_config_watcher(Arc::clone(&store), notifications.clone());
+
     let rpc = Rpc {
         store: Arc::clone(&store),
         notifications: notifications.clone(),
         current_transaction: Arc::clone(&current_transaction),
+        background_update: Arc::clone(&background_update),
     };
 
     Server::builder()
         .add_service(pb::pahkat_server::PahkatServer::new(rpc))
         .serve_with_incoming_shutdown(
             incoming.map_ok(StreamBox),
-            shutdown_handler(shutdown_rx, notifications, Arc::clone(&current_transaction)),
+            shutdown_handler(
+                shutdown_rx,
+                notifications,
+                Arc::clone(&current_transaction),
+                background_update,
+            ),
         )
         .await?;
 
@@ -949,6 +1211,7 @@ fn shutdown_handler(
     mut shutdown_rx: mpsc::UnboundedReceiver<()>,
     mut broadcast_tx: broadcast::Sender<Notification>,
     current_transaction: Arc<tokio::sync::Mutex<()>>,
+    background_update: BackgroundUpdate,
 ) -> impl std::future::Future<Output = ()> {
     let ctrl_c = tokio::signal::ctrl_c();
 
@@ -962,6 +1225,13 @@ fn shutdown_handler(
             }
         };
 
+        // A background update would otherwise keep the service running until
+        // it finished. A transaction a client asked for is left to finish.
+        if let Some(token) = background_update.lock().unwrap().as_ref() {
+            log::info!("Cancelling background update.");
+            token.cancel();
+        }
+
         log::info!("Attempting to attain transaction lock...");
         current_transaction.lock().await;
         log::info!("Lock attained!");
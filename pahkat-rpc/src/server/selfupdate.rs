@@ -18,7 +18,8 @@ pub(crate) async fn package_store() -> Box<dyn PackageStore> {
     use pahkat_client::{Config, WindowsPackageStore};
     let mut config = Config::read_only();
     config.repos_mut().insert(UPDATER_KEY.repository_url.clone(), RepoRecord {
-        channel: Some(UPDATER_DEFAULT_CHANNEL.to_string())
+        channel: Some(UPDATER_DEFAULT_CHANNEL.to_string()),
+        ..Default::default()
     }).unwrap();
 
     Box::new(WindowsPackageStore::new(config).await)
@@ -30,7 +31,8 @@ pub(crate) async fn package_store() -> Box<dyn PackageStore> {
     let mut config = Config::read_only();
 
     config.repos_mut().insert(UPDATER_KEY.repository_url.clone(), RepoRecord {
-        channel: Some(UPDATER_DEFAULT_CHANNEL.to_string())
+        channel: Some(UPDATER_DEFAULT_CHANNEL.to_string()),
+        ..Default::default()
     }).unwrap();
 
     Box::new(MacOSPackageStore::new(config).await)
@@ -87,7 +89,11 @@ pub(crate) async fn self_update() -> Result<bool, Box<dyn Error>> {
 
         while let Some(result) = stream.next().await {
             match result {
-                DownloadEvent::Progress((current, total)) => log::debug!("Downloaded: {}/{}", current, total),
+                DownloadEvent::Progress(p) => log::debug!("Downloaded: {}/{}", p.current, p.total),
+                DownloadEvent::Phase(phase) => log::debug!("Download phase: {:?}", phase),
+                DownloadEvent::Retrying { attempt, delay, error } => {
+                    log::warn!("Retrying update download, attempt {} in {:?}: {:?}", attempt, delay, error)
+                }
                 DownloadEvent::Error(error) => {
                     log::error!("Error downloading update: {:?}", error);
                     if i == retries {
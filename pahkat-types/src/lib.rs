@@ -13,7 +13,7 @@ pub type LangTagMap<T> = std::collections::BTreeMap<String, T>;
 pub type DependencyMap = std::collections::BTreeMap<String, String>;
 
 pub use payload::AsDownloadUrl;
-pub use package_key::PackageKey;
+pub use package_key::{PackageKey, ShortPackageKey};
 
 #[cfg(test)]
 mod tests {
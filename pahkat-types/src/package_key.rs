@@ -1,5 +1,6 @@
 use std::convert::TryFrom;
 use std::fmt;
+use std::str::FromStr;
 
 use serde::de::{self, Deserialize, Deserializer, Visitor};
 use serde::ser::{Serialize, Serializer};
@@ -137,24 +138,26 @@ impl<'a> TryFrom<&'a Url> for PackageKey {
         }
 
         let (left, id) = {
-            // Find first /packages/ segment
+            // The id is always the last segment, right after /packages/. Only
+            // that packages segment counts, as local repository paths may well
+            // contain a directory called packages, and the id may be
+            // `packages` itself.
             let path_segments = url
                 .path_segments()
                 .ok_or_else(|| TryFromError::BaseForbidden)?;
-            let sides = path_segments.collect::<Vec<_>>();
-            let sides = sides.splitn(2, |x| *x == "packages").collect::<Vec<_>>();
-
-            if sides.len() != 2 {
-                return Err(TryFromError::MissingPackagesSegment);
-            }
-
-            if sides[1].len() != 1 {
-                return Err(TryFromError::InvalidPackageSegment);
+            let mut segments = path_segments.collect::<Vec<_>>();
+            let id = segments.pop().unwrap_or_default().to_string();
+
+            match segments.pop() {
+                Some("packages") => {}
+                _ if segments.contains(&"packages") || id == "packages" => {
+                    return Err(TryFromError::InvalidPackageSegment)
+                }
+                _ => return Err(TryFromError::MissingPackagesSegment),
             }
 
-            let id = sides[1][0].to_string();
             (
-                sides[0]
+                segments
                     .iter()
                     .map(|x| x.to_string())
                     .collect::<Vec<String>>(),
@@ -188,6 +191,79 @@ impl<'a> TryFrom<&'a str> for PackageKey {
     }
 }
 
+/// A package key as typed by hand: `id`, optionally prefixed by the alias of
+/// its repository and followed by a version and a channel, such as
+/// `sami:speller@1.0.0#beta`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ShortPackageKey {
+    pub alias: Option<String>,
+    pub id: String,
+    pub query: PackageKeyParams,
+}
+
+impl ShortPackageKey {
+    /// The full key of this package in the repository at `repository_url`.
+    pub fn into_key(self, repository_url: Url) -> PackageKey {
+        PackageKey::new_unchecked(repository_url, self.id, Some(self.query))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ShortKeyError {
+    #[error("No package id given")]
+    MissingId,
+
+    #[error("Empty {0} in package key")]
+    EmptyPart(&'static str),
+}
+
+fn non_empty(value: &str, part: &'static str) -> Result<String, ShortKeyError> {
+    if value.is_empty() {
+        Err(ShortKeyError::EmptyPart(part))
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+impl FromStr for ShortPackageKey {
+    type Err = ShortKeyError;
+
+    fn from_str(value: &str) -> Result<ShortPackageKey, Self::Err> {
+        let mut query = PackageKeyParams::default();
+
+        let value = match value.rfind('#') {
+            Some(i) => {
+                query.channel = Some(non_empty(&value[i + 1..], "channel")?);
+                &value[..i]
+            }
+            None => value,
+        };
+
+        let value = match value.rfind('@') {
+            Some(i) => {
+                query.version = Some(non_empty(&value[i + 1..], "version")?);
+                &value[..i]
+            }
+            None => value,
+        };
+
+        let (alias, id) = match value.find(':') {
+            Some(i) => (Some(non_empty(&value[..i], "alias")?), &value[i + 1..]),
+            None => (None, value),
+        };
+
+        if id.is_empty() {
+            return Err(ShortKeyError::MissingId);
+        }
+
+        Ok(ShortPackageKey {
+            alias,
+            id: id.to_string(),
+            query,
+        })
+    }
+}
+
 impl Serialize for PackageKey {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -222,3 +298,97 @@ impl<'de> Visitor<'de> for PackageKeyVisitor {
         PackageKey::try_from(value).map_err(|e| E::custom(e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_url_round_trip() {
+        let key = PackageKey::try_from(
+            "file:///srv/packages/repo/packages/speller-sme?channel=nightly",
+        )
+        .unwrap();
+
+        assert_eq!(key.repository_url.as_str(), "file:///srv/packages/repo/");
+        assert_eq!(key.id, "speller-sme");
+        assert_eq!(key.query.channel.as_deref(), Some("nightly"));
+        assert_eq!(
+            key.to_string(),
+            "file:///srv/packages/repo/packages/speller-sme?channel=nightly"
+        );
+    }
+
+    #[test]
+    fn short_key_with_alias_version_and_channel() {
+        let key = "sami:speller-sme@1.2.0#beta".parse::<ShortPackageKey>().unwrap();
+
+        assert_eq!(key.alias.as_deref(), Some("sami"));
+        assert_eq!(key.id, "speller-sme");
+        assert_eq!(key.query.version.as_deref(), Some("1.2.0"));
+        assert_eq!(key.query.channel.as_deref(), Some("beta"));
+
+        let key = key.into_key(Url::parse("https://pahkat.example/repo/").unwrap());
+        assert_eq!(
+            key.to_string(),
+            "https://pahkat.example/repo/packages/speller-sme?channel=beta&version=1.2.0"
+        );
+    }
+
+    #[test]
+    fn short_key_with_only_id() {
+        let key = "speller-sme#nightly".parse::<ShortPackageKey>().unwrap();
+        assert_eq!(key.alias, None);
+        assert_eq!(key.id, "speller-sme");
+        assert_eq!(key.query.channel.as_deref(), Some("nightly"));
+        assert_eq!(key.query.version, None);
+    }
+
+    #[test]
+    fn short_key_rejects_empty_parts() {
+        assert_eq!("sami:".parse::<ShortPackageKey>(), Err(ShortKeyError::MissingId));
+        assert_eq!(
+            ":speller-sme".parse::<ShortPackageKey>(),
+            Err(ShortKeyError::EmptyPart("alias"))
+        );
+        assert_eq!(
+            "speller-sme@".parse::<ShortPackageKey>(),
+            Err(ShortKeyError::EmptyPart("version"))
+        );
+    }
+
+    #[test]
+    fn id_may_be_packages() {
+        let url = "https://pahkat.example/packages/repo/packages/packages";
+        let key = PackageKey::try_from(url).unwrap();
+
+        assert_eq!(key.repository_url.as_str(), "https://pahkat.example/packages/repo/");
+        assert_eq!(key.id, "packages");
+        assert_eq!(key.to_string(), url);
+    }
+
+    #[test]
+    fn package_segment_must_precede_id() {
+        assert!(matches!(
+            PackageKey::try_from("https://pahkat.example/repo/packages/speller-sme/extra"),
+            Err(TryFromError::InvalidPackageSegment)
+        ));
+        assert!(matches!(
+            PackageKey::try_from("https://pahkat.example/repo/packages"),
+            Err(TryFromError::InvalidPackageSegment)
+        ));
+        assert!(matches!(
+            PackageKey::try_from("https://pahkat.example/repo/speller-sme"),
+            Err(TryFromError::MissingPackagesSegment)
+        ));
+    }
+
+    #[test]
+    fn network_share_round_trip() {
+        let url = "file://server/share/repo/packages/speller-sme";
+        let key = PackageKey::try_from(url).unwrap();
+
+        assert_eq!(key.repository_url.as_str(), "file://server/share/repo/");
+        assert_eq!(key.to_string(), url);
+    }
+}
@@ -22,6 +22,8 @@ pub struct RepositoryData {
     #[builder(default)]
     pub landing_url: Option<Url>,
 
+    /// Ordered from the most to the least stable. A channel also receives the
+    /// releases of every channel before it.
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     #[builder(default)]
     pub channels: Vec<String>,
@@ -39,6 +41,29 @@ pub struct RepositoryData {
     pub accepted_redirections: Vec<Url>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Channel `{0}` is not declared by the repository")]
+pub struct UnknownChannel(pub String);
+
+impl RepositoryData {
+    /// The channels whose releases are accepted when following `channel`:
+    /// `channel` itself and every channel declared before it. Releases without
+    /// a channel are stable, and are accepted by every channel.
+    ///
+    /// A repository that declares no channels accepts any channel, without
+    /// inheritance.
+    pub fn channel_chain<'a>(&'a self, channel: &'a str) -> Result<Vec<&'a str>, UnknownChannel> {
+        if self.channels.is_empty() {
+            return Ok(vec![channel]);
+        }
+
+        match self.channels.iter().position(|x| x == channel) {
+            Some(index) => Ok(self.channels[..=index].iter().map(|x| &**x).collect()),
+            None => Err(UnknownChannel(channel.to_string())),
+        }
+    }
+}
+
 #[derive(
     Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, TypedBuilder,
 )]
@@ -95,3 +120,40 @@ pub struct RedirectData {
 pub struct Redirect {
     pub redirect: RedirectData,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repository(channels: &[&str]) -> RepositoryData {
+        RepositoryData::builder()
+            .url(Url::parse("https://example.com/repo/").unwrap())
+            .channels(channels.iter().map(|x| x.to_string()).collect())
+            .build()
+    }
+
+    #[test]
+    fn channel_chain_inherits_earlier_channels() {
+        let repo = repository(&["beta", "nightly"]);
+
+        assert_eq!(repo.channel_chain("beta"), Ok(vec!["beta"]));
+        assert_eq!(repo.channel_chain("nightly"), Ok(vec!["beta", "nightly"]));
+    }
+
+    #[test]
+    fn channel_chain_rejects_undeclared_channel() {
+        let repo = repository(&["beta"]);
+
+        assert_eq!(
+            repo.channel_chain("alpha"),
+            Err(UnknownChannel("alpha".to_string()))
+        );
+    }
+
+    #[test]
+    fn channel_chain_without_declared_channels() {
+        let repo = repository(&[]);
+
+        assert_eq!(repo.channel_chain("anything"), Ok(vec!["anything"]));
+    }
+}